@@ -2,21 +2,11 @@ use crate::{ClientID, TransactionID};
 use rust_decimal::Decimal;
 use serde::Deserialize;
 
-/* Would have liked to have done something like:
+/* The CSV crate does not support internally tagged enums, so csv rows are deserialized into the flat
+TransactionRecord below and the engine checks which fields each type requires. Formats that do support them (like
+NDJSON) use TaggedTransactionRecord, which is then converted into a TransactionRecord. */
 
-#[derive(Deserialize)]
-#[serde(tag = "type", rename_all = "lowercase")]
-pub enum TransactionRecord {
-    Deposit {client: ClientID, tx: TransactionID, amount: Decimal},
-    Withdrawal {client: ClientID, tx: TransactionID, amount: Decimal},
-    Dispute {client: ClientID, tx: TransactionID},
-    Resolve {client: ClientID, tx: TransactionID},
-    Chargeback {client: ClientID, tx: TransactionID},
-}
-
-Unfortunately, the CSV crate does not support internally tagged enums */
-
-#[derive(Deserialize)]
+#[derive(Deserialize, Default, Clone, Debug)]
 pub struct TransactionRecord {
     #[serde(rename = "type")]
     pub(crate) transaction_type: TransactionType,
@@ -26,14 +16,187 @@ pub struct TransactionRecord {
     pub(crate) transaction_id: TransactionID,
     #[serde(deserialize_with = "csv::invalid_option")]
     pub(crate) amount: Option<Decimal>,
+    // Currency of the amount, records without one all share the same implicit currency
+    #[serde(default)]
+    pub(crate) currency: Option<String>,
+    // Destination client, only used by transfers
+    #[serde(rename = "to", default)]
+    pub(crate) to_client_id: Option<ClientID>,
+    // Only used by admin transactions (unlock, freeze and close)
+    #[serde(default)]
+    pub(crate) reason: Option<String>,
+    #[serde(default)]
+    pub(crate) operator: Option<String>,
 }
 
-#[derive(Deserialize, PartialEq, Eq, Debug)]
+#[derive(Deserialize, PartialEq, Eq, Debug, Default, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
+    #[default]
     Deposit,
     Withdrawal,
+    Transfer,
     Dispute,
     Resolve,
     Chargeback,
+    Authorize,
+    Capture,
+    Void,
+    Limit,
+    Unlock,
+    Freeze,
+    Close,
+}
+
+/// Internally tagged representation of a record, so each type only accepts (and requires) the fields it uses
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum TaggedTransactionRecord {
+    Deposit(Movement),
+    Withdrawal(Movement),
+    Transfer(TransferFields),
+    Dispute(DisputeStep),
+    Resolve(DisputeStep),
+    Chargeback(DisputeStep),
+    Authorize(Movement),
+    Capture(DisputeStep),
+    Void(DisputeStep),
+    Limit(CreditLimit),
+    Unlock(AdminAction),
+    Freeze(AdminAction),
+    Close(AdminAction),
+}
+
+/// Fields of deposits, withdrawals and authorizations
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct Movement {
+    client: ClientID,
+    tx: TransactionID,
+    amount: Decimal,
+    currency: Option<String>,
+}
+
+/// Fields of transfers, `client` is the source client
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct TransferFields {
+    client: ClientID,
+    to: ClientID,
+    tx: TransactionID,
+    amount: Decimal,
+    currency: Option<String>,
+}
+
+/// Fields of disputes, resolves and chargebacks, and of the captures and voids of a hold
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct DisputeStep {
+    client: ClientID,
+    tx: TransactionID,
+    amount: Option<Decimal>,
+    currency: Option<String>,
+}
+
+/// Fields of credit limit changes, `amount` is the new limit
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct CreditLimit {
+    client: ClientID,
+    tx: TransactionID,
+    amount: Decimal,
+}
+
+/// Fields of admin transactions
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct AdminAction {
+    client: ClientID,
+    tx: TransactionID,
+    reason: String,
+    operator: String,
+}
+
+impl Movement {
+    fn record(self, transaction_type: TransactionType) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type,
+            client_id: self.client,
+            transaction_id: self.tx,
+            amount: Some(self.amount),
+            currency: self.currency,
+            ..TransactionRecord::default()
+        }
+    }
+}
+
+impl TransferFields {
+    fn record(self) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type: TransactionType::Transfer,
+            client_id: self.client,
+            transaction_id: self.tx,
+            amount: Some(self.amount),
+            currency: self.currency,
+            to_client_id: Some(self.to),
+            ..TransactionRecord::default()
+        }
+    }
+}
+
+impl DisputeStep {
+    fn record(self, transaction_type: TransactionType) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type,
+            client_id: self.client,
+            transaction_id: self.tx,
+            amount: self.amount,
+            currency: self.currency,
+            ..TransactionRecord::default()
+        }
+    }
+}
+
+impl CreditLimit {
+    fn record(self) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type: TransactionType::Limit,
+            client_id: self.client,
+            transaction_id: self.tx,
+            amount: Some(self.amount),
+            ..TransactionRecord::default()
+        }
+    }
+}
+
+impl AdminAction {
+    fn record(self, transaction_type: TransactionType) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type,
+            client_id: self.client,
+            transaction_id: self.tx,
+            reason: Some(self.reason),
+            operator: Some(self.operator),
+            ..TransactionRecord::default()
+        }
+    }
+}
+
+impl From<TaggedTransactionRecord> for TransactionRecord {
+    fn from(record: TaggedTransactionRecord) -> Self {
+        match record {
+            TaggedTransactionRecord::Deposit(fields) => fields.record(TransactionType::Deposit),
+            TaggedTransactionRecord::Withdrawal(fields) => {
+                fields.record(TransactionType::Withdrawal)
+            }
+            TaggedTransactionRecord::Transfer(fields) => fields.record(),
+            TaggedTransactionRecord::Dispute(fields) => fields.record(TransactionType::Dispute),
+            TaggedTransactionRecord::Resolve(fields) => fields.record(TransactionType::Resolve),
+            TaggedTransactionRecord::Chargeback(fields) => {
+                fields.record(TransactionType::Chargeback)
+            }
+            TaggedTransactionRecord::Authorize(fields) => fields.record(TransactionType::Authorize),
+            TaggedTransactionRecord::Capture(fields) => fields.record(TransactionType::Capture),
+            TaggedTransactionRecord::Void(fields) => fields.record(TransactionType::Void),
+            TaggedTransactionRecord::Limit(fields) => fields.record(),
+            TaggedTransactionRecord::Unlock(fields) => fields.record(TransactionType::Unlock),
+            TaggedTransactionRecord::Freeze(fields) => fields.record(TransactionType::Freeze),
+            TaggedTransactionRecord::Close(fields) => fields.record(TransactionType::Close),
+        }
+    }
 }
@@ -1,36 +1,996 @@
-use std::collections::HashMap;
+use rust_decimal::Decimal;
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
 
 use crate::stores::{
-    clients::{Client, Clients},
+    clients::{Client, Clients, Status},
     transactions::{self, Transactions},
+    ClientStore, TransactionStore,
 };
 use crate::{
     dtos::{TransactionRecord, TransactionType},
     errors::Error,
-    ClientID,
+    fees::FeeSchedule,
+    journal::{Account, Journal, Posting, TrialBalance},
+    ledger::Ledger,
+    rules::{Check, Rules},
+    snapshot::{ClientState, Snapshot, State, TransactionState},
+    ClientID, TransactionID,
 };
 
-#[derive(Default)]
-pub struct Engine {
-    clients_store: Clients,
-    transactions_store: Transactions,
+/// Which transaction kinds can be disputed
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DisputePolicy {
+    /// Only deposits and transfers can be disputed, disputing a withdrawal is rejected
+    #[default]
+    DepositsOnly,
+    /// Withdrawals can be disputed too. The disputed amount is held as a pending credit, which is dropped on resolve
+    /// and credited back to the client on chargeback.
+    DepositsAndWithdrawals,
+}
+
+/// Whether the engine keeps a double-entry `Journal` and when it checks the trial balance
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JournalMode {
+    #[default]
+    Disabled,
+    /// Post every balance change to the journal, the trial balance is only checked on demand
+    OnDemand,
+    /// Check the trial balance after each record, rejecting any record that would leave the journal out of balance
+    /// with the clients store. This reads every client, so it is meant for audit runs rather than large files.
+    EveryRecord,
+}
+
+/// Bounds on record amounts, on top of amounts always having to be positive (credit limits can be zero)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AmountLimits {
+    /// Maximum number of decimal places, trailing zeros aside
+    pub max_scale: u32,
+    /// Largest accepted amount, unbounded if unset
+    pub max_amount: Option<Decimal>,
+}
+
+impl Default for AmountLimits {
+    fn default() -> Self {
+        Self {
+            max_scale: 4,
+            max_amount: None,
+        }
+    }
+}
+
+/// Business rules the engine applies on top of the stores
+#[derive(Default, Clone, Debug)]
+pub struct Config {
+    pub dispute_policy: DisputePolicy,
+    /// Keep a `Ledger` of every accepted record and the balance changes it caused
+    pub event_log: bool,
+    /// Post balanced journal entries for every balance change. It has to be enabled before any record is handled.
+    pub journal: JournalMode,
+    /// Fees charged on deposits and withdrawals, none if unset
+    pub fees: Option<FeeSchedule>,
+    /// Release the funds of an authorization hold that is neither captured nor voided after this many subsequent
+    /// accepted records. Holds never expire if unset.
+    pub hold_expiry: Option<u64>,
+    /// Fraud and velocity rules checked before each record, none by default
+    pub rules: Rules,
+    /// Scale and size bounds of record amounts, 4 decimal places and no maximum by default
+    pub amount_limits: AmountLimits,
+    /// Treat a deposit, withdrawal, transfer or authorization whose id is already stored with the same kind, client,
+    /// amount, currency and destination as a replay, accepted without changing anything. Records reusing an id with
+    /// other data are still rejected.
+    pub idempotent: bool,
+    /// Only let a transaction be disputed by the next this many accepted records. Once out of the window, disputes of
+    /// the transaction fail with `DisputeWindowExpired` and it is evicted from the transactions store, which only keeps
+    /// its id, as soon as it is neither under dispute nor an open hold. Transactions are kept forever if unset.
+    ///
+    /// Evicted transactions no longer count towards rules, and replays of them are rejected as reused ids. The window
+    /// cannot be shorter than the `records` window of a `MaxWithdrawals` rule, see `Engine::with_config`.
+    pub dispute_window: Option<u64>,
+}
+
+/// The transaction engine, generic over the client and transaction stores it persists state into.
+///
+/// `Engine::default()` uses the in-memory `Clients` and `Transactions` stores.
+pub struct Engine<C = Clients, T = Transactions> {
+    clients_store: C,
+    transactions_store: T,
+    config: Config,
+    records_handled: u64,
+    records_accepted: u64,
+    ledger: Option<Ledger>,
+    journal: Option<Journal>,
+    // Postings made by the record currently being processed
+    postings: Vec<Posting>,
+    // Authorization holds by the number of accepted records after which they expire. Entries are only dropped once
+    // the hold is closed, so the queue can hold stale entries of rolled back authorizations.
+    holds: BTreeMap<u64, Vec<TransactionID>>,
+    // Transactions by the number of accepted records after which they leave the dispute window, along with the
+    // sequence number they were saved with so entries of rolled back records are skipped. Deadlines depend on the
+    // configured window, so the queue is only loaded from the transactions store with the first record handled under a
+    // window, and `None` until then.
+    evictions: Option<BTreeMap<u64, Vec<(TransactionID, u64)>>>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::with_stores(Clients::default(), Transactions::default())
+    }
 }
 
-impl Engine {
+impl<C: ClientStore, T: TransactionStore> Engine<C, T> {
+    /// Creates an engine backed by the given stores, picking up where the last engine over them stopped: record numbers
+    /// continue from the counts saved in the transactions store, and the open authorization holds expire on schedule.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a store fails
+    pub fn new(clients_store: C, transactions_store: T) -> Result<Self, Error> {
+        let mut engine = Self::with_stores(clients_store, transactions_store);
+        (engine.records_handled, engine.records_accepted) =
+            engine.transactions_store.get_record_counts()?;
+        let transactions = engine.transactions_store.get_all_transactions()?;
+        engine.queue_holds(
+            transactions
+                .iter()
+                .map(|(id, transaction)| (*id, transaction)),
+        );
+        Ok(engine)
+    }
+
+    /// Creates an engine backed by the given stores without loading anything from them, for stores that are empty or
+    /// whose record counts the caller sets
+    pub(crate) fn with_stores(clients_store: C, transactions_store: T) -> Self {
+        Self {
+            clients_store,
+            transactions_store,
+            config: Config::default(),
+            records_handled: 0,
+            records_accepted: 0,
+            ledger: None,
+            journal: None,
+            postings: Vec::new(),
+            holds: BTreeMap::new(),
+            evictions: None,
+        }
+    }
+
+    /// Replaces the engine configuration
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the dispute window is shorter than the window of a `MaxWithdrawals` rule, as withdrawals
+    /// would be evicted while the rule still counts them
+    pub fn with_config(mut self, config: Config) -> Result<Self, Error> {
+        if let Some(window) = config.dispute_window {
+            for rule in &config.rules.rules {
+                if let Check::MaxWithdrawals { records, .. } = rule.check {
+                    if window < records {
+                        return Err(Error::InvalidConfig(format!(
+                            "dispute window {window} is shorter than the {records} records window of rule {}",
+                            rule.name
+                        )));
+                    }
+                }
+            }
+        }
+        if config.event_log {
+            self.ledger.get_or_insert_with(Ledger::default);
+        } else {
+            self.ledger = None;
+        }
+        if config.journal == JournalMode::Disabled {
+            self.journal = None;
+        } else {
+            self.journal.get_or_insert_with(Journal::default);
+        }
+        if config.dispute_window != self.config.dispute_window {
+            self.evictions = None;
+        }
+        self.config = config;
+        Ok(self)
+    }
+
+    /// Processes a single transaction record, updating the stores.
+    ///
+    /// All store changes made while processing the record are committed together, or rolled back if the record fails.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the record is invalid for the current state of the stores, or if a store fails
     pub fn handle(&mut self, record: &TransactionRecord) -> Result<(), Error> {
-        match record.transaction_type {
-            TransactionType::Deposit => self.process_deposit(record),
-            TransactionType::Withdrawal => self.process_withdrawal(record),
-            TransactionType::Dispute => self.process_dispute(record),
-            TransactionType::Resolve => self.process_resolve(record),
-            TransactionType::Chargeback => self.process_chargeback(record),
+        self.records_handled += 1;
+
+        // Replays are neither applied again nor logged, so the ledger only holds records that changed something
+        if self.config.idempotent && self.is_replay(record)? {
+            return Ok(());
+        }
+
+        let before = match self.ledger {
+            Some(_) => Some(
+                self.affected_clients(record)?
+                    .into_iter()
+                    .map(|id| Ok((id, self.clients_store.find_client(id)?)))
+                    .collect::<Result<Vec<_>, Error>>()?,
+            ),
+            None => None,
+        };
+
+        self.apply(record)?;
+
+        if let Some(before) = before {
+            let changes = before
+                .into_iter()
+                .map(|(id, before)| {
+                    let after = self.clients_store.find_client(id)?.unwrap_or_default();
+                    Ok((id, before, after))
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            if let Some(ledger) = &mut self.ledger {
+                ledger.append(self.records_handled, record, &changes);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts an input row that could not be parsed into a record. Such rows are rejected without touching the stores,
+    /// but still take a sequence number so the records after them keep the number of their row in the input.
+    pub fn skip_record(&mut self) {
+        self.records_handled += 1;
+    }
+
+    /// Returns true if the record creates a transaction that is already stored with the same data
+    fn is_replay(&self, record: &TransactionRecord) -> Result<bool, Error> {
+        let kind = match record.transaction_type {
+            TransactionType::Deposit => transactions::Kind::Deposit,
+            TransactionType::Withdrawal => transactions::Kind::Withdrawal,
+            TransactionType::Transfer => transactions::Kind::Transfer,
+            TransactionType::Authorize => transactions::Kind::Authorization,
+            _ => return Ok(false),
+        };
+        let Some(transaction) = self
+            .transactions_store
+            .find_transaction(record.transaction_id)?
+        else {
+            return Ok(false);
+        };
+
+        Ok(transaction.kind == kind
+            && transaction.client_id == record.client_id
+            && Some(transaction.amount) == record.amount
+            && transaction.currency == record.currency.clone().unwrap_or_default()
+            && (kind != transactions::Kind::Transfer
+                || transaction.counterparty == record.to_client_id))
+    }
+
+    /// Clients whose balances the record can change: the record client, plus the other side of a transfer
+    fn affected_clients(&self, record: &TransactionRecord) -> Result<Vec<ClientID>, Error> {
+        let counterparty = match record.transaction_type {
+            TransactionType::Transfer => record.to_client_id,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                self.transactions_store
+                    .find_transaction(record.transaction_id)?
+                    .and_then(|transaction| transaction.counterparty)
+            }
+            _ => None,
+        };
+        let house = match record.transaction_type {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Chargeback => {
+                self.config.fees.as_ref().map(|fees| fees.house_client_id)
+            }
+            _ => None,
+        };
+
+        let mut expiring = Vec::new();
+        for (deadline, holds) in self.holds.range(..=self.records_accepted + 1) {
+            for id in holds {
+                if let Some(hold) = self.open_hold(*id, *deadline)? {
+                    expiring.push(hold.client_id);
+                }
+            }
+        }
+
+        let mut ids = vec![record.client_id];
+        for id in counterparty.into_iter().chain(house).chain(expiring) {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub(crate) fn stores(&self) -> (&C, &T) {
+        (&self.clients_store, &self.transactions_store)
+    }
+
+    pub(crate) fn stores_mut(&mut self) -> (&mut C, &mut T) {
+        (&mut self.clients_store, &mut self.transactions_store)
+    }
+
+    /// Number of records handled and accepted so far
+    pub(crate) fn record_counts(&self) -> (u64, u64) {
+        (self.records_handled, self.records_accepted)
+    }
+
+    /// Sets the number of records handled and accepted so far, for engines sharing their stores with others
+    pub(crate) fn set_record_counts(&mut self, handled: u64, accepted: u64) {
+        self.records_handled = handled;
+        self.records_accepted = accepted;
+    }
+
+    /// The log of accepted records, if `Config::event_log` is enabled
+    pub fn ledger(&self) -> Option<&Ledger> {
+        self.ledger.as_ref()
+    }
+
+    /// The double-entry journal, if `Config::journal` is enabled
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Checks that the journal balances and agrees with every client in the clients store
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the journal is not enabled, does not balance or disagrees with the clients store
+    pub fn trial_balance(&self) -> Result<TrialBalance, Error> {
+        let journal = self.journal.as_ref().ok_or(Error::JournalDisabled)?;
+        let clients = self.clients_store.get_all()?;
+        journal.trial_balance(clients.iter().map(|(id, client)| (*id, client)))
+    }
+
+    fn apply(&mut self, record: &TransactionRecord) -> Result<(), Error> {
+        self.prune_holds()?;
+        self.prune_evictions()?;
+        self.validate_amount(record)?;
+        self.check_rules(record)?;
+
+        let entries = self.journal.as_ref().map(|journal| journal.entries().len());
+        let result = self.atomically(|engine| {
+            engine.postings.clear();
+            match record.transaction_type {
+                TransactionType::Deposit => engine.process_deposit(record),
+                TransactionType::Withdrawal => engine.process_withdrawal(record),
+                TransactionType::Transfer => engine.process_transfer(record),
+                TransactionType::Dispute => engine.process_dispute(record),
+                TransactionType::Resolve => engine.process_resolve(record),
+                TransactionType::Chargeback => engine.process_chargeback(record),
+                TransactionType::Authorize => engine.process_authorize(record),
+                TransactionType::Capture => engine.process_capture(record),
+                TransactionType::Void => engine.process_void(record),
+                TransactionType::Limit => engine.process_credit_limit(record),
+                TransactionType::Unlock => engine.process_status_change(record, Status::Active),
+                TransactionType::Freeze => engine.process_status_change(record, Status::Frozen),
+                TransactionType::Close => engine.process_status_change(record, Status::Closed),
+            }
+            .and_then(|()| engine.freeze_after_disputes(record))
+            .and_then(|()| engine.expire_holds())
+            .and_then(|()| engine.evict_transactions())
+            .and_then(|()| {
+                engine
+                    .transactions_store
+                    .save_record_counts(engine.records_handled, engine.records_accepted + 1)
+            })
+            .and_then(|()| engine.post_journal_entry())
+        });
+        if let Err(error) = result {
+            // The entry of the record is posted before the stores are committed, so it has to go if a commit failed
+            if let Some(journal) = &mut self.journal {
+                if Some(journal.entries().len()) > entries {
+                    journal.unpost();
+                }
+            }
+            return Err(error);
+        }
+        self.records_accepted += 1;
+
+        Ok(())
+    }
+
+    /// Runs `f` in a transaction of both stores, committing its changes together if it succeeds and rolling them back
+    /// otherwise.
+    ///
+    /// The transactions store is begun last and committed first. If its commit fails both stores are rolled back,
+    /// while a failing clients commit can only roll back the clients store: stores sharing a database (like the
+    /// `SQLite` ones) nest their savepoints so this also discards the committed transactions.
+    fn atomically(&mut self, f: impl FnOnce(&mut Self) -> Result<(), Error>) -> Result<(), Error> {
+        self.clients_store.begin()?;
+        if let Err(error) = self.transactions_store.begin() {
+            self.clients_store.rollback()?;
+            return Err(error);
+        }
+
+        let result = f(self);
+        if let Err(error) = result.and_then(|()| self.transactions_store.commit()) {
+            self.rollback_stores()?;
+            return Err(error);
+        }
+        if let Err(error) = self.clients_store.commit() {
+            self.clients_store.rollback()?;
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Rolls back both stores, the clients store even if rolling back the transactions store fails
+    fn rollback_stores(&mut self) -> Result<(), Error> {
+        let transactions = self.transactions_store.rollback();
+        let clients = self.clients_store.rollback();
+        transactions.and(clients)
+    }
+
+    /// Takes a snapshot of every client and transaction in the stores, along with the record counters
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a store fails
+    pub fn snapshot(&self) -> Result<Snapshot, Error> {
+        let clients = self
+            .clients_store
+            .get_all()?
+            .into_iter()
+            .map(|(id, client)| {
+                Ok(ClientState {
+                    id,
+                    client,
+                    status_changes: self.clients_store.get_status_changes(id)?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        let transactions = self
+            .transactions_store
+            .get_all_transactions()?
+            .into_iter()
+            .map(|(id, transaction)| TransactionState { id, transaction })
+            .collect();
+
+        Ok(Snapshot {
+            state: State {
+                records_handled: self.records_handled,
+                records_accepted: self.records_accepted,
+                clients,
+                transactions,
+                evicted: self.transactions_store.get_evicted_ids()?,
+            },
+        })
+    }
+
+    /// Loads a snapshot into the engine, whose stores have to be empty. Record numbers continue from the snapshot.
+    ///
+    /// The journal and event log are not part of snapshots. When enabled, the journal gets an opening entry moving the
+    /// restored balances out of the settlement account, and the event log starts from the restored balances.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the stores are not empty or fail, in which case nothing is restored
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), Error> {
+        if !self.clients_store.get_all()?.is_empty()
+            || !self.transactions_store.get_all_transactions()?.is_empty()
+            || !self.transactions_store.get_evicted_ids()?.is_empty()
+        {
+            return Err(Error::EngineNotEmpty);
+        }
+
+        let State {
+            records_handled,
+            records_accepted,
+            clients,
+            transactions,
+            evicted,
+        } = snapshot.state;
+        self.atomically(|engine| {
+            for state in &clients {
+                engine
+                    .clients_store
+                    .upsert_client(state.id, state.client.clone())?;
+                for change in &state.status_changes {
+                    engine
+                        .clients_store
+                        .save_status_change(state.id, change.clone())?;
+                }
+            }
+            for state in &transactions {
+                engine
+                    .transactions_store
+                    .upsert_transaction(state.id, state.transaction.clone())?;
+            }
+            for id in &evicted {
+                engine.transactions_store.evict_transaction(*id)?;
+            }
+            engine
+                .transactions_store
+                .save_record_counts(records_handled, records_accepted)
+        })?;
+
+        self.records_handled = records_handled;
+        self.records_accepted = records_accepted;
+        self.queue_holds(
+            transactions
+                .iter()
+                .map(|state| (state.id, &state.transaction)),
+        );
+        self.evictions = None;
+        if let Some(journal) = &mut self.journal {
+            let postings = clients
+                .iter()
+                .flat_map(|state| {
+                    state
+                        .client
+                        .balances
+                        .iter()
+                        .flat_map(|(currency, balance)| {
+                            [
+                                (Account::ClientAvailable(state.id), balance.available_amount),
+                                (Account::ClientHeld(state.id), balance.held_amount),
+                            ]
+                            .into_iter()
+                            .filter(|(_, amount)| !amount.is_zero())
+                            .flat_map(|(account, amount)| {
+                                Posting::transfer(currency, Account::Settlement, account, amount)
+                            })
+                        })
+                })
+                .collect::<Vec<_>>();
+            if !postings.is_empty() {
+                journal.post(records_handled, postings)?;
+            }
+        }
+        if let Some(ledger) = &mut self.ledger {
+            *ledger = Ledger::starting_from(
+                clients
+                    .into_iter()
+                    .map(|state| (state.id, state.client))
+                    .collect(),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Writes a snapshot of the engine state, see `Snapshot`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a store fails, or if the snapshot cannot be written to the passed in writer
+    pub fn write_snapshot(&self, writer: impl Write) -> Result<(), Error> {
+        self.snapshot()?.write(writer)
+    }
+
+    /// Restores the engine from a snapshot written by `write_snapshot`, see `restore`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the snapshot cannot be read or is invalid, or if the stores are not empty or fail
+    pub fn restore_snapshot(&mut self, reader: impl Read) -> Result<(), Error> {
+        self.restore(Snapshot::read(reader)?)
+    }
+
+    /// Rejects record amounts that are zero, negative, too precise or too large, before the rules see them
+    fn validate_amount(&self, record: &TransactionRecord) -> Result<(), Error> {
+        let Some(amount) = record.amount else {
+            return Ok(());
+        };
+        let id = record.transaction_id;
+        let credit_limit = record.transaction_type == TransactionType::Limit;
+
+        if amount.is_zero() {
+            // A zero credit limit takes the credit line away
+            if !credit_limit {
+                return Err(Error::ZeroAmount(id));
+            }
+        } else if amount.is_sign_negative() {
+            return Err(if credit_limit {
+                Error::NegativeCreditLimit(id)
+            } else {
+                Error::NegativeAmount(id)
+            });
+        }
+
+        let AmountLimits {
+            max_scale,
+            max_amount,
+        } = self.config.amount_limits;
+        if amount.normalize().scale() > max_scale {
+            return Err(Error::AmountTooPrecise {
+                id,
+                amount,
+                max_scale,
+            });
+        }
+        if let Some(max) = max_amount.filter(|max| amount > *max) {
+            return Err(Error::AmountTooLarge { id, amount, max });
+        }
+
+        Ok(())
+    }
+
+    /// Runs the configured rules against the record. Runs before the stores are touched, so the rules only ever see
+    /// committed state.
+    fn check_rules(&self, record: &TransactionRecord) -> Result<(), Error> {
+        self.config
+            .rules
+            .check(record, self.records_accepted + 1, || {
+                self.transactions_store
+                    .get_client_transactions(record.client_id)
+            })
+    }
+
+    /// Freezes the client of an accepted dispute once its disputes reach the limit of a `FreezeAfterDisputes` rule.
+    /// The status change names the rule that fired.
+    fn freeze_after_disputes(&mut self, record: &TransactionRecord) -> Result<(), Error> {
+        if record.transaction_type != TransactionType::Dispute || !self.config.rules.freezes() {
+            return Ok(());
+        }
+
+        let disputes = self
+            .transactions_store
+            .get_client_transactions(record.client_id)?
+            .iter()
+            .map(|transaction| transaction.dispute_count)
+            .sum();
+        let Some(rule) = self.config.rules.freezing_rule(disputes) else {
+            return Ok(());
+        };
+        let active = self
+            .clients_store
+            .find_client(record.client_id)?
+            .is_some_and(|client| client.status == Status::Active);
+        if !active {
+            return Ok(());
+        }
+
+        let reason = format!("rule {rule}");
+        self.clients_store
+            .change_status(record.client_id, Status::Frozen, reason, None)
+    }
+
+    /// Saves a transaction created by the current record, stamped with the record sequence number
+    fn save_new_transaction(
+        &mut self,
+        transaction_id: TransactionID,
+        mut transaction: transactions::Transaction,
+    ) -> Result<(), Error> {
+        let sequence = self.records_accepted + 1;
+        transaction.sequence = sequence;
+        self.transactions_store
+            .save_new_transaction(transaction_id, transaction)?;
+
+        // Not loaded yet, the transaction is queued along with the stored ones once it is
+        if let (Some(window), Some(evictions)) = (self.config.dispute_window, &mut self.evictions) {
+            evictions
+                .entry(sequence + window)
+                .or_default()
+                .push((transaction_id, sequence));
+        }
+        Ok(())
+    }
+
+    /// Returns true if the dispute window of the transaction closed before the current record
+    fn outside_dispute_window(&self, transaction: &transactions::Transaction) -> bool {
+        self.config
+            .dispute_window
+            .is_some_and(|window| transaction.sequence + window <= self.records_accepted)
+    }
+
+    /// Looks up a transaction queued for eviction, if it is still stored and was saved with the queued sequence number
+    fn queued_transaction(
+        &self,
+        id: TransactionID,
+        sequence: u64,
+    ) -> Result<Option<transactions::Transaction>, Error> {
+        Ok(self
+            .transactions_store
+            .find_transaction(id)?
+            .filter(|transaction| transaction.sequence == sequence))
+    }
+
+    /// Returns true if the transaction has to stay in the store past its dispute window: it is still under dispute or
+    /// holds funds
+    fn kept_past_window(transaction: &transactions::Transaction) -> bool {
+        transaction.disputed()
+            || (transaction.kind == transactions::Kind::Authorization
+                && transaction.open_hold_amount() > Decimal::ZERO)
+    }
+
+    /// Queues every stored transaction to leave the dispute window `window` accepted records after it was saved.
+    /// Transactions already past it are requeued by `prune_evictions`.
+    fn load_evictions(
+        &self,
+        window: u64,
+    ) -> Result<BTreeMap<u64, Vec<(TransactionID, u64)>>, Error> {
+        let mut evictions: BTreeMap<u64, Vec<_>> = BTreeMap::new();
+        for (id, transaction) in self.transactions_store.get_all_transactions()? {
+            evictions
+                .entry(transaction.sequence + window)
+                .or_default()
+                .push((id, transaction.sequence));
+        }
+        Ok(evictions)
+    }
+
+    /// Requeues the transactions that are past their dispute window but still stored: a window later if they are kept
+    /// by a dispute or hold, with the current record if their eviction was rolled back. Runs outside of any store
+    /// transaction, so it only ever sees committed state.
+    ///
+    /// Loads the queue from the transactions store first if needed. If a store fails the queue is dropped, and loaded
+    /// again with the next record.
+    fn prune_evictions(&mut self) -> Result<(), Error> {
+        let Some(window) = self.config.dispute_window else {
+            return Ok(());
+        };
+        let mut evictions = match self.evictions.take() {
+            Some(evictions) => evictions,
+            None => self.load_evictions(window)?,
+        };
+        let due: Vec<u64> = evictions
+            .range(..=self.records_accepted)
+            .map(|(deadline, _)| *deadline)
+            .collect();
+        for deadline in due {
+            for (id, sequence) in evictions.remove(&deadline).unwrap_or_default() {
+                let Some(transaction) = self.queued_transaction(id, sequence)? else {
+                    continue;
+                };
+                let retry = if Self::kept_past_window(&transaction) {
+                    self.records_accepted + window.max(1)
+                } else {
+                    self.records_accepted + 1
+                };
+                evictions.entry(retry).or_default().push((id, sequence));
+            }
         }
+        self.evictions = Some(evictions);
+        Ok(())
+    }
+
+    /// Evicts the transactions whose dispute window closes with the current record
+    fn evict_transactions(&mut self) -> Result<(), Error> {
+        let due: Vec<(TransactionID, u64)> = self
+            .evictions
+            .iter()
+            .flat_map(|evictions| evictions.range(..=self.records_accepted + 1))
+            .flat_map(|(_, queued)| queued.iter().copied())
+            .collect();
+        for (id, sequence) in due {
+            let evictable = self
+                .queued_transaction(id, sequence)?
+                .is_some_and(|transaction| !Self::kept_past_window(&transaction));
+            if evictable {
+                self.transactions_store.evict_transaction(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues the open authorization holds among the given transactions to expire at their deadline, replacing the
+    /// queued ones
+    fn queue_holds<'a>(
+        &mut self,
+        transactions: impl IntoIterator<Item = (TransactionID, &'a transactions::Transaction)>,
+    ) {
+        self.holds.clear();
+        for (id, transaction) in transactions {
+            if let Some(deadline) = transaction.expires_at {
+                if transaction.open_hold_amount() > Decimal::ZERO {
+                    self.holds.entry(deadline).or_default().push(id);
+                }
+            }
+        }
+    }
+
+    /// Looks up the authorization hold queued to expire after `deadline` accepted records, if it is still open
+    fn open_hold(
+        &self,
+        id: TransactionID,
+        deadline: u64,
+    ) -> Result<Option<transactions::Transaction>, Error> {
+        Ok(self
+            .transactions_store
+            .find_transaction(id)?
+            .filter(|hold| {
+                hold.kind == transactions::Kind::Authorization
+                    && hold.expires_at == Some(deadline)
+                    && hold.open_hold_amount() > Decimal::ZERO
+            }))
+    }
+
+    /// Drops the queued holds that are past their deadline and closed. Runs outside of any store transaction, so it
+    /// only ever sees committed state.
+    fn prune_holds(&mut self) -> Result<(), Error> {
+        let due: Vec<u64> = self
+            .holds
+            .range(..=self.records_accepted)
+            .map(|(deadline, _)| *deadline)
+            .collect();
+        for deadline in due {
+            let mut open = Vec::new();
+            for id in self.holds.remove(&deadline).unwrap_or_default() {
+                if self.open_hold(id, deadline)?.is_some() {
+                    open.push(id);
+                }
+            }
+            if !open.is_empty() {
+                self.holds.insert(deadline, open);
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases the holds that expire with the current record. Holds of clients that are not active are kept until the
+    /// client is active again.
+    fn expire_holds(&mut self) -> Result<(), Error> {
+        let due: Vec<(u64, TransactionID)> = self
+            .holds
+            .range(..=self.records_accepted + 1)
+            .flat_map(|(deadline, ids)| ids.iter().map(|id| (*deadline, *id)))
+            .collect();
+        for (deadline, id) in due {
+            let Some(mut hold) = self.open_hold(id, deadline)? else {
+                continue;
+            };
+            let active = self
+                .clients_store
+                .find_client(hold.client_id)?
+                .is_some_and(|client| client.status == Status::Active);
+            if active {
+                self.release_hold(id, &mut hold)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases whatever is still held by an authorization hold, for a void or when it expires
+    fn release_hold(
+        &mut self,
+        id: TransactionID,
+        hold: &mut transactions::Transaction,
+    ) -> Result<(), Error> {
+        let amount = hold.open_hold_amount();
+        self.clients_store
+            .move_to_available(hold.client_id, &hold.currency, amount)?;
+        self.post(
+            &hold.currency,
+            Account::ClientHeld(hold.client_id),
+            Account::ClientAvailable(hold.client_id),
+            amount,
+        );
+        hold.released_amount += amount;
+
+        self.transactions_store.upsert_transaction(id, hold.clone())
+    }
+
+    /// Posts the postings of the current record as a single journal entry, checking the trial balance if configured.
+    /// Runs before the stores are committed, so a failed check rejects the record, and `apply` unposts the entry if a
+    /// commit fails.
+    fn post_journal_entry(&mut self) -> Result<(), Error> {
+        let Some(journal) = &mut self.journal else {
+            return Ok(());
+        };
+        if self.postings.is_empty() {
+            return Ok(());
+        }
+
+        journal.post(self.records_handled, std::mem::take(&mut self.postings))?;
+
+        if self.config.journal == JournalMode::EveryRecord {
+            if let Err(error) = self.trial_balance() {
+                if let Some(journal) = &mut self.journal {
+                    journal.unpost();
+                }
+                return Err(error);
+            }
+        }
+
+        Ok(())
     }
 
-    pub(crate) fn get_clients(&self) -> &HashMap<ClientID, Client> {
+    /// Records a posting for the current record, if the journal is enabled
+    fn post(&mut self, currency: &str, debit: Account, credit: Account, amount: Decimal) {
+        if self.journal.is_some() {
+            self.postings
+                .extend(Posting::transfer(currency, debit, credit, amount));
+        }
+    }
+
+    /// Checks the house can be credited `fee`, so a record is rejected before it writes anything
+    fn check_fee_credit(&self, fee: Decimal) -> Result<(), Error> {
+        let Some(house) = self.config.fees.as_ref().map(|fees| fees.house_client_id) else {
+            return Ok(());
+        };
+        if fee.is_zero() {
+            return Ok(());
+        }
+
+        match self.clients_store.find_client(house)? {
+            Some(client) => client.ensure_active(house),
+            None => Ok(()),
+        }
+    }
+
+    /// Credits a fee paid by `client_id`, already debited from its balance, to the house client
+    fn credit_fee(
+        &mut self,
+        client_id: ClientID,
+        currency: &str,
+        fee: Decimal,
+    ) -> Result<(), Error> {
+        let Some(house) = self.config.fees.as_ref().map(|fees| fees.house_client_id) else {
+            return Ok(());
+        };
+        if fee.is_zero() {
+            return Ok(());
+        }
+
+        self.clients_store.deposit(house, currency, fee)?;
+        self.post(
+            currency,
+            Account::ClientAvailable(client_id),
+            Account::ClientAvailable(house),
+            fee,
+        );
+        Ok(())
+    }
+
+    /// House client and share of a deposit fee to refund when `amount` of the deposit is charged back, as set by the
+    /// `FeeRefundPolicy`. `None` if there is nothing to refund.
+    fn fee_refund(
+        &self,
+        client_id: ClientID,
+        transaction: &transactions::Transaction,
+        amount: Decimal,
+    ) -> Option<(ClientID, Decimal)> {
+        let fees = self.config.fees.as_ref()?;
+        let house = fees.house_client_id;
+        let refund = fees.refund(
+            transaction.fee,
+            transaction.net_amount(),
+            transaction.charged_back_amount,
+            amount,
+        );
+        (!refund.is_zero() && house != client_id).then_some((house, refund))
+    }
+
+    /// Looks up the transaction a dispute, resolve or chargeback refers to. Those always act on the currency of the
+    /// original transaction, so a record naming another currency is rejected.
+    fn get_referenced_transaction(
+        &self,
+        record: &TransactionRecord,
+    ) -> Result<transactions::Transaction, Error> {
+        let transaction = self
+            .transactions_store
+            .get_transaction(record.transaction_id, record.client_id)?;
+
+        match &record.currency {
+            Some(currency) if *currency != transaction.currency => Err(
+                Error::TransactionWithWrongCurrency(record.transaction_id, currency.clone()),
+            ),
+            _ => Ok(transaction),
+        }
+    }
+
+    /// Every client, ordered by id. When the journal is enabled, the trial balance has to pass first.
+    pub(crate) fn get_clients(&self) -> Result<Vec<(ClientID, Client)>, Error> {
+        if self.journal.is_some() {
+            self.trial_balance()?;
+        }
         self.clients_store.get_all()
     }
 
+    /// Every client as it was right after the record with the given sequence number was processed, from the event log
+    pub(crate) fn clients_at(&self, sequence: u64) -> Result<Vec<(ClientID, Client)>, Error> {
+        let ledger = self.ledger.as_ref().ok_or(Error::EventLogDisabled)?;
+        ledger.clients_at(sequence).get_all()
+    }
+
     fn process_deposit(&mut self, record: &TransactionRecord) -> Result<(), Error> {
         assert_eq!(record.transaction_type, TransactionType::Deposit);
 
@@ -38,23 +998,37 @@ impl Engine {
             record.transaction_id,
         ))?;
 
-        if self.transactions_store.has_id(record.transaction_id) {
+        if self.transactions_store.has_id(record.transaction_id)? {
             return Err(Error::TransactionIdAlreadyExists(record.transaction_id));
         }
 
-        self.clients_store.deposit(record.client_id, amount)?;
-
-        self.transactions_store.save_new_transaction(
-            record.transaction_id,
-            transactions::Transaction {
-                kind: transactions::Kind::Deposit,
-                client_id: record.client_id,
-                amount,
-                disputed: false,
-            },
+        let currency = record.currency.clone().unwrap_or_default();
+        let fee = self
+            .config
+            .fees
+            .as_ref()
+            .map_or(Ok(Decimal::ZERO), |fees| {
+                fees.deposit_fee(record.client_id, amount)
+            })?;
+
+        // The client is only credited the amount left after the fee
+        self.check_fee_credit(fee)?;
+        self.clients_store
+            .deposit(record.client_id, &currency, amount - fee)?;
+        self.post(
+            &currency,
+            Account::Settlement,
+            Account::ClientAvailable(record.client_id),
+            amount,
         );
+        self.credit_fee(record.client_id, &currency, fee)?;
 
-        Ok(())
+        self.save_new_transaction(
+            record.transaction_id,
+            transactions::Transaction::new(transactions::Kind::Deposit, record.client_id, amount)
+                .with_currency(currency)
+                .with_fee(fee),
+        )
     }
 
     fn process_withdrawal(&mut self, record: &TransactionRecord) -> Result<(), Error> {
@@ -66,121 +1040,521 @@ impl Engine {
                 record.transaction_id,
             ))?;
 
-        if self.transactions_store.has_id(record.transaction_id) {
+        if self.transactions_store.has_id(record.transaction_id)? {
             return Err(Error::TransactionIdAlreadyExists(record.transaction_id));
         }
 
-        self.clients_store.withdrawal(record.client_id, amount)?;
+        // Withdrawals only ever debit the balance in their own currency
+        let currency = record.currency.clone().unwrap_or_default();
+        let fee = self
+            .config
+            .fees
+            .as_ref()
+            .map_or(Ok(Decimal::ZERO), |fees| {
+                fees.withdrawal_fee(record.client_id, amount)
+            })?;
+
+        // The fee is debited on top of the withdrawn amount
+        self.check_fee_credit(fee)?;
+        self.clients_store
+            .withdrawal(record.client_id, &currency, amount + fee)?;
+        self.post(
+            &currency,
+            Account::ClientAvailable(record.client_id),
+            Account::Settlement,
+            amount,
+        );
+        self.credit_fee(record.client_id, &currency, fee)?;
 
-        self.transactions_store.save_new_transaction(
+        self.save_new_transaction(
             record.transaction_id,
-            transactions::Transaction {
-                kind: transactions::Kind::Withdrawal,
-                client_id: record.client_id,
+            transactions::Transaction::new(
+                transactions::Kind::Withdrawal,
+                record.client_id,
                 amount,
-                disputed: false,
-            },
+            )
+            .with_currency(currency)
+            .with_fee(fee),
+        )
+    }
+
+    fn process_transfer(&mut self, record: &TransactionRecord) -> Result<(), Error> {
+        assert_eq!(record.transaction_type, TransactionType::Transfer);
+
+        let amount = record
+            .amount
+            .ok_or(Error::TransferTransactionMissingAmount(
+                record.transaction_id,
+            ))?;
+        let to = record
+            .to_client_id
+            .ok_or(Error::TransferTransactionMissingDestination(
+                record.transaction_id,
+            ))?;
+        if to == record.client_id {
+            return Err(Error::TransferToSameClient(record.transaction_id));
+        }
+
+        if self.transactions_store.has_id(record.transaction_id)? {
+            return Err(Error::TransactionIdAlreadyExists(record.transaction_id));
+        }
+
+        let currency = record.currency.clone().unwrap_or_default();
+        self.clients_store
+            .transfer(record.client_id, to, &currency, amount)?;
+        self.post(
+            &currency,
+            Account::ClientAvailable(record.client_id),
+            Account::ClientAvailable(to),
+            amount,
         );
 
-        Ok(())
+        self.save_new_transaction(
+            record.transaction_id,
+            transactions::Transaction::new(transactions::Kind::Transfer, record.client_id, amount)
+                .with_currency(currency)
+                .with_counterparty(to),
+        )
+    }
+
+    /// Destination client of a disputed transfer
+    fn transfer_destination(
+        record: &TransactionRecord,
+        transaction: &transactions::Transaction,
+    ) -> Result<ClientID, Error> {
+        transaction
+            .counterparty
+            .ok_or(Error::TransferTransactionMissingDestination(
+                record.transaction_id,
+            ))
     }
 
     fn process_dispute(&mut self, record: &TransactionRecord) -> Result<(), Error> {
         assert_eq!(record.transaction_type, TransactionType::Dispute);
 
-        let transaction = self
-            .transactions_store
-            .get_mut_transaction(record.transaction_id, record.client_id)?;
-
-        if transaction.kind != transactions::Kind::Deposit {
-            return Err(Error::DisputeNonDepositTransaction(record.transaction_id));
+        let mut transaction = self.get_referenced_transaction(record)?;
+        let currency = transaction.currency.clone();
+        if self.outside_dispute_window(&transaction) {
+            return Err(Error::DisputeWindowExpired(record.transaction_id));
         }
 
-        if transaction.disputed {
-            return Err(Error::DisputeAlreadyDisputedTransaction(
-                record.transaction_id,
-            ));
+        // Without an amount, the whole remaining transaction amount is disputed
+        let disputable = transaction.disputable_amount();
+        let amount = match record.amount {
+            None if disputable <= Decimal::ZERO => {
+                return Err(Error::DisputeAlreadyDisputedTransaction(
+                    record.transaction_id,
+                ))
+            }
+            None => disputable,
+            Some(amount) if amount > disputable => {
+                return Err(Error::DisputeAmountExceedsTransaction {
+                    id: record.transaction_id,
+                    amount,
+                    disputable,
+                })
+            }
+            Some(amount) => amount,
+        };
+
+        match (transaction.kind, self.config.dispute_policy) {
+            (transactions::Kind::Deposit, _) => {
+                self.clients_store
+                    .move_to_held(record.client_id, &currency, amount)?;
+                self.post(
+                    &currency,
+                    Account::ClientAvailable(record.client_id),
+                    Account::ClientHeld(record.client_id),
+                    amount,
+                );
+            }
+            (transactions::Kind::Withdrawal, DisputePolicy::DepositsAndWithdrawals) => {
+                // The pending credit is provisioned against the chargeback loss account
+                self.clients_store
+                    .hold_pending_credit(record.client_id, &currency, amount)?;
+                self.post(
+                    &currency,
+                    Account::ChargebackLoss,
+                    Account::ClientHeld(record.client_id),
+                    amount,
+                );
+            }
+            (transactions::Kind::Transfer, _) => {
+                // The source client disputes the transfer, so the funds are held at the destination
+                let to = Self::transfer_destination(record, &transaction)?;
+                self.clients_store.move_to_held(to, &currency, amount)?;
+                self.post(
+                    &currency,
+                    Account::ClientAvailable(to),
+                    Account::ClientHeld(to),
+                    amount,
+                );
+            }
+            (transactions::Kind::Withdrawal, DisputePolicy::DepositsOnly)
+            | (transactions::Kind::Authorization, _) => {
+                return Err(Error::DisputeNonDepositTransaction(record.transaction_id))
+            }
         }
+        transaction.disputed_amount += amount;
+        transaction.dispute_count += 1;
 
-        self.clients_store
-            .move_to_held(record.client_id, transaction.amount)?;
-        transaction.disputed = true;
-
-        Ok(())
+        self.transactions_store
+            .upsert_transaction(record.transaction_id, transaction)
     }
 
     fn process_resolve(&mut self, record: &TransactionRecord) -> Result<(), Error> {
         assert_eq!(record.transaction_type, TransactionType::Resolve);
 
-        let transaction = self
-            .transactions_store
-            .get_mut_transaction(record.transaction_id, record.client_id)?;
+        let mut transaction = self.get_referenced_transaction(record)?;
+        let currency = transaction.currency.clone();
 
-        if !transaction.disputed {
+        if !transaction.disputed() {
             return Err(Error::ResolveNonDisputedTransaction(record.transaction_id));
         }
 
-        self.clients_store
-            .move_to_available(record.client_id, transaction.amount)?;
-        transaction.disputed = false;
+        // Without an amount, the whole disputed amount is resolved
+        let amount = match record.amount {
+            None => transaction.disputed_amount,
+            Some(amount) if amount > transaction.disputed_amount => {
+                return Err(Error::ResolveAmountExceedsDisputed {
+                    id: record.transaction_id,
+                    amount,
+                    disputed: transaction.disputed_amount,
+                })
+            }
+            Some(amount) => amount,
+        };
+
+        match transaction.kind {
+            transactions::Kind::Deposit => {
+                self.clients_store
+                    .move_to_available(record.client_id, &currency, amount)?;
+                self.post(
+                    &currency,
+                    Account::ClientHeld(record.client_id),
+                    Account::ClientAvailable(record.client_id),
+                    amount,
+                );
+            }
+            transactions::Kind::Withdrawal => {
+                self.clients_store
+                    .release_pending_credit(record.client_id, &currency, amount)?;
+                self.post(
+                    &currency,
+                    Account::ClientHeld(record.client_id),
+                    Account::ChargebackLoss,
+                    amount,
+                );
+            }
+            transactions::Kind::Transfer => {
+                let to = Self::transfer_destination(record, &transaction)?;
+                self.clients_store
+                    .move_to_available(to, &currency, amount)?;
+                self.post(
+                    &currency,
+                    Account::ClientHeld(to),
+                    Account::ClientAvailable(to),
+                    amount,
+                );
+            }
+            // Holds are never disputed
+            transactions::Kind::Authorization => {
+                return Err(Error::ResolveNonDisputedTransaction(record.transaction_id))
+            }
+        }
+        transaction.disputed_amount -= amount;
+        transaction.resolved_amount += amount;
 
-        Ok(())
+        self.transactions_store
+            .upsert_transaction(record.transaction_id, transaction)
     }
 
     fn process_chargeback(&mut self, record: &TransactionRecord) -> Result<(), Error> {
         assert_eq!(record.transaction_type, TransactionType::Chargeback);
 
-        let transaction = self
-            .transactions_store
-            .get_mut_transaction(record.transaction_id, record.client_id)?;
+        let mut transaction = self.get_referenced_transaction(record)?;
+        let currency = transaction.currency.clone();
 
-        if !transaction.disputed {
+        if !transaction.disputed() {
             return Err(Error::ChargeBackNonDisputedTransaction(
                 record.transaction_id,
             ));
         }
 
-        self.clients_store
-            .chargeback(record.client_id, transaction.amount)?;
-        transaction.disputed = false;
+        // Without an amount, the whole disputed amount is charged back
+        let amount = match record.amount {
+            None => transaction.disputed_amount,
+            Some(amount) if amount > transaction.disputed_amount => {
+                return Err(Error::ChargeBackAmountExceedsDisputed {
+                    id: record.transaction_id,
+                    amount,
+                    disputed: transaction.disputed_amount,
+                })
+            }
+            Some(amount) => amount,
+        };
+
+        match transaction.kind {
+            transactions::Kind::Deposit => {
+                // The fee refund is paid along with the chargeback, which checks the client and the house first
+                match self.fee_refund(record.client_id, &transaction, amount) {
+                    Some((house, refund)) => {
+                        self.clients_store.chargeback_with_refund(
+                            record.client_id,
+                            house,
+                            &currency,
+                            amount,
+                            refund,
+                        )?;
+                        self.post(
+                            &currency,
+                            Account::ClientAvailable(house),
+                            Account::ClientAvailable(record.client_id),
+                            refund,
+                        );
+                    }
+                    None => self
+                        .clients_store
+                        .chargeback(record.client_id, &currency, amount)?,
+                }
+
+                // The deposited funds are returned through settlement
+                self.post(
+                    &currency,
+                    Account::ClientHeld(record.client_id),
+                    Account::Settlement,
+                    amount,
+                );
+            }
+            transactions::Kind::Withdrawal => {
+                // The provision becomes a loss as the client is credited back
+                self.clients_store.chargeback_pending_credit(
+                    record.client_id,
+                    &currency,
+                    amount,
+                )?;
+                self.post(
+                    &currency,
+                    Account::ClientHeld(record.client_id),
+                    Account::ClientAvailable(record.client_id),
+                    amount,
+                );
+            }
+            transactions::Kind::Transfer => {
+                // The held funds go back to the source client and the destination is locked
+                let to = Self::transfer_destination(record, &transaction)?;
+                self.clients_store
+                    .chargeback_transfer(record.client_id, to, &currency, amount)?;
+                self.post(
+                    &currency,
+                    Account::ClientHeld(to),
+                    Account::ClientAvailable(record.client_id),
+                    amount,
+                );
+            }
+            // Holds are never disputed
+            transactions::Kind::Authorization => {
+                return Err(Error::ChargeBackNonDisputedTransaction(
+                    record.transaction_id,
+                ))
+            }
+        }
+        transaction.disputed_amount -= amount;
+        transaction.charged_back_amount += amount;
 
-        Ok(())
+        self.transactions_store
+            .upsert_transaction(record.transaction_id, transaction)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    fn process_authorize(&mut self, record: &TransactionRecord) -> Result<(), Error> {
+        assert_eq!(record.transaction_type, TransactionType::Authorize);
 
-    use rust_decimal_macros::dec;
+        let amount = record
+            .amount
+            .ok_or(Error::AuthorizeTransactionMissingAmount(
+                record.transaction_id,
+            ))?;
 
-    #[test]
-    fn test_process_deposit() -> Result<(), Error> {
-        let mut engine = Engine::default();
-        let client_id = 1;
+        if self.transactions_store.has_id(record.transaction_id)? {
+            return Err(Error::TransactionIdAlreadyExists(record.transaction_id));
+        }
 
-        // Should correctly process deposits
-        engine.process_deposit(&TransactionRecord {
-            transaction_type: TransactionType::Deposit,
-            client_id,
-            transaction_id: 9,
+        let currency = record.currency.clone().unwrap_or_default();
+        self.clients_store
+            .move_to_held(record.client_id, &currency, amount)
+            .map_err(|error| match error {
+                Error::ClientCannotDispute {
+                    id,
+                    amount,
+                    available,
+                } => Error::ClientCannotAuthorize {
+                    id,
+                    amount,
+                    available,
+                },
+                error => error,
+            })?;
+        self.post(
+            &currency,
+            Account::ClientAvailable(record.client_id),
+            Account::ClientHeld(record.client_id),
+            amount,
+        );
+
+        let mut hold = transactions::Transaction::new(
+            transactions::Kind::Authorization,
+            record.client_id,
+            amount,
+        )
+        .with_currency(currency);
+        if let Some(expiry) = self.config.hold_expiry {
+            let deadline = self.records_accepted + 1 + expiry;
+            hold.expires_at = Some(deadline);
+            let queued = self.holds.entry(deadline).or_default();
+            if !queued.contains(&record.transaction_id) {
+                queued.push(record.transaction_id);
+            }
+        }
+
+        self.save_new_transaction(record.transaction_id, hold)
+    }
+
+    /// Looks up the hold a capture or void refers to, rejecting holds with nothing left to capture or release
+    fn get_open_hold(
+        &self,
+        record: &TransactionRecord,
+    ) -> Result<transactions::Transaction, Error> {
+        let hold = self.get_referenced_transaction(record)?;
+        if hold.kind != transactions::Kind::Authorization {
+            return Err(Error::NotAHold(record.transaction_id));
+        }
+        if hold.open_hold_amount() <= Decimal::ZERO {
+            return Err(Error::HoldClosed(record.transaction_id));
+        }
+        Ok(hold)
+    }
+
+    fn process_capture(&mut self, record: &TransactionRecord) -> Result<(), Error> {
+        assert_eq!(record.transaction_type, TransactionType::Capture);
+
+        let mut hold = self.get_open_hold(record)?;
+        let currency = hold.currency.clone();
+
+        // Without an amount, whatever is still held is captured
+        let open_amount = hold.open_hold_amount();
+        let amount = match record.amount {
+            None => open_amount,
+            Some(amount) if amount > open_amount => {
+                return Err(Error::CaptureAmountExceedsHold {
+                    id: record.transaction_id,
+                    amount,
+                    held: open_amount,
+                })
+            }
+            Some(amount) => amount,
+        };
+
+        self.clients_store
+            .capture(record.client_id, &currency, amount)?;
+        self.post(
+            &currency,
+            Account::ClientHeld(record.client_id),
+            Account::Settlement,
+            amount,
+        );
+        hold.captured_amount += amount;
+
+        self.transactions_store
+            .upsert_transaction(record.transaction_id, hold)
+    }
+
+    fn process_void(&mut self, record: &TransactionRecord) -> Result<(), Error> {
+        assert_eq!(record.transaction_type, TransactionType::Void);
+
+        let mut hold = self.get_open_hold(record)?;
+        self.release_hold(record.transaction_id, &mut hold)
+    }
+
+    fn process_credit_limit(&mut self, record: &TransactionRecord) -> Result<(), Error> {
+        assert_eq!(record.transaction_type, TransactionType::Limit);
+
+        let credit_limit = record
+            .amount
+            .ok_or(Error::LimitTransactionMissingAmount(record.transaction_id))?;
+        if credit_limit.is_sign_negative() {
+            return Err(Error::NegativeCreditLimit(record.transaction_id));
+        }
+
+        self.clients_store
+            .set_credit_limit(record.client_id, credit_limit)
+    }
+
+    fn process_status_change(
+        &mut self,
+        record: &TransactionRecord,
+        status: Status,
+    ) -> Result<(), Error> {
+        let reason = record
+            .reason
+            .clone()
+            .ok_or(Error::AdminTransactionMissingReason(record.transaction_id))?;
+        let operator = record
+            .operator
+            .clone()
+            .ok_or(Error::AdminTransactionMissingOperator(
+                record.transaction_id,
+            ))?;
+
+        self.clients_store
+            .change_status(record.client_id, status, reason, Some(operator))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::too_many_lines)]
+mod tests {
+    use super::*;
+    use crate::fees::{Fee, FeeRefundPolicy, Fees};
+    use crate::ledger::{AssetDelta, BalanceDelta};
+    use crate::rules::{Check, Rule};
+    use crate::stores::clients::{Balance, StatusChange};
+
+    use proptest::prelude::*;
+    use rust_decimal_macros::dec;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn test_process_deposit() -> Result<(), Error> {
+        let mut engine = Engine::default();
+        let client_id = 1;
+
+        // Should correctly process deposits
+        engine.process_deposit(&TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id: 9,
             amount: Some(dec!(1.1)),
+            ..TransactionRecord::default()
         })?;
         engine.process_deposit(&TransactionRecord {
             transaction_type: TransactionType::Deposit,
             client_id,
             transaction_id: 10,
             amount: Some(dec!(2)),
+            ..TransactionRecord::default()
         })?;
         engine.process_deposit(&TransactionRecord {
             transaction_type: TransactionType::Deposit,
             client_id,
             transaction_id: 11,
             amount: Some(dec!(9)),
+            ..TransactionRecord::default()
         })?;
 
         assert_eq!(
-            engine.clients_store.database[&client_id].available_amount,
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
             dec!(12.1),
             "sequence of deposits should correctly update available amount"
         );
@@ -191,7 +1565,8 @@ mod tests {
                 transaction_type: TransactionType::Deposit,
                 client_id,
                 transaction_id: 999,
-                amount: None
+                amount: None,
+                ..TransactionRecord::default()
             }),
             Err(Error::DepositTransactionMissingAmount(999)),
             "deposit transaction requires amount"
@@ -204,13 +1579,16 @@ mod tests {
                 client_id,
                 transaction_id: 9,
                 amount: Some(dec!(1.1)),
+                ..TransactionRecord::default()
             }),
             Err(Error::TransactionIdAlreadyExists(9)),
             "transaction ids need to be globally unique"
         );
 
         assert_eq!(
-            engine.clients_store.database[&client_id].available_amount,
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
             dec!(12.1),
             "failed deposits should not update available amount"
         );
@@ -223,7 +1601,7 @@ mod tests {
         let mut engine = Engine::default();
 
         let client_id = 1;
-        engine.clients_store.deposit(client_id, dec!(36.22))?;
+        engine.clients_store.deposit(client_id, "", dec!(36.22))?;
 
         // Should correctly process withdrawals
         engine.process_withdrawal(&TransactionRecord {
@@ -231,16 +1609,20 @@ mod tests {
             client_id,
             transaction_id: 10,
             amount: Some(dec!(10.01)),
+            ..TransactionRecord::default()
         })?;
         engine.process_withdrawal(&TransactionRecord {
             transaction_type: TransactionType::Withdrawal,
             client_id,
             transaction_id: 11,
             amount: Some(dec!(10.01)),
+            ..TransactionRecord::default()
         })?;
 
         assert_eq!(
-            engine.clients_store.database[&client_id].available_amount,
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
             dec!(16.20),
             "sequence of withdrawals should correctly update available amount"
         );
@@ -251,7 +1633,8 @@ mod tests {
                 transaction_type: TransactionType::Withdrawal,
                 client_id,
                 transaction_id: 12,
-                amount: Some(dec!(1000))
+                amount: Some(dec!(1000)),
+                ..TransactionRecord::default()
             }),
             Err(Error::ClientCannotWithdrawl {
                 id: client_id,
@@ -267,7 +1650,8 @@ mod tests {
                 transaction_type: TransactionType::Withdrawal,
                 client_id,
                 transaction_id: 999,
-                amount: None
+                amount: None,
+                ..TransactionRecord::default()
             }),
             Err(Error::WithdrawalTransactionMissingAmount(999)),
             "should fail if amount is missing"
@@ -280,13 +1664,16 @@ mod tests {
                 client_id,
                 transaction_id: 10,
                 amount: Some(dec!(0.1)),
+                ..TransactionRecord::default()
             }),
             Err(Error::TransactionIdAlreadyExists(10)),
             "should fail if transaction id is reused"
         );
 
         assert_eq!(
-            engine.clients_store.database[&client_id].available_amount,
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
             dec!(16.20),
             "failed withdrawals should not update available amount"
         );
@@ -306,6 +1693,7 @@ mod tests {
             client_id,
             transaction_id,
             amount: Some(dec!(101.95)),
+            ..TransactionRecord::default()
         })?;
 
         engine.process_dispute(&TransactionRecord {
@@ -313,16 +1701,21 @@ mod tests {
             client_id,
             transaction_id,
             amount: None,
+            ..TransactionRecord::default()
         })?;
 
         assert_eq!(
-            engine.clients_store.database[&client_id].available_amount,
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
             dec!(0.00),
             "dispute should lower available amount"
         );
 
         assert_eq!(
-            engine.clients_store.database[&client_id].held_amount,
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .held_amount,
             dec!(101.95),
             "dispute should update held amount"
         );
@@ -333,6 +1726,7 @@ mod tests {
                 client_id,
                 transaction_id,
                 amount: None,
+                ..TransactionRecord::default()
             }),
             Err(Error::DisputeAlreadyDisputedTransaction(transaction_id)),
             "should not be able to dispute an already disputed transaction"
@@ -345,6 +1739,7 @@ mod tests {
             client_id,
             transaction_id: other_deposit_tx,
             amount: Some(dec!(200)),
+            ..TransactionRecord::default()
         })?;
 
         engine.process_withdrawal(&TransactionRecord {
@@ -352,6 +1747,7 @@ mod tests {
             client_id,
             transaction_id: withdrawal_tx,
             amount: Some(dec!(100)),
+            ..TransactionRecord::default()
         })?;
 
         assert_eq!(
@@ -360,6 +1756,7 @@ mod tests {
                 client_id,
                 transaction_id: withdrawal_tx,
                 amount: None,
+                ..TransactionRecord::default()
             }),
             Err(Error::DisputeNonDepositTransaction(withdrawal_tx)),
             "cannot dispute withdrawal transactions"
@@ -371,6 +1768,7 @@ mod tests {
                 client_id,
                 transaction_id: other_deposit_tx,
                 amount: None,
+                ..TransactionRecord::default()
             }),
             Err(Error::ClientCannotDispute {
                 id: client_id,
@@ -386,6 +1784,7 @@ mod tests {
                 client_id,
                 transaction_id: 99999,
                 amount: None,
+                ..TransactionRecord::default()
             }),
             Err(Error::TransactionNotExists(99999)),
             "cannot dispute non-existant transaction"
@@ -406,6 +1805,7 @@ mod tests {
             client_id,
             transaction_id,
             amount: Some(dec!(101.95)),
+            ..TransactionRecord::default()
         })?;
 
         engine.process_dispute(&TransactionRecord {
@@ -413,6 +1813,7 @@ mod tests {
             client_id,
             transaction_id,
             amount: None,
+            ..TransactionRecord::default()
         })?;
 
         engine.process_resolve(&TransactionRecord {
@@ -420,16 +1821,21 @@ mod tests {
             client_id,
             transaction_id,
             amount: None,
+            ..TransactionRecord::default()
         })?;
 
         assert_eq!(
-            engine.clients_store.database[&client_id].available_amount,
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
             dec!(101.95),
             "resolving a dispute should put back held funds into available"
         );
 
         assert_eq!(
-            engine.clients_store.database[&client_id].held_amount,
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .held_amount,
             dec!(0),
             "resolving a dispute should put back held funds into available"
         );
@@ -440,6 +1846,7 @@ mod tests {
                 client_id,
                 transaction_id,
                 amount: None,
+                ..TransactionRecord::default()
             }),
             Err(Error::ResolveNonDisputedTransaction(transaction_id)),
             "should not be able to resolve a non-disputed transaction"
@@ -451,6 +1858,7 @@ mod tests {
                 client_id,
                 transaction_id: 9875,
                 amount: None,
+                ..TransactionRecord::default()
             }),
             Err(Error::TransactionNotExists(9875)),
             "should not be able to resolve non-existant transaction"
@@ -471,6 +1879,7 @@ mod tests {
             client_id,
             transaction_id,
             amount: Some(dec!(101.95)),
+            ..TransactionRecord::default()
         })?;
 
         engine.process_dispute(&TransactionRecord {
@@ -478,6 +1887,7 @@ mod tests {
             client_id,
             transaction_id,
             amount: None,
+            ..TransactionRecord::default()
         })?;
 
         engine.process_chargeback(&TransactionRecord {
@@ -485,16 +1895,21 @@ mod tests {
             client_id,
             transaction_id,
             amount: None,
+            ..TransactionRecord::default()
         })?;
 
         assert_eq!(
-            engine.clients_store.database[&client_id].available_amount,
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
             dec!(0),
             "chargeback should not put back held funds into available"
         );
 
         assert_eq!(
-            engine.clients_store.database[&client_id].held_amount,
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .held_amount,
             dec!(0),
             "chargeback should remove funds from held"
         );
@@ -505,6 +1920,7 @@ mod tests {
                 client_id,
                 transaction_id,
                 amount: None,
+                ..TransactionRecord::default()
             }),
             Err(Error::ChargeBackNonDisputedTransaction(transaction_id)),
             "should not be able to chargeback a non-disputed transaction"
@@ -516,6 +1932,7 @@ mod tests {
                 client_id,
                 transaction_id: 9875,
                 amount: None,
+                ..TransactionRecord::default()
             }),
             Err(Error::TransactionNotExists(9875)),
             "should not be able to chargeback non-existant transaction"
@@ -523,4 +1940,1888 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_process_withdrawal_dispute() -> Result<(), Error> {
+        let mut engine = Engine::default().with_config(Config {
+            dispute_policy: DisputePolicy::DepositsAndWithdrawals,
+            ..Config::default()
+        })?;
+
+        let client_id = 1;
+        let withdrawal_tx = 67;
+        let other_withdrawal_tx = 68;
+
+        engine.process_deposit(&TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id: 66,
+            amount: Some(dec!(200)),
+            ..TransactionRecord::default()
+        })?;
+        engine.process_withdrawal(&TransactionRecord {
+            transaction_type: TransactionType::Withdrawal,
+            client_id,
+            transaction_id: withdrawal_tx,
+            amount: Some(dec!(50)),
+            ..TransactionRecord::default()
+        })?;
+        engine.process_withdrawal(&TransactionRecord {
+            transaction_type: TransactionType::Withdrawal,
+            client_id,
+            transaction_id: other_withdrawal_tx,
+            amount: Some(dec!(25)),
+            ..TransactionRecord::default()
+        })?;
+
+        // Disputing a withdrawal holds a pending credit without touching available funds
+        engine.process_dispute(&TransactionRecord {
+            transaction_type: TransactionType::Dispute,
+            client_id,
+            transaction_id: withdrawal_tx,
+            amount: None,
+            ..TransactionRecord::default()
+        })?;
+        assert_eq!(
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
+            dec!(125),
+            "withdrawal dispute should not change available amount"
+        );
+        assert_eq!(
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .held_amount,
+            dec!(50),
+            "withdrawal dispute should hold the withdrawn amount"
+        );
+
+        // Resolving drops the hold
+        engine.process_resolve(&TransactionRecord {
+            transaction_type: TransactionType::Resolve,
+            client_id,
+            transaction_id: withdrawal_tx,
+            amount: None,
+            ..TransactionRecord::default()
+        })?;
+        assert_eq!(
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
+            dec!(125),
+            "resolving a withdrawal dispute should not change available amount"
+        );
+        assert_eq!(
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .held_amount,
+            dec!(0),
+            "resolving a withdrawal dispute should drop the hold"
+        );
+
+        // Chargeback credits the client back and locks the account
+        engine.process_dispute(&TransactionRecord {
+            transaction_type: TransactionType::Dispute,
+            client_id,
+            transaction_id: other_withdrawal_tx,
+            amount: None,
+            ..TransactionRecord::default()
+        })?;
+        engine.process_chargeback(&TransactionRecord {
+            transaction_type: TransactionType::Chargeback,
+            client_id,
+            transaction_id: other_withdrawal_tx,
+            amount: None,
+            ..TransactionRecord::default()
+        })?;
+        assert_eq!(
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
+            dec!(150),
+            "withdrawal chargeback should credit the client back"
+        );
+        assert_eq!(
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .held_amount,
+            dec!(0),
+            "withdrawal chargeback should drop the hold"
+        );
+        assert!(
+            engine.clients_store.database[&client_id].status == Status::Locked,
+            "chargeback should lock the client"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_partial_dispute() -> Result<(), Error> {
+        let mut engine = Engine::default();
+
+        let client_id = 1;
+        let transaction_id = 55;
+
+        engine.process_deposit(&TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id,
+            amount: Some(dec!(100)),
+            ..TransactionRecord::default()
+        })?;
+
+        // Should dispute only part of the deposit
+        engine.process_dispute(&TransactionRecord {
+            transaction_type: TransactionType::Dispute,
+            client_id,
+            transaction_id,
+            amount: Some(dec!(30)),
+            ..TransactionRecord::default()
+        })?;
+        assert_eq!(
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .held_amount,
+            dec!(30),
+            "partial dispute should only hold the disputed amount"
+        );
+
+        assert_eq!(
+            engine.process_dispute(&TransactionRecord {
+                transaction_type: TransactionType::Dispute,
+                client_id,
+                transaction_id,
+                amount: Some(dec!(80)),
+                ..TransactionRecord::default()
+            }),
+            Err(Error::DisputeAmountExceedsTransaction {
+                id: transaction_id,
+                amount: dec!(80),
+                disputable: dec!(70)
+            }),
+            "disputed total cannot exceed the original deposit"
+        );
+
+        // Without an amount, the rest of the deposit is disputed
+        engine.process_dispute(&TransactionRecord {
+            transaction_type: TransactionType::Dispute,
+            client_id,
+            transaction_id,
+            amount: None,
+            ..TransactionRecord::default()
+        })?;
+
+        engine.process_resolve(&TransactionRecord {
+            transaction_type: TransactionType::Resolve,
+            client_id,
+            transaction_id,
+            amount: Some(dec!(20)),
+            ..TransactionRecord::default()
+        })?;
+        assert_eq!(
+            engine.process_resolve(&TransactionRecord {
+                transaction_type: TransactionType::Resolve,
+                client_id,
+                transaction_id,
+                amount: Some(dec!(81)),
+                ..TransactionRecord::default()
+            }),
+            Err(Error::ResolveAmountExceedsDisputed {
+                id: transaction_id,
+                amount: dec!(81),
+                disputed: dec!(80)
+            }),
+            "cannot resolve more than is in dispute"
+        );
+
+        engine.process_chargeback(&TransactionRecord {
+            transaction_type: TransactionType::Chargeback,
+            client_id,
+            transaction_id,
+            amount: Some(dec!(50)),
+            ..TransactionRecord::default()
+        })?;
+        assert_eq!(
+            engine.process_chargeback(&TransactionRecord {
+                transaction_type: TransactionType::Chargeback,
+                client_id,
+                transaction_id,
+                amount: Some(dec!(31)),
+                ..TransactionRecord::default()
+            }),
+            Err(Error::ChargeBackAmountExceedsDisputed {
+                id: transaction_id,
+                amount: dec!(31),
+                disputed: dec!(30)
+            }),
+            "cannot chargeback more than is in dispute"
+        );
+
+        assert_eq!(
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount,
+            dec!(20),
+            "resolved amount should be available again"
+        );
+        assert_eq!(
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .held_amount,
+            dec!(30),
+            "charged back amount should be removed from held"
+        );
+
+        let transaction = engine
+            .transactions_store
+            .get_transaction(transaction_id, client_id)?;
+        assert_eq!(transaction.disputed_amount, dec!(30));
+        assert_eq!(transaction.resolved_amount, dec!(20));
+        assert_eq!(transaction.charged_back_amount, dec!(50));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_status_change() -> Result<(), Error> {
+        let mut engine = Engine::default();
+
+        let client_id = 1;
+
+        engine.process_deposit(&TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id: 1,
+            amount: Some(dec!(10)),
+            ..TransactionRecord::default()
+        })?;
+        engine.process_deposit(&TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client_id,
+            transaction_id: 2,
+            amount: Some(dec!(5)),
+            ..TransactionRecord::default()
+        })?;
+        engine.process_dispute(&TransactionRecord {
+            transaction_type: TransactionType::Dispute,
+            client_id,
+            transaction_id: 1,
+            ..TransactionRecord::default()
+        })?;
+        engine.process_dispute(&TransactionRecord {
+            transaction_type: TransactionType::Dispute,
+            client_id,
+            transaction_id: 2,
+            ..TransactionRecord::default()
+        })?;
+        engine.process_chargeback(&TransactionRecord {
+            transaction_type: TransactionType::Chargeback,
+            client_id,
+            transaction_id: 1,
+            ..TransactionRecord::default()
+        })?;
+
+        // Admin transactions need a reason and an operator
+        assert_eq!(
+            engine.handle(&TransactionRecord {
+                transaction_type: TransactionType::Unlock,
+                client_id,
+                transaction_id: 100,
+                operator: Some("alice".to_string()),
+                ..TransactionRecord::default()
+            }),
+            Err(Error::AdminTransactionMissingReason(100)),
+            "unlock requires a reason"
+        );
+        assert_eq!(
+            engine.handle(&TransactionRecord {
+                transaction_type: TransactionType::Unlock,
+                client_id,
+                transaction_id: 100,
+                reason: Some("chargeback reversed".to_string()),
+                ..TransactionRecord::default()
+            }),
+            Err(Error::AdminTransactionMissingOperator(100)),
+            "unlock requires an operator"
+        );
+
+        // Unlocking keeps the held funds of the open dispute, which can then be resolved
+        engine.handle(&TransactionRecord {
+            transaction_type: TransactionType::Unlock,
+            client_id,
+            transaction_id: 100,
+            reason: Some("chargeback reversed".to_string()),
+            operator: Some("alice".to_string()),
+            ..TransactionRecord::default()
+        })?;
+        assert_eq!(
+            engine.clients_store.database[&client_id].status,
+            Status::Active,
+            "unlock should reactivate the client"
+        );
+        assert_eq!(
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .held_amount,
+            dec!(5),
+            "unlock should not release held funds"
+        );
+
+        // Closing is rejected while funds are held
+        let close = TransactionRecord {
+            transaction_type: TransactionType::Close,
+            client_id,
+            transaction_id: 101,
+            reason: Some("client request".to_string()),
+            operator: Some("bob".to_string()),
+            ..TransactionRecord::default()
+        };
+        assert_eq!(
+            engine.handle(&close),
+            Err(Error::ClientCannotClose {
+                id: client_id,
+                held: dec!(5)
+            }),
+            "cannot close a client with held funds"
+        );
+
+        engine.process_resolve(&TransactionRecord {
+            transaction_type: TransactionType::Resolve,
+            client_id,
+            transaction_id: 2,
+            ..TransactionRecord::default()
+        })?;
+        engine.handle(&close)?;
+        assert_eq!(
+            engine.handle(&TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client_id,
+                transaction_id: 3,
+                amount: Some(dec!(1)),
+                ..TransactionRecord::default()
+            }),
+            Err(Error::ClientClosed(client_id)),
+            "closed clients cannot transact"
+        );
+
+        assert_eq!(
+            engine
+                .clients_store
+                .get_status_changes(client_id)?
+                .into_iter()
+                .map(|change| (change.to, change.operator))
+                .collect::<Vec<_>>(),
+            vec![
+                (Status::Locked, None),
+                (Status::Active, Some("alice".to_string())),
+                (Status::Closed, Some("bob".to_string())),
+            ],
+            "every status change should be recorded"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_currency() -> Result<(), Error> {
+        let mut engine = Engine::default();
+        let client_id = 1;
+        let record =
+            |transaction_type, transaction_id, amount, currency: Option<&str>| TransactionRecord {
+                transaction_type,
+                client_id,
+                transaction_id,
+                amount,
+                currency: currency.map(str::to_string),
+                ..TransactionRecord::default()
+            };
+
+        engine.handle(&record(
+            TransactionType::Deposit,
+            1,
+            Some(dec!(100)),
+            Some("USD"),
+        ))?;
+        engine.handle(&record(
+            TransactionType::Deposit,
+            2,
+            Some(dec!(5)),
+            Some("EUR"),
+        ))?;
+        engine.handle(&record(TransactionType::Deposit, 3, Some(dec!(1)), None))?;
+
+        assert_eq!(
+            engine.handle(&record(
+                TransactionType::Withdrawal,
+                4,
+                Some(dec!(10)),
+                Some("EUR")
+            )),
+            Err(Error::ClientCannotWithdrawl {
+                id: client_id,
+                amount: dec!(10),
+                available: dec!(5),
+            }),
+            "withdrawals should never use the funds of another currency"
+        );
+        engine.handle(&record(
+            TransactionType::Withdrawal,
+            4,
+            Some(dec!(2)),
+            Some("EUR"),
+        ))?;
+
+        // Disputes act on the currency of the original deposit
+        engine.handle(&record(TransactionType::Dispute, 1, Some(dec!(30)), None))?;
+        assert_eq!(
+            engine.handle(&record(TransactionType::Resolve, 1, None, Some("EUR"))),
+            Err(Error::TransactionWithWrongCurrency(1, "EUR".to_string())),
+            "records naming another currency than the original transaction should be rejected"
+        );
+        engine.handle(&record(TransactionType::Chargeback, 1, None, Some("USD")))?;
+
+        let client = &engine.clients_store.database[&client_id];
+        assert_eq!(
+            client.balance("USD"),
+            Balance {
+                available_amount: dec!(70),
+                held_amount: dec!(0),
+            }
+        );
+        assert_eq!(client.balance("EUR").available_amount, dec!(3));
+        assert_eq!(client.balance("").available_amount, dec!(1));
+        assert_eq!(
+            client.status,
+            Status::Locked,
+            "a chargeback in any currency should lock the client"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal() -> Result<(), Error> {
+        let mut engine = Engine::default().with_config(Config {
+            dispute_policy: DisputePolicy::DepositsAndWithdrawals,
+            journal: JournalMode::EveryRecord,
+            ..Config::default()
+        })?;
+        let record = |transaction_type, client_id, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            ..TransactionRecord::default()
+        };
+
+        engine.handle(&record(TransactionType::Deposit, 1, 1, Some(dec!(10))))?;
+        engine.handle(&record(TransactionType::Withdrawal, 1, 2, Some(dec!(3))))?;
+        engine.handle(&record(TransactionType::Dispute, 1, 1, Some(dec!(4))))?;
+        engine.handle(&record(TransactionType::Chargeback, 1, 1, None))?;
+        engine.handle(&record(TransactionType::Deposit, 2, 3, Some(dec!(5))))?;
+        engine.handle(&record(TransactionType::Withdrawal, 2, 4, Some(dec!(2))))?;
+        engine.handle(&record(TransactionType::Dispute, 2, 4, None))?;
+        engine.handle(&record(TransactionType::Resolve, 2, 4, Some(dec!(0.5))))?;
+        engine.handle(&record(TransactionType::Chargeback, 2, 4, None))?;
+
+        let trial_balance = engine.trial_balance()?;
+        assert_eq!(trial_balance.debits, trial_balance.credits);
+        assert_eq!(
+            trial_balance
+                .balances
+                .into_iter()
+                .map(|((_, account), balance)| (account, balance))
+                .collect::<Vec<_>>(),
+            vec![
+                (Account::ClientAvailable(1), dec!(3)),
+                (Account::ClientAvailable(2), dec!(4.5)),
+                (Account::ClientHeld(1), dec!(0)),
+                (Account::ClientHeld(2), dec!(0)),
+                (Account::Settlement, dec!(6)),
+                (Account::ChargebackLoss, dec!(1.5)),
+            ],
+            "settlement should hold the net funds received and the loss account the withdrawal chargebacks"
+        );
+        assert_eq!(
+            engine.journal().map(|journal| journal.entries().len()),
+            Some(9),
+            "every accepted record should post one entry"
+        );
+
+        // A store that drifts from the journal should be caught by the trial balance
+        engine
+            .clients_store
+            .database
+            .get_mut(&2)
+            .expect("client 2 should exist")
+            .balances
+            .entry(String::new())
+            .or_default()
+            .available_amount += dec!(1);
+        assert_eq!(
+            engine.trial_balance().map(|_| ()),
+            Err(Error::JournalMismatch {
+                account: "client 2 available".to_string(),
+                journal: dec!(4.5),
+                store: dec!(5.5),
+            })
+        );
+        assert!(
+            engine
+                .handle(&record(TransactionType::Deposit, 3, 5, Some(dec!(1))))
+                .is_err(),
+            "records should be rejected while the trial balance fails"
+        );
+        assert!(
+            !engine.clients_store.database.contains_key(&3),
+            "rejected record should be rolled back"
+        );
+        assert_eq!(
+            engine.journal().map(|journal| journal.entries().len()),
+            Some(9),
+            "rejected record should not stay in the journal"
+        );
+
+        assert_eq!(
+            Engine::default().trial_balance().map(|_| ()),
+            Err(Error::JournalDisabled)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_transfer() -> Result<(), Error> {
+        let mut engine = Engine::default().with_config(Config {
+            event_log: true,
+            journal: JournalMode::EveryRecord,
+            ..Config::default()
+        })?;
+        let transfer = |transaction_id, amount, to_client_id| TransactionRecord {
+            transaction_type: TransactionType::Transfer,
+            client_id: 1,
+            transaction_id,
+            amount,
+            to_client_id,
+            ..TransactionRecord::default()
+        };
+        let balance =
+            |engine: &Engine, client_id| engine.clients_store.database[&client_id].balance("");
+
+        engine.handle(&TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id: 1,
+            amount: Some(dec!(10)),
+            ..TransactionRecord::default()
+        })?;
+
+        // Should move the funds and record a single linked transaction
+        engine.handle(&transfer(2, Some(dec!(4)), Some(2)))?;
+        assert_eq!(balance(&engine, 1).available_amount, dec!(6));
+        assert_eq!(balance(&engine, 2).available_amount, dec!(4));
+        assert_eq!(
+            engine.transactions_store.database[&2],
+            transactions::Transaction {
+                sequence: 2,
+                ..transactions::Transaction::new(transactions::Kind::Transfer, 1, dec!(4))
+                    .with_counterparty(2)
+            },
+            "transfer should be stored once, linked to both clients"
+        );
+        assert_eq!(
+            engine
+                .ledger()
+                .and_then(|ledger| ledger.events().last())
+                .map(|event| event.deltas.iter().map(|d| d.client_id).collect::<Vec<_>>()),
+            Some(vec![1, 2]),
+            "the event log should record the change to both clients"
+        );
+
+        // Invalid transfers should be rejected without changing anything
+        assert_eq!(
+            engine.handle(&transfer(3, None, Some(2))),
+            Err(Error::TransferTransactionMissingAmount(3))
+        );
+        assert_eq!(
+            engine.handle(&transfer(3, Some(dec!(1)), None)),
+            Err(Error::TransferTransactionMissingDestination(3))
+        );
+        assert_eq!(
+            engine.handle(&transfer(3, Some(dec!(1)), Some(1))),
+            Err(Error::TransferToSameClient(3))
+        );
+        assert_eq!(
+            engine.handle(&transfer(2, Some(dec!(1)), Some(2))),
+            Err(Error::TransactionIdAlreadyExists(2))
+        );
+        assert_eq!(
+            engine.handle(&transfer(3, Some(dec!(7)), Some(2))),
+            Err(Error::ClientCannotWithdrawl {
+                id: 1,
+                amount: dec!(7),
+                available: dec!(6)
+            })
+        );
+        engine.clients_store.database.get_mut(&2).unwrap().status = Status::Frozen;
+        assert_eq!(
+            engine.handle(&transfer(3, Some(dec!(1)), Some(2))),
+            Err(Error::ClientFrozen(2))
+        );
+        engine.clients_store.database.get_mut(&2).unwrap().status = Status::Active;
+        assert_eq!(balance(&engine, 1).available_amount, dec!(6));
+        assert_eq!(balance(&engine, 2).available_amount, dec!(4));
+
+        // The source client can dispute the transfer, holding the funds at the destination
+        engine.handle(&TransactionRecord {
+            transaction_type: TransactionType::Dispute,
+            client_id: 1,
+            transaction_id: 2,
+            ..TransactionRecord::default()
+        })?;
+        assert_eq!(
+            balance(&engine, 2),
+            Balance {
+                available_amount: dec!(0),
+                held_amount: dec!(4),
+            }
+        );
+        engine.handle(&TransactionRecord {
+            transaction_type: TransactionType::Resolve,
+            client_id: 1,
+            transaction_id: 2,
+            amount: Some(dec!(1)),
+            ..TransactionRecord::default()
+        })?;
+        assert_eq!(
+            balance(&engine, 2),
+            Balance {
+                available_amount: dec!(1),
+                held_amount: dec!(3),
+            }
+        );
+
+        // A chargeback returns the held funds to the source and locks the destination
+        engine.handle(&TransactionRecord {
+            transaction_type: TransactionType::Chargeback,
+            client_id: 1,
+            transaction_id: 2,
+            ..TransactionRecord::default()
+        })?;
+        assert_eq!(
+            balance(&engine, 1),
+            Balance {
+                available_amount: dec!(9),
+                held_amount: dec!(0),
+            }
+        );
+        assert_eq!(
+            balance(&engine, 2),
+            Balance {
+                available_amount: dec!(1),
+                held_amount: dec!(0),
+            }
+        );
+        assert_eq!(engine.clients_store.database[&1].status, Status::Active);
+        assert_eq!(engine.clients_store.database[&2].status, Status::Locked);
+
+        let trial_balance = engine.trial_balance()?;
+        assert_eq!(trial_balance.debits, trial_balance.credits);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fees() -> Result<(), Error> {
+        let house = 100;
+        let mut fees = FeeSchedule {
+            default: Fees {
+                deposit: Some(Fee::Percentage(dec!(2))),
+                withdrawal: Some(Fee::Flat(dec!(0.5))),
+            },
+            refund_policy: FeeRefundPolicy::Refund,
+            ..FeeSchedule::new(house)
+        };
+        fees.overrides.insert(2, Fees::default());
+        let mut engine = Engine::default().with_config(Config {
+            event_log: true,
+            journal: JournalMode::EveryRecord,
+            fees: Some(fees),
+            ..Config::default()
+        })?;
+        let record = |transaction_type, client_id, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            ..TransactionRecord::default()
+        };
+        let available = |engine: &Engine, client_id| {
+            engine.clients_store.database[&client_id]
+                .balance("")
+                .available_amount
+        };
+
+        // Fees are taken from the deposited amount and added on top of the withdrawn amount
+        engine.handle(&record(TransactionType::Deposit, 1, 1, Some(dec!(100))))?;
+        engine.handle(&record(TransactionType::Withdrawal, 1, 2, Some(dec!(10))))?;
+        assert_eq!(available(&engine, 1), dec!(87.5));
+        assert_eq!(available(&engine, house), dec!(2.5));
+        assert_eq!(
+            engine.transactions_store.database[&1].fee,
+            dec!(2),
+            "the fee should be stored with the transaction"
+        );
+        assert_eq!(
+            engine
+                .ledger()
+                .and_then(|ledger| ledger.events().last())
+                .map(|event| event.deltas.iter().map(|d| d.client_id).collect::<Vec<_>>()),
+            Some(vec![1, house]),
+            "the event log should record the fee credited to the house"
+        );
+
+        // The fee counts towards the funds a withdrawal needs
+        assert_eq!(
+            engine.handle(&record(TransactionType::Withdrawal, 1, 3, Some(dec!(87.5)))),
+            Err(Error::ClientCannotWithdrawl {
+                id: 1,
+                amount: dec!(88),
+                available: dec!(87.5)
+            })
+        );
+        assert_eq!(available(&engine, house), dec!(2.5));
+
+        // Clients with an override use their own fees
+        engine.handle(&record(TransactionType::Deposit, 2, 4, Some(dec!(50))))?;
+        engine.handle(&record(TransactionType::Withdrawal, 2, 5, Some(dec!(50))))?;
+        assert_eq!(available(&engine, 2), dec!(0));
+        assert_eq!(available(&engine, house), dec!(2.5));
+
+        // A chargeback refunds the matching share of the deposit fee, half the fee for half the credited amount
+        engine.handle(&record(TransactionType::Dispute, 1, 1, Some(dec!(49))))?;
+        engine.handle(&record(TransactionType::Chargeback, 1, 1, None))?;
+        assert_eq!(
+            engine.clients_store.database[&1].balance(""),
+            Balance {
+                available_amount: dec!(39.5),
+                held_amount: dec!(0),
+            }
+        );
+        assert_eq!(available(&engine, house), dec!(1.5));
+
+        // A house that cannot be credited rejects the record before the client is credited
+        engine
+            .clients_store
+            .change_status(house, Status::Frozen, "audit".to_string(), None)?;
+        assert_eq!(
+            engine.check_fee_credit(dec!(0.2)),
+            Err(Error::ClientFrozen(house))
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Deposit, 3, 6, Some(dec!(10)))),
+            Err(Error::ClientFrozen(house))
+        );
+        assert!(!engine.clients_store.database.contains_key(&3));
+
+        let trial_balance = engine.trial_balance()?;
+        assert_eq!(trial_balance.debits, trial_balance.credits);
+        assert_eq!(
+            engine.get_clients()?.last().map(|(id, _)| *id),
+            Some(house),
+            "the house should be exported like any other client"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fee_chargeback() -> Result<(), Error> {
+        let house = 9;
+        let record = |transaction_type, amount| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id: 1,
+            amount,
+            ..TransactionRecord::default()
+        };
+
+        for (refund_policy, client_available, house_available) in [
+            (FeeRefundPolicy::Keep, dec!(0), dec!(2)),
+            (FeeRefundPolicy::Refund, dec!(2), dec!(0)),
+        ] {
+            let mut engine = Engine::default().with_config(Config {
+                journal: JournalMode::EveryRecord,
+                fees: Some(FeeSchedule {
+                    default: Fees {
+                        deposit: Some(Fee::Percentage(dec!(2))),
+                        withdrawal: None,
+                    },
+                    refund_policy,
+                    ..FeeSchedule::new(house)
+                }),
+                ..Config::default()
+            })?;
+
+            // A dispute without an amount holds everything the deposit credited
+            engine.handle(&record(TransactionType::Deposit, Some(dec!(100))))?;
+            engine.handle(&record(TransactionType::Dispute, None))?;
+            assert_eq!(
+                engine.clients_store.database[&1].balance(""),
+                Balance {
+                    available_amount: dec!(0),
+                    held_amount: dec!(98),
+                }
+            );
+            assert_eq!(
+                engine.handle(&record(TransactionType::Dispute, Some(dec!(1)))),
+                Err(Error::DisputeAmountExceedsTransaction {
+                    id: 1,
+                    amount: dec!(1),
+                    disputable: dec!(0)
+                }),
+                "the fee was never credited, so it cannot be disputed"
+            );
+
+            engine.handle(&record(TransactionType::Chargeback, None))?;
+            let client = &engine.clients_store.database[&1];
+            assert_eq!(
+                client.balance(""),
+                Balance {
+                    available_amount: client_available,
+                    held_amount: dec!(0),
+                },
+                "{refund_policy:?}"
+            );
+            assert_eq!(client.status, Status::Locked);
+            assert_eq!(
+                engine.clients_store.database[&house]
+                    .balance("")
+                    .available_amount,
+                house_available,
+                "{refund_policy:?}"
+            );
+            let trial_balance = engine.trial_balance()?;
+            assert_eq!(trial_balance.debits, trial_balance.credits);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fee_refund_house_cannot_cover() -> Result<(), Error> {
+        let house = 9;
+        let mut engine = Engine::default().with_config(Config {
+            journal: JournalMode::EveryRecord,
+            fees: Some(FeeSchedule {
+                default: Fees {
+                    deposit: Some(Fee::Percentage(dec!(2))),
+                    withdrawal: None,
+                },
+                refund_policy: FeeRefundPolicy::Refund,
+                ..FeeSchedule::new(house)
+            }),
+            ..Config::default()
+        })?;
+        let record = |transaction_type, client_id, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            ..TransactionRecord::default()
+        };
+
+        engine.handle(&record(TransactionType::Deposit, 1, 1, Some(dec!(100))))?;
+        engine.handle(&record(TransactionType::Dispute, 1, 1, None))?;
+        // The house pays its fees out before the chargeback
+        engine.handle(&record(
+            TransactionType::Withdrawal,
+            house,
+            2,
+            Some(dec!(2)),
+        ))?;
+
+        assert_eq!(
+            engine.handle(&record(TransactionType::Chargeback, 1, 1, None)),
+            Err(Error::HouseCannotRefund {
+                id: house,
+                amount: dec!(2),
+                available: dec!(0)
+            })
+        );
+        let client = &engine.clients_store.database[&1];
+        assert_eq!(client.balance("").held_amount, dec!(98));
+        assert_eq!(
+            client.status,
+            Status::Active,
+            "a rejected chargeback should not lock the client"
+        );
+
+        // Once the house can cover the refund, the chargeback goes through
+        engine.handle(&record(TransactionType::Deposit, house, 3, Some(dec!(2))))?;
+        engine.handle(&record(TransactionType::Chargeback, 1, 1, None))?;
+        let client = &engine.clients_store.database[&1];
+        assert_eq!(
+            client.balance(""),
+            Balance {
+                available_amount: dec!(2),
+                held_amount: dec!(0),
+            }
+        );
+        assert_eq!(client.status, Status::Locked);
+        assert_eq!(
+            engine.clients_store.database[&house]
+                .balance("")
+                .available_amount,
+            dec!(0)
+        );
+        let trial_balance = engine.trial_balance()?;
+        assert_eq!(trial_balance.debits, trial_balance.credits);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_holds() -> Result<(), Error> {
+        let mut engine = Engine::default().with_config(Config {
+            event_log: true,
+            journal: JournalMode::EveryRecord,
+            hold_expiry: Some(2),
+            ..Config::default()
+        })?;
+        let record = |transaction_type, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount,
+            ..TransactionRecord::default()
+        };
+        let balance = |engine: &Engine| engine.clients_store.database[&1].balance("");
+
+        engine.handle(&record(TransactionType::Deposit, 1, Some(dec!(100))))?;
+
+        // An authorization holds the funds until they are captured
+        engine.handle(&record(TransactionType::Authorize, 2, Some(dec!(30))))?;
+        engine.handle(&record(TransactionType::Capture, 2, Some(dec!(10))))?;
+        assert_eq!(
+            balance(&engine),
+            Balance {
+                available_amount: dec!(70),
+                held_amount: dec!(20),
+            }
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Capture, 2, Some(dec!(25)))),
+            Err(Error::CaptureAmountExceedsHold {
+                id: 2,
+                amount: dec!(25),
+                held: dec!(20)
+            })
+        );
+
+        // The rest of the hold is released after two more accepted records
+        engine.handle(&record(TransactionType::Deposit, 3, Some(dec!(5))))?;
+        assert_eq!(
+            balance(&engine),
+            Balance {
+                available_amount: dec!(95),
+                held_amount: dec!(0),
+            },
+            "the hold should expire with the second accepted record after it"
+        );
+        assert_eq!(
+            engine
+                .ledger()
+                .and_then(|ledger| ledger.events().last())
+                .map(|event| event.deltas.clone()),
+            Some(vec![BalanceDelta {
+                client_id: 1,
+                assets: vec![AssetDelta {
+                    currency: String::new(),
+                    available: dec!(25),
+                    held: dec!(-20),
+                }],
+                status: None,
+                credit_limit: None,
+            }]),
+            "the released funds should be logged with the record the hold expired on"
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Capture, 2, None)),
+            Err(Error::HoldClosed(2))
+        );
+
+        // A void releases the whole hold
+        assert_eq!(
+            engine.handle(&record(TransactionType::Authorize, 4, Some(dec!(200)))),
+            Err(Error::ClientCannotAuthorize {
+                id: 1,
+                amount: dec!(200),
+                available: dec!(95)
+            })
+        );
+        engine.handle(&record(TransactionType::Authorize, 5, Some(dec!(10))))?;
+        engine.handle(&record(TransactionType::Void, 5, None))?;
+        assert_eq!(balance(&engine).available_amount, dec!(95));
+        assert_eq!(
+            engine.handle(&record(TransactionType::Void, 5, None)),
+            Err(Error::HoldClosed(5))
+        );
+
+        // Holds and other transactions do not mix
+        assert_eq!(
+            engine.handle(&record(TransactionType::Void, 1, None)),
+            Err(Error::NotAHold(1))
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Dispute, 5, None)),
+            Err(Error::DisputeNonDepositTransaction(5))
+        );
+
+        let trial_balance = engine.trial_balance()?;
+        assert_eq!(trial_balance.debits, trial_balance.credits);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_window() -> Result<(), Error> {
+        let mut engine = Engine::default().with_config(Config {
+            journal: JournalMode::EveryRecord,
+            dispute_window: Some(2),
+            ..Config::default()
+        })?;
+        let record = |transaction_type, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount,
+            ..TransactionRecord::default()
+        };
+        let stored = |engine: &Engine| {
+            let mut ids: Vec<_> = engine.transactions_store.database.keys().copied().collect();
+            ids.sort_unstable();
+            ids
+        };
+
+        engine.handle(&record(TransactionType::Deposit, 1, Some(dec!(10))))?;
+        engine.handle(&record(TransactionType::Deposit, 2, Some(dec!(20))))?;
+        engine.handle(&record(TransactionType::Dispute, 1, None))?;
+        engine.handle(&record(TransactionType::Deposit, 3, Some(dec!(30))))?;
+
+        // Transaction 2 left the window with the fourth record, transaction 1 is kept by its dispute
+        assert_eq!(stored(&engine), vec![1, 3]);
+        assert_eq!(
+            engine.handle(&record(TransactionType::Dispute, 2, None)),
+            Err(Error::DisputeWindowExpired(2))
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Deposit, 2, Some(dec!(20)))),
+            Err(Error::TransactionIdAlreadyExists(2)),
+            "evicted ids should not be reused"
+        );
+
+        // Open disputes can still be settled, which lets the transaction go
+        engine.handle(&record(TransactionType::Resolve, 1, None))?;
+        assert_eq!(stored(&engine), vec![3]);
+        assert_eq!(
+            engine.handle(&record(TransactionType::Dispute, 1, None)),
+            Err(Error::DisputeWindowExpired(1))
+        );
+
+        // Transactions kept past their window cannot be disputed any further
+        engine.handle(&record(TransactionType::Dispute, 3, Some(dec!(5))))?;
+        engine.handle(&record(TransactionType::Deposit, 4, Some(dec!(1))))?;
+        assert_eq!(
+            engine.handle(&record(TransactionType::Dispute, 3, Some(dec!(5)))),
+            Err(Error::DisputeWindowExpired(3))
+        );
+        engine.handle(&record(TransactionType::Resolve, 3, None))?;
+        assert_eq!(stored(&engine), vec![4]);
+        assert_eq!(engine.transactions_store.get_evicted_ids()?, vec![1, 2, 3]);
+
+        // Evicted ids are part of snapshots
+        let mut restored = Engine::default().with_config(engine.config.clone())?;
+        restored.restore(engine.snapshot()?)?;
+        assert_eq!(
+            restored.handle(&record(TransactionType::Dispute, 2, None)),
+            Err(Error::DisputeWindowExpired(2))
+        );
+        restored.handle(&record(TransactionType::Deposit, 5, Some(dec!(1))))?;
+        assert_eq!(
+            stored(&restored),
+            vec![5],
+            "restored transactions should leave the window too"
+        );
+
+        let trial_balance = engine.trial_balance()?;
+        assert_eq!(trial_balance.debits, trial_balance.credits);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispute_window_shorter_than_rules() -> Result<(), Error> {
+        let config = |dispute_window| Config {
+            rules: Rules {
+                rules: vec![Rule {
+                    name: "velocity".to_string(),
+                    check: Check::MaxWithdrawals {
+                        count: 1,
+                        records: 3,
+                    },
+                }],
+            },
+            dispute_window,
+            ..Config::default()
+        };
+        assert!(matches!(
+            Engine::default().with_config(config(Some(2))),
+            Err(Error::InvalidConfig(_))
+        ));
+
+        // With a window as long as the rule's, withdrawals are only evicted once the rule stops counting them
+        let mut engine = Engine::default().with_config(config(Some(3)))?;
+        let record = |transaction_type, transaction_id| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount: Some(dec!(1)),
+            ..TransactionRecord::default()
+        };
+        engine.handle(&record(TransactionType::Deposit, 1))?;
+        engine.handle(&record(TransactionType::Withdrawal, 2))?;
+        engine.handle(&record(TransactionType::Deposit, 3))?;
+        assert_eq!(
+            engine.handle(&record(TransactionType::Withdrawal, 4)),
+            Err(Error::RuleViolation {
+                rule: "velocity".to_string(),
+                id: 4
+            })
+        );
+        engine.handle(&record(TransactionType::Deposit, 5))?;
+        engine.handle(&record(TransactionType::Withdrawal, 4))?;
+        assert_eq!(engine.transactions_store.get_evicted_ids()?, vec![1, 2]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> Result<(), Error> {
+        let config = Config {
+            event_log: true,
+            journal: JournalMode::EveryRecord,
+            hold_expiry: Some(2),
+            ..Config::default()
+        };
+        let mut engine = Engine::default().with_config(config.clone())?;
+        let record = |transaction_type, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount,
+            ..TransactionRecord::default()
+        };
+
+        engine.handle(&record(TransactionType::Deposit, 1, Some(dec!(100))))?;
+        engine.handle(&record(TransactionType::Authorize, 2, Some(dec!(30))))?;
+        engine.handle(&record(TransactionType::Dispute, 1, Some(dec!(20))))?;
+
+        let mut restored = Engine::default().with_config(config)?;
+        restored.restore(engine.snapshot()?)?;
+        assert_eq!(restored.get_clients()?, engine.get_clients()?);
+        assert_eq!(
+            restored.transactions_store.get_all_transactions()?,
+            engine.transactions_store.get_all_transactions()?
+        );
+        let trial_balance = restored.trial_balance()?;
+        assert_eq!(trial_balance.debits, trial_balance.credits);
+
+        // The hold expires with the next record in both engines
+        for engine in [&mut engine, &mut restored] {
+            engine.handle(&record(TransactionType::Deposit, 3, Some(dec!(1))))?;
+            assert_eq!(
+                engine.clients_store.database[&1].balance(""),
+                Balance {
+                    available_amount: dec!(81),
+                    held_amount: dec!(20),
+                }
+            );
+        }
+        assert_eq!(
+            restored
+                .ledger()
+                .and_then(|ledger| ledger.events().last())
+                .map(|event| event.sequence),
+            Some(4),
+            "record numbers should continue from the snapshot"
+        );
+        assert_eq!(
+            restored
+                .ledger()
+                .map(|ledger| ledger.clients_at(4).get_all())
+                .transpose()?,
+            Some(engine.get_clients()?),
+            "the event log should start from the restored balances"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_idempotent() -> Result<(), Error> {
+        let mut engine = Engine::default().with_config(Config {
+            event_log: true,
+            idempotent: true,
+            ..Config::default()
+        })?;
+        let record = |transaction_type, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount: Some(amount),
+            ..TransactionRecord::default()
+        };
+
+        engine.handle(&record(TransactionType::Deposit, 1, dec!(10)))?;
+        engine.handle(&record(TransactionType::Withdrawal, 2, dec!(4)))?;
+
+        // Replaying the same rows is a no-op
+        engine.handle(&record(TransactionType::Deposit, 1, dec!(10.0)))?;
+        engine.handle(&record(TransactionType::Withdrawal, 2, dec!(4)))?;
+        assert_eq!(
+            engine.clients_store.database[&1]
+                .balance("")
+                .available_amount,
+            dec!(6)
+        );
+        assert_eq!(
+            engine.ledger().map(|ledger| ledger.events().len()),
+            Some(2),
+            "replays should not be logged"
+        );
+
+        // Reusing an id with other data is still an error
+        assert_eq!(
+            engine.handle(&record(TransactionType::Deposit, 1, dec!(11))),
+            Err(Error::TransactionIdAlreadyExists(1))
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Withdrawal, 1, dec!(10))),
+            Err(Error::TransactionIdAlreadyExists(1))
+        );
+        assert_eq!(
+            engine.handle(&TransactionRecord {
+                client_id: 2,
+                ..record(TransactionType::Deposit, 1, dec!(10))
+            }),
+            Err(Error::TransactionIdAlreadyExists(1))
+        );
+        assert_eq!(
+            engine.handle(&TransactionRecord {
+                currency: Some("EUR".to_string()),
+                ..record(TransactionType::Deposit, 1, dec!(10))
+            }),
+            Err(Error::TransactionIdAlreadyExists(1))
+        );
+
+        // Without the idempotent mode, replays are rejected like any other reused id
+        let mut engine = engine.with_config(Config::default())?;
+        assert_eq!(
+            engine.handle(&record(TransactionType::Deposit, 1, dec!(10))),
+            Err(Error::TransactionIdAlreadyExists(1))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_amount_validation() -> Result<(), Error> {
+        let mut engine = Engine::default().with_config(Config {
+            amount_limits: AmountLimits {
+                max_amount: Some(dec!(1000)),
+                ..AmountLimits::default()
+            },
+            ..Config::default()
+        })?;
+        let record = |transaction_type, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount: Some(amount),
+            ..TransactionRecord::default()
+        };
+
+        assert_eq!(
+            engine.handle(&record(TransactionType::Deposit, 1, dec!(-5))),
+            Err(Error::NegativeAmount(1)),
+            "a negative deposit should not act as a withdrawal"
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Withdrawal, 1, dec!(0))),
+            Err(Error::ZeroAmount(1))
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Deposit, 1, dec!(-0))),
+            Err(Error::ZeroAmount(1))
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Deposit, 1, dec!(1.00001))),
+            Err(Error::AmountTooPrecise {
+                id: 1,
+                amount: dec!(1.00001),
+                max_scale: 4
+            })
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Transfer, 1, dec!(1000.0001))),
+            Err(Error::AmountTooLarge {
+                id: 1,
+                amount: dec!(1000.0001),
+                max: dec!(1000)
+            })
+        );
+        assert!(
+            engine.clients_store.database.is_empty(),
+            "invalid amounts should not change anything"
+        );
+
+        // Trailing zeros do not count towards the scale
+        engine.handle(&record(TransactionType::Deposit, 1, dec!(1000.000000)))?;
+        engine.handle(&record(TransactionType::Deposit, 2, dec!(0.0001)))?;
+
+        // Partial dispute amounts are validated the same way
+        assert_eq!(
+            engine.handle(&record(TransactionType::Dispute, 1, dec!(-1))),
+            Err(Error::NegativeAmount(1))
+        );
+
+        // Credit limits can be zero but not negative
+        engine.handle(&record(TransactionType::Limit, 3, dec!(0)))?;
+        assert_eq!(
+            engine.handle(&record(TransactionType::Limit, 3, dec!(-1))),
+            Err(Error::NegativeCreditLimit(3))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rules() -> Result<(), Error> {
+        let mut engine = Engine::default().with_config(Config {
+            rules: Rules {
+                rules: vec![
+                    Rule {
+                        name: "big".to_string(),
+                        check: Check::MaxAmount { amount: dec!(1000) },
+                    },
+                    Rule {
+                        name: "velocity".to_string(),
+                        check: Check::MaxWithdrawals {
+                            count: 2,
+                            records: 4,
+                        },
+                    },
+                    Rule {
+                        name: "disputes".to_string(),
+                        check: Check::FreezeAfterDisputes { disputes: 2 },
+                    },
+                ],
+            },
+            ..Config::default()
+        })?;
+        let record = |transaction_type, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount,
+            ..TransactionRecord::default()
+        };
+
+        assert_eq!(
+            engine.handle(&record(TransactionType::Deposit, 1, Some(dec!(1000.5)))),
+            Err(Error::RuleViolation {
+                rule: "big".to_string(),
+                id: 1
+            })
+        );
+        assert!(
+            engine.clients_store.database.is_empty(),
+            "a rule hit should not change anything"
+        );
+        engine.handle(&record(TransactionType::Deposit, 1, Some(dec!(1000))))?;
+
+        // The third withdrawal within 4 records is rejected, until the first one falls out of the window
+        engine.handle(&record(TransactionType::Withdrawal, 2, Some(dec!(1))))?;
+        engine.handle(&record(TransactionType::Withdrawal, 3, Some(dec!(1))))?;
+        assert_eq!(
+            engine.handle(&record(TransactionType::Withdrawal, 4, Some(dec!(1)))),
+            Err(Error::RuleViolation {
+                rule: "velocity".to_string(),
+                id: 4
+            })
+        );
+        engine.handle(&record(TransactionType::Deposit, 5, Some(dec!(1))))?;
+        engine.handle(&record(TransactionType::Deposit, 6, Some(dec!(1))))?;
+        engine.handle(&record(TransactionType::Withdrawal, 4, Some(dec!(1))))?;
+
+        // The second dispute freezes the client, even after the first one was resolved
+        engine.handle(&record(TransactionType::Dispute, 1, Some(dec!(10))))?;
+        engine.handle(&record(TransactionType::Resolve, 1, None))?;
+        assert_eq!(engine.clients_store.database[&1].status, Status::Active);
+        engine.handle(&record(TransactionType::Dispute, 5, None))?;
+        assert_eq!(engine.clients_store.database[&1].status, Status::Frozen);
+        assert_eq!(
+            engine
+                .clients_store
+                .get_status_changes(1)?
+                .last()
+                .map(|change| change.reason.as_str()),
+            Some("rule disputes"),
+            "the freeze should name the rule that fired"
+        );
+        assert_eq!(
+            engine.handle(&record(TransactionType::Withdrawal, 7, Some(dec!(1)))),
+            Err(Error::ClientFrozen(1))
+        );
+
+        Ok(())
+    }
+
+    // Store implementing nothing but the required primitives, with hooks that do not support rollback
+    #[derive(Default)]
+    struct MinimalClients {
+        clients: BTreeMap<ClientID, Client>,
+        status_changes: BTreeMap<ClientID, Vec<StatusChange>>,
+    }
+
+    impl ClientStore for MinimalClients {
+        fn find_client(&self, id: ClientID) -> Result<Option<Client>, Error> {
+            Ok(self.clients.get(&id).cloned())
+        }
+
+        fn upsert_client(&mut self, id: ClientID, client: Client) -> Result<(), Error> {
+            self.clients.insert(id, client);
+            Ok(())
+        }
+
+        fn get_all(&self) -> Result<Vec<(ClientID, Client)>, Error> {
+            Ok(self.clients.clone().into_iter().collect())
+        }
+
+        fn save_status_change(&mut self, id: ClientID, change: StatusChange) -> Result<(), Error> {
+            self.status_changes.entry(id).or_default().push(change);
+            Ok(())
+        }
+
+        fn get_status_changes(&self, id: ClientID) -> Result<Vec<StatusChange>, Error> {
+            Ok(self.status_changes.get(&id).cloned().unwrap_or_default())
+        }
+
+        fn begin(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn rollback(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct MinimalTransactions {
+        transactions: BTreeMap<TransactionID, transactions::Transaction>,
+        evicted: BTreeSet<TransactionID>,
+    }
+
+    impl TransactionStore for MinimalTransactions {
+        fn find_transaction(
+            &self,
+            transaction_id: TransactionID,
+        ) -> Result<Option<transactions::Transaction>, Error> {
+            Ok(self.transactions.get(&transaction_id).cloned())
+        }
+
+        fn upsert_transaction(
+            &mut self,
+            transaction_id: TransactionID,
+            transaction: transactions::Transaction,
+        ) -> Result<(), Error> {
+            self.transactions.insert(transaction_id, transaction);
+            Ok(())
+        }
+
+        fn get_client_transactions(
+            &self,
+            client_id: ClientID,
+        ) -> Result<Vec<transactions::Transaction>, Error> {
+            Ok(self
+                .transactions
+                .values()
+                .filter(|transaction| transaction.client_id == client_id)
+                .cloned()
+                .collect())
+        }
+
+        fn get_all_transactions(
+            &self,
+        ) -> Result<Vec<(TransactionID, transactions::Transaction)>, Error> {
+            Ok(self.transactions.clone().into_iter().collect())
+        }
+
+        fn evict_transaction(&mut self, transaction_id: TransactionID) -> Result<(), Error> {
+            self.transactions.remove(&transaction_id);
+            self.evicted.insert(transaction_id);
+            Ok(())
+        }
+
+        fn get_evicted_ids(&self) -> Result<Vec<TransactionID>, Error> {
+            Ok(self.evicted.iter().copied().collect())
+        }
+
+        fn is_evicted(&self, transaction_id: TransactionID) -> Result<bool, Error> {
+            Ok(self.evicted.contains(&transaction_id))
+        }
+
+        fn begin(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn rollback(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_custom_store() -> Result<(), Error> {
+        let record = |transaction_type, client_id, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            ..TransactionRecord::default()
+        };
+        let records = [
+            record(TransactionType::Deposit, 1, 1, Some(dec!(10))),
+            record(TransactionType::Deposit, 2, 2, Some(dec!(5.5))),
+            record(TransactionType::Withdrawal, 1, 3, Some(dec!(4))),
+            record(TransactionType::Withdrawal, 2, 4, Some(dec!(6))),
+            record(TransactionType::Dispute, 1, 1, Some(dec!(6))),
+            record(TransactionType::Resolve, 1, 1, None),
+            record(TransactionType::Dispute, 2, 2, None),
+            record(TransactionType::Chargeback, 2, 2, None),
+            record(TransactionType::Deposit, 2, 5, Some(dec!(1))),
+        ];
+
+        // Should behave exactly like the default stores, rejections included
+        let mut engine = Engine::default();
+        let mut custom = Engine::new(MinimalClients::default(), MinimalTransactions::default())?;
+        for record in &records {
+            assert_eq!(custom.handle(record), engine.handle(record), "{record:?}");
+        }
+        assert_eq!(custom.get_clients()?, engine.get_clients()?);
+        assert_eq!(
+            custom.clients_store.get_status_changes(2)?,
+            engine.clients_store.get_status_changes(2)?
+        );
+        assert_eq!(
+            custom.transactions_store.get_all_transactions()?,
+            engine.transactions_store.get_all_transactions()?
+        );
+
+        Ok(())
+    }
+
+    // Default transactions store whose commits fail while `fail_commit` is set
+    #[derive(Default)]
+    struct FailingTransactions {
+        store: Transactions,
+        fail_commit: bool,
+    }
+
+    impl TransactionStore for FailingTransactions {
+        fn find_transaction(
+            &self,
+            transaction_id: TransactionID,
+        ) -> Result<Option<transactions::Transaction>, Error> {
+            self.store.find_transaction(transaction_id)
+        }
+
+        fn upsert_transaction(
+            &mut self,
+            transaction_id: TransactionID,
+            transaction: transactions::Transaction,
+        ) -> Result<(), Error> {
+            self.store.upsert_transaction(transaction_id, transaction)
+        }
+
+        fn get_client_transactions(
+            &self,
+            client_id: ClientID,
+        ) -> Result<Vec<transactions::Transaction>, Error> {
+            self.store.get_client_transactions(client_id)
+        }
+
+        fn get_all_transactions(
+            &self,
+        ) -> Result<Vec<(TransactionID, transactions::Transaction)>, Error> {
+            self.store.get_all_transactions()
+        }
+
+        fn evict_transaction(&mut self, transaction_id: TransactionID) -> Result<(), Error> {
+            self.store.evict_transaction(transaction_id)
+        }
+
+        fn get_evicted_ids(&self) -> Result<Vec<TransactionID>, Error> {
+            self.store.get_evicted_ids()
+        }
+
+        fn is_evicted(&self, transaction_id: TransactionID) -> Result<bool, Error> {
+            self.store.is_evicted(transaction_id)
+        }
+
+        fn begin(&mut self) -> Result<(), Error> {
+            self.store.begin()
+        }
+
+        fn commit(&mut self) -> Result<(), Error> {
+            if self.fail_commit {
+                return Err(Error::StoreFailure("commit failed".to_string()));
+            }
+            self.store.commit()
+        }
+
+        fn rollback(&mut self) -> Result<(), Error> {
+            self.store.rollback()
+        }
+    }
+
+    #[test]
+    fn test_failed_commit() -> Result<(), Error> {
+        let mut engine = Engine::new(Clients::default(), FailingTransactions::default())?
+            .with_config(Config {
+                journal: JournalMode::OnDemand,
+                ..Config::default()
+            })?;
+        let deposit = |transaction_id, amount| TransactionRecord {
+            transaction_type: TransactionType::Deposit,
+            client_id: 1,
+            transaction_id,
+            amount: Some(amount),
+            ..TransactionRecord::default()
+        };
+        engine.handle(&deposit(1, dec!(10)))?;
+
+        // Should roll back both stores when the commit fails
+        engine.transactions_store.fail_commit = true;
+        assert_eq!(
+            engine.handle(&deposit(2, dec!(5))),
+            Err(Error::StoreFailure("commit failed".to_string()))
+        );
+        assert_eq!(
+            engine.clients_store.database[&1]
+                .balance("")
+                .available_amount,
+            dec!(10)
+        );
+        assert_eq!(engine.transactions_store.find_transaction(2)?, None);
+        assert_eq!(
+            engine.journal().map(|journal| journal.entries().len()),
+            Some(1),
+            "the journal entry of the record should be dropped with the store changes"
+        );
+        engine.trial_balance()?;
+
+        // Should leave no changes pending for the next record
+        engine.transactions_store.fail_commit = false;
+        engine.handle(&deposit(3, dec!(1)))?;
+        assert_eq!(
+            engine.clients_store.database[&1]
+                .balance("")
+                .available_amount,
+            dec!(11)
+        );
+        assert_eq!(engine.transactions_store.find_transaction(2)?, None);
+        assert_eq!(engine.record_counts(), (3, 2));
+        engine.trial_balance()?;
+
+        Ok(())
+    }
+
+    // Renders both stores in a stable order through the store traits, so any store can be compared. Decimals keep
+    // their scale when formatted, so two fingerprints are only equal if the stores are byte-for-byte identical.
+    fn fingerprint<C: ClientStore, T: TransactionStore>(engine: &Engine<C, T>) -> String {
+        let clients = engine.clients_store.get_all();
+        let status_changes: Vec<_> = clients
+            .iter()
+            .flatten()
+            .map(|(id, _)| engine.clients_store.get_status_changes(*id))
+            .collect();
+        let transactions = engine.transactions_store.get_all_transactions();
+        let evicted = engine.transactions_store.get_evicted_ids();
+        format!("{clients:?}\n{status_changes:?}\n{transactions:?}\n{evicted:?}")
+    }
+
+    fn assert_rejections_leave_stores_unchanged<C: ClientStore, T: TransactionStore>(
+        mut engine: Engine<C, T>,
+        records: &[TransactionRecord],
+    ) -> Result<(), TestCaseError> {
+        for record in records {
+            let before = fingerprint(&engine);
+            if let Err(error) = engine.handle(record) {
+                prop_assert!(
+                    !matches!(
+                        error,
+                        Error::UnbalancedJournalEntry { .. } | Error::JournalMismatch { .. }
+                    ),
+                    "{:?} left the journal out of balance: {}",
+                    record,
+                    error
+                );
+                prop_assert_eq!(
+                    &before,
+                    &fingerprint(&engine),
+                    "{:?} was rejected with {} but changed the stores",
+                    record,
+                    error
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn record_strategy() -> impl Strategy<Value = TransactionRecord> {
+        let transaction_type = prop_oneof![
+            4 => Just(TransactionType::Deposit),
+            3 => Just(TransactionType::Withdrawal),
+            2 => Just(TransactionType::Transfer),
+            3 => Just(TransactionType::Dispute),
+            2 => Just(TransactionType::Resolve),
+            2 => Just(TransactionType::Chargeback),
+            2 => Just(TransactionType::Authorize),
+            2 => Just(TransactionType::Capture),
+            1 => Just(TransactionType::Void),
+            1 => Just(TransactionType::Limit),
+            1 => Just(TransactionType::Unlock),
+            1 => Just(TransactionType::Freeze),
+            1 => Just(TransactionType::Close),
+        ];
+        let amount = proptest::option::weighted(
+            0.8,
+            (-100i64..5_000, 0u32..6).prop_map(|(mantissa, scale)| Decimal::new(mantissa, scale)),
+        );
+        let currency = proptest::option::weighted(0.3, Just("EUR".to_string()));
+        let admin_field = proptest::option::weighted(0.8, Just("ops".to_string()));
+        let to_client_id = proptest::option::weighted(0.9, 1..4u16);
+
+        (
+            transaction_type,
+            1..4u16,
+            1..16u32,
+            amount,
+            currency,
+            to_client_id,
+            (admin_field.clone(), admin_field),
+        )
+            .prop_map(
+                |(
+                    transaction_type,
+                    client_id,
+                    transaction_id,
+                    amount,
+                    currency,
+                    to_client_id,
+                    (reason, operator),
+                )| {
+                    TransactionRecord {
+                        transaction_type,
+                        client_id,
+                        transaction_id,
+                        amount,
+                        currency,
+                        to_client_id,
+                        reason,
+                        operator,
+                    }
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn prop_rejected_records_leave_stores_unchanged(
+            withdrawal_disputes in any::<bool>(),
+            charge_fees in any::<bool>(),
+            hold_expiry in proptest::option::of(0..5u64),
+            apply_rules in any::<bool>(),
+            idempotent in any::<bool>(),
+            dispute_window in proptest::option::of(0..8u64),
+            records in proptest::collection::vec(record_strategy(), 1..200),
+        ) {
+            let config = Config {
+                dispute_policy: if withdrawal_disputes {
+                    DisputePolicy::DepositsAndWithdrawals
+                } else {
+                    DisputePolicy::DepositsOnly
+                },
+                journal: JournalMode::EveryRecord,
+                // The house is one of the generated clients, so its own records and locks are covered too
+                fees: charge_fees.then(|| FeeSchedule {
+                    default: Fees {
+                        deposit: Some(Fee::Percentage(dec!(1.5))),
+                        withdrawal: Some(Fee::Flat(dec!(0.5))),
+                    },
+                    refund_policy: FeeRefundPolicy::Refund,
+                    ..FeeSchedule::new(3)
+                }),
+                hold_expiry,
+                rules: Rules {
+                    rules: if apply_rules {
+                        vec![
+                            Rule {
+                                name: "big".to_string(),
+                                check: Check::MaxAmount { amount: dec!(40) },
+                            },
+                            Rule {
+                                name: "velocity".to_string(),
+                                // Within the dispute window, which cannot be shorter
+                                check: Check::MaxWithdrawals {
+                                    count: 2,
+                                    records: dispute_window.map_or(10, |window| window.min(10)),
+                                },
+                            },
+                            Rule {
+                                name: "disputes".to_string(),
+                                check: Check::FreezeAfterDisputes { disputes: 3 },
+                            },
+                        ]
+                    } else {
+                        Vec::new()
+                    },
+                },
+                idempotent,
+                dispute_window,
+                ..Config::default()
+            };
+
+            assert_rejections_leave_stores_unchanged(
+                Engine::default().with_config(config.clone())?,
+                &records,
+            )?;
+            // Stores that cannot roll back rely on every rejection happening before the first write
+            assert_rejections_leave_stores_unchanged(
+                Engine::new(MinimalClients::default(), MinimalTransactions::default())?.with_config(config)?,
+                &records,
+            )?;
+        }
+    }
 }
@@ -1,15 +1,95 @@
-use crate::{ClientID, TransactionID};
+use crate::{stores::clients::Status, ClientID, TransactionID};
 use rust_decimal::Decimal;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use std::fmt;
 use thiserror::Error;
 
+/// Broad kind of an error, for callers that only need to know what went wrong rather than the exact error
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Category {
+    /// The input could not be parsed
+    Parse,
+    /// The record is invalid on its own or refers to something it cannot
+    Validation,
+    /// The client does not have the funds for the record
+    Balance,
+    /// The record does not apply to the current state of the transaction or client
+    State,
+    /// The client is frozen, locked or closed
+    Lock,
+    /// Output, storage or engine configuration failure, or an engine integrity check failing (the journal), not
+    /// caused by the record
+    System,
+}
+
 #[derive(Error, Debug, PartialEq)]
 pub enum Error {
     #[error("csv row parsing failure: {0}")]
     CSVRowReadFailure(String),
     #[error("csv row writing failure: {0}")]
     CSVRowWriteFailure(String),
+    #[error("json row parsing failure: {0}")]
+    JSONRowReadFailure(String),
+    #[error("json row writing failure: {0}")]
+    JSONRowWriteFailure(String),
+    #[error("event log is not enabled")]
+    EventLogDisabled,
+    #[error("journal is not enabled")]
+    JournalDisabled,
+    #[error(
+        "journal does not balance: debits {debits} and credits {credits} in currency '{currency}'"
+    )]
+    UnbalancedJournalEntry {
+        currency: String,
+        debits: Decimal,
+        credits: Decimal,
+    },
+    #[error("journal balance of {account} is {journal} but the store has {store}")]
+    JournalMismatch {
+        account: String,
+        journal: Decimal,
+        store: Decimal,
+    },
+    #[error("store failure: {0}")]
+    StoreFailure(String),
+    #[error("rules config parsing failure: {0}")]
+    RulesConfigFailure(String),
+    #[error("invalid fee: {0}")]
+    InvalidFee(String),
+    #[error("invalid engine config: {0}")]
+    InvalidConfig(String),
+    #[error("snapshot reading failure: {0}")]
+    SnapshotReadFailure(String),
+    #[error("snapshot writing failure: {0}")]
+    SnapshotWriteFailure(String),
+    #[error("snapshot version {version} is not supported, expected version {supported}")]
+    UnsupportedSnapshotVersion { version: u32, supported: u32 },
+    #[error("snapshot checksum {checksum} does not match its content checksum {computed}")]
+    SnapshotChecksumMismatch { checksum: String, computed: String },
+    #[error("snapshots can only be restored into an engine with empty stores")]
+    EngineNotEmpty,
+    #[error("parallel processing does not support {0}")]
+    ParallelUnsupported(String),
+    #[error("the processor stopped before the record was processed")]
+    ProcessorStopped,
+    #[error("http request parsing failure: {0}")]
+    HTTPRequestFailure(String),
+    #[error("server failure: {0}")]
+    ServerFailure(String),
     #[error("client {0} is locked")]
     ClientLocked(ClientID),
+    #[error("client {0} is frozen")]
+    ClientFrozen(ClientID),
+    #[error("client {0} is closed")]
+    ClientClosed(ClientID),
+    #[error("client {id} cannot go from {from} to {to}")]
+    ClientStatusChangeNotAllowed {
+        id: ClientID,
+        from: Status,
+        to: Status,
+    },
+    #[error("client {id} cannot be closed as held amount is {held}")]
+    ClientCannotClose { id: ClientID, held: Decimal },
     #[error("client {0} not exist")]
     ClientNotExist(ClientID),
     #[error("client {id} cannot withdrawl {amount} as available amount is {available}")]
@@ -24,6 +104,18 @@ pub enum Error {
         amount: Decimal,
         available: Decimal,
     },
+    #[error("client {id} cannot authorize {amount} as available amount is {available}")]
+    ClientCannotAuthorize {
+        id: ClientID,
+        amount: Decimal,
+        available: Decimal,
+    },
+    #[error("client {id} cannot capture {amount} as held amount is {held}")]
+    ClientCannotCapture {
+        id: ClientID,
+        amount: Decimal,
+        held: Decimal,
+    },
     #[error("client {id} cannot resolve {amount} as held amount is {held}")]
     ClientCannotResolve {
         id: ClientID,
@@ -36,22 +128,483 @@ pub enum Error {
         amount: Decimal,
         held: Decimal,
     },
+    #[error(
+        "house client {id} cannot refund a fee of {amount} as available amount is {available}"
+    )]
+    HouseCannotRefund {
+        id: ClientID,
+        amount: Decimal,
+        available: Decimal,
+    },
     #[error("transaction {0} already exist")]
     TransactionIdAlreadyExists(TransactionID),
     #[error("transaction {0} not exist")]
     TransactionNotExists(TransactionID),
+    #[error("dispute window expired for transaction {0}")]
+    DisputeWindowExpired(TransactionID),
     #[error("transaction {0} is not for client {1}")]
     TransactionWithWrongClientId(TransactionID, ClientID),
+    #[error("transaction {0} is not in currency {1}")]
+    TransactionWithWrongCurrency(TransactionID, String),
+    #[error("transaction {0} has a negative amount")]
+    NegativeAmount(TransactionID),
+    #[error("transaction {0} has a zero amount")]
+    ZeroAmount(TransactionID),
+    #[error("transaction {id} amount {amount} has more than {max_scale} decimal places")]
+    AmountTooPrecise {
+        id: TransactionID,
+        amount: Decimal,
+        max_scale: u32,
+    },
+    #[error("transaction {id} amount {amount} is more than the maximum of {max}")]
+    AmountTooLarge {
+        id: TransactionID,
+        amount: Decimal,
+        max: Decimal,
+    },
     #[error("deposit transaction {0} missing amount field")]
     DepositTransactionMissingAmount(TransactionID),
     #[error("withdrawal transaction {0} missing amount field")]
     WithdrawalTransactionMissingAmount(TransactionID),
+    #[error("transfer transaction {0} missing amount field")]
+    TransferTransactionMissingAmount(TransactionID),
+    #[error("transfer transaction {0} missing to field")]
+    TransferTransactionMissingDestination(TransactionID),
+    #[error("transfer transaction {0} cannot transfer to its own client")]
+    TransferToSameClient(TransactionID),
+    #[error("authorize transaction {0} missing amount field")]
+    AuthorizeTransactionMissingAmount(TransactionID),
+    #[error("transaction {0} is not an authorization hold")]
+    NotAHold(TransactionID),
+    #[error("hold {0} is already captured, voided or expired")]
+    HoldClosed(TransactionID),
+    #[error("hold {id} cannot capture {amount} as only {held} is held")]
+    CaptureAmountExceedsHold {
+        id: TransactionID,
+        amount: Decimal,
+        held: Decimal,
+    },
+    #[error("limit transaction {0} missing amount field")]
+    LimitTransactionMissingAmount(TransactionID),
+    #[error("limit transaction {0} cannot set a negative credit limit")]
+    NegativeCreditLimit(TransactionID),
+    #[error("transaction {id} rejected by rule {rule}")]
+    RuleViolation { rule: String, id: TransactionID },
+    #[error("admin transaction {0} missing reason field")]
+    AdminTransactionMissingReason(TransactionID),
+    #[error("admin transaction {0} missing operator field")]
+    AdminTransactionMissingOperator(TransactionID),
     #[error("transaction {0} is already in dispute")]
     DisputeAlreadyDisputedTransaction(TransactionID),
     #[error("transaction {0} cannot be disputed as it is not a deposit")]
     DisputeNonDepositTransaction(TransactionID),
+    #[error("transaction {id} cannot dispute {amount} as only {disputable} is disputable")]
+    DisputeAmountExceedsTransaction {
+        id: TransactionID,
+        amount: Decimal,
+        disputable: Decimal,
+    },
     #[error("transaction {0} cannot be resolved as it is not in dispute")]
     ResolveNonDisputedTransaction(TransactionID),
+    #[error("transaction {id} cannot resolve {amount} as only {disputed} is in dispute")]
+    ResolveAmountExceedsDisputed {
+        id: TransactionID,
+        amount: Decimal,
+        disputed: Decimal,
+    },
     #[error("transaction {0} cannot be chargebacked as it is not in dispute")]
     ChargeBackNonDisputedTransaction(TransactionID),
+    #[error("transaction {id} cannot chargeback {amount} as only {disputed} is in dispute")]
+    ChargeBackAmountExceedsDisputed {
+        id: TransactionID,
+        amount: Decimal,
+        disputed: Decimal,
+    },
+}
+
+impl Category {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Parse => "parse",
+            Category::Validation => "validation",
+            Category::Balance => "balance",
+            Category::State => "state",
+            Category::Lock => "lock",
+            Category::System => "system",
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Serialized as a map of the `code`, `category` and `message` of the error, followed by its structured fields (like
+/// `id`, `amount`, `available` or `held`). Amounts are serialized as strings so they stay exact.
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("code", self.code())?;
+        map.serialize_entry("category", self.category().as_str())?;
+        map.serialize_entry("message", &self.to_string())?;
+        self.serialize_fields(&mut map)?;
+        map.end()
+    }
+}
+
+impl Error {
+    // Structured fields of the error, as entries of the serialized map. One arm per variant, so it grows with the enum.
+    #[allow(clippy::too_many_lines)]
+    fn serialize_fields<M: SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+        match self {
+            Error::CSVRowReadFailure(detail)
+            | Error::CSVRowWriteFailure(detail)
+            | Error::JSONRowReadFailure(detail)
+            | Error::JSONRowWriteFailure(detail)
+            | Error::StoreFailure(detail)
+            | Error::RulesConfigFailure(detail)
+            | Error::InvalidFee(detail)
+            | Error::InvalidConfig(detail)
+            | Error::SnapshotReadFailure(detail)
+            | Error::SnapshotWriteFailure(detail)
+            | Error::ParallelUnsupported(detail)
+            | Error::HTTPRequestFailure(detail)
+            | Error::ServerFailure(detail) => map.serialize_entry("detail", detail)?,
+            Error::EventLogDisabled
+            | Error::JournalDisabled
+            | Error::EngineNotEmpty
+            | Error::ProcessorStopped => {}
+            Error::UnsupportedSnapshotVersion { version, supported } => {
+                map.serialize_entry("version", version)?;
+                map.serialize_entry("supported", supported)?;
+            }
+            Error::SnapshotChecksumMismatch { checksum, computed } => {
+                map.serialize_entry("checksum", checksum)?;
+                map.serialize_entry("computed", computed)?;
+            }
+            Error::UnbalancedJournalEntry {
+                currency,
+                debits,
+                credits,
+            } => {
+                map.serialize_entry("currency", currency)?;
+                map.serialize_entry("debits", debits)?;
+                map.serialize_entry("credits", credits)?;
+            }
+            Error::JournalMismatch {
+                account,
+                journal,
+                store,
+            } => {
+                map.serialize_entry("account", account)?;
+                map.serialize_entry("journal", journal)?;
+                map.serialize_entry("store", store)?;
+            }
+            Error::ClientLocked(id)
+            | Error::ClientFrozen(id)
+            | Error::ClientClosed(id)
+            | Error::ClientNotExist(id) => map.serialize_entry("id", id)?,
+            Error::ClientStatusChangeNotAllowed { id, from, to } => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("from", from.as_str())?;
+                map.serialize_entry("to", to.as_str())?;
+            }
+            Error::ClientCannotClose { id, held } => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("held", held)?;
+            }
+            Error::ClientCannotWithdrawl {
+                id,
+                amount,
+                available,
+            }
+            | Error::ClientCannotDispute {
+                id,
+                amount,
+                available,
+            }
+            | Error::ClientCannotAuthorize {
+                id,
+                amount,
+                available,
+            }
+            | Error::HouseCannotRefund {
+                id,
+                amount,
+                available,
+            } => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("amount", amount)?;
+                map.serialize_entry("available", available)?;
+            }
+            Error::ClientCannotResolve { id, amount, held }
+            | Error::ClientCannotChargeBack { id, amount, held }
+            | Error::ClientCannotCapture { id, amount, held } => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("amount", amount)?;
+                map.serialize_entry("held", held)?;
+            }
+            Error::TransactionIdAlreadyExists(id)
+            | Error::TransactionNotExists(id)
+            | Error::DisputeWindowExpired(id)
+            | Error::NegativeAmount(id)
+            | Error::ZeroAmount(id)
+            | Error::DepositTransactionMissingAmount(id)
+            | Error::WithdrawalTransactionMissingAmount(id)
+            | Error::TransferTransactionMissingAmount(id)
+            | Error::TransferTransactionMissingDestination(id)
+            | Error::TransferToSameClient(id)
+            | Error::AuthorizeTransactionMissingAmount(id)
+            | Error::NotAHold(id)
+            | Error::HoldClosed(id)
+            | Error::LimitTransactionMissingAmount(id)
+            | Error::NegativeCreditLimit(id)
+            | Error::AdminTransactionMissingReason(id)
+            | Error::AdminTransactionMissingOperator(id)
+            | Error::DisputeAlreadyDisputedTransaction(id)
+            | Error::DisputeNonDepositTransaction(id)
+            | Error::ResolveNonDisputedTransaction(id)
+            | Error::ChargeBackNonDisputedTransaction(id) => map.serialize_entry("id", id)?,
+            Error::RuleViolation { rule, id } => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("rule", rule)?;
+            }
+            Error::TransactionWithWrongClientId(id, client_id) => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("client_id", client_id)?;
+            }
+            Error::TransactionWithWrongCurrency(id, currency) => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("currency", currency)?;
+            }
+            Error::DisputeAmountExceedsTransaction {
+                id,
+                amount,
+                disputable,
+            } => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("amount", amount)?;
+                map.serialize_entry("disputable", disputable)?;
+            }
+            Error::ResolveAmountExceedsDisputed {
+                id,
+                amount,
+                disputed,
+            }
+            | Error::ChargeBackAmountExceedsDisputed {
+                id,
+                amount,
+                disputed,
+            } => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("amount", amount)?;
+                map.serialize_entry("disputed", disputed)?;
+            }
+            Error::CaptureAmountExceedsHold { id, amount, held } => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("amount", amount)?;
+                map.serialize_entry("held", held)?;
+            }
+            Error::AmountTooPrecise {
+                id,
+                amount,
+                max_scale,
+            } => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("amount", amount)?;
+                map.serialize_entry("max_scale", max_scale)?;
+            }
+            Error::AmountTooLarge { id, amount, max } => {
+                map.serialize_entry("id", id)?;
+                map.serialize_entry("amount", amount)?;
+                map.serialize_entry("max", max)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stable machine readable code of the error. Unlike the message, it never changes once released.
+    #[must_use]
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::CSVRowReadFailure(_) => "csv_row_read_failure",
+            Error::CSVRowWriteFailure(_) => "csv_row_write_failure",
+            Error::JSONRowReadFailure(_) => "json_row_read_failure",
+            Error::JSONRowWriteFailure(_) => "json_row_write_failure",
+            Error::EventLogDisabled => "event_log_disabled",
+            Error::JournalDisabled => "journal_disabled",
+            Error::UnbalancedJournalEntry { .. } => "unbalanced_journal_entry",
+            Error::JournalMismatch { .. } => "journal_mismatch",
+            Error::StoreFailure(_) => "store_failure",
+            Error::RulesConfigFailure(_) => "rules_config_failure",
+            Error::InvalidFee(_) => "invalid_fee",
+            Error::InvalidConfig(_) => "invalid_config",
+            Error::SnapshotReadFailure(_) => "snapshot_read_failure",
+            Error::SnapshotWriteFailure(_) => "snapshot_write_failure",
+            Error::UnsupportedSnapshotVersion { .. } => "unsupported_snapshot_version",
+            Error::SnapshotChecksumMismatch { .. } => "snapshot_checksum_mismatch",
+            Error::EngineNotEmpty => "engine_not_empty",
+            Error::ParallelUnsupported(_) => "parallel_unsupported",
+            Error::ProcessorStopped => "processor_stopped",
+            Error::HTTPRequestFailure(_) => "http_request_failure",
+            Error::ServerFailure(_) => "server_failure",
+            Error::ClientLocked(_) => "client_locked",
+            Error::ClientFrozen(_) => "client_frozen",
+            Error::ClientClosed(_) => "client_closed",
+            Error::ClientStatusChangeNotAllowed { .. } => "client_status_change_not_allowed",
+            Error::ClientCannotClose { .. } => "client_cannot_close",
+            Error::ClientNotExist(_) => "client_not_exist",
+            Error::ClientCannotWithdrawl { .. } => "client_cannot_withdraw",
+            Error::ClientCannotDispute { .. } => "client_cannot_dispute",
+            Error::ClientCannotAuthorize { .. } => "client_cannot_authorize",
+            Error::ClientCannotCapture { .. } => "client_cannot_capture",
+            Error::ClientCannotResolve { .. } => "client_cannot_resolve",
+            Error::ClientCannotChargeBack { .. } => "client_cannot_chargeback",
+            Error::HouseCannotRefund { .. } => "house_cannot_refund",
+            Error::TransactionIdAlreadyExists(_) => "transaction_id_already_exists",
+            Error::TransactionNotExists(_) => "transaction_not_exists",
+            Error::DisputeWindowExpired(_) => "dispute_window_expired",
+            Error::TransactionWithWrongClientId(..) => "transaction_with_wrong_client_id",
+            Error::TransactionWithWrongCurrency(..) => "transaction_with_wrong_currency",
+            Error::NegativeAmount(_) => "negative_amount",
+            Error::ZeroAmount(_) => "zero_amount",
+            Error::AmountTooPrecise { .. } => "amount_too_precise",
+            Error::AmountTooLarge { .. } => "amount_too_large",
+            Error::DepositTransactionMissingAmount(_) => "deposit_missing_amount",
+            Error::WithdrawalTransactionMissingAmount(_) => "withdrawal_missing_amount",
+            Error::TransferTransactionMissingAmount(_) => "transfer_missing_amount",
+            Error::TransferTransactionMissingDestination(_) => "transfer_missing_destination",
+            Error::TransferToSameClient(_) => "transfer_to_same_client",
+            Error::AuthorizeTransactionMissingAmount(_) => "authorize_missing_amount",
+            Error::NotAHold(_) => "not_a_hold",
+            Error::HoldClosed(_) => "hold_closed",
+            Error::CaptureAmountExceedsHold { .. } => "capture_amount_exceeds_hold",
+            Error::LimitTransactionMissingAmount(_) => "limit_missing_amount",
+            Error::NegativeCreditLimit(_) => "negative_credit_limit",
+            Error::RuleViolation { .. } => "rule_violation",
+            Error::AdminTransactionMissingReason(_) => "admin_missing_reason",
+            Error::AdminTransactionMissingOperator(_) => "admin_missing_operator",
+            Error::DisputeAlreadyDisputedTransaction(_) => "dispute_already_disputed",
+            Error::DisputeNonDepositTransaction(_) => "dispute_non_deposit",
+            Error::DisputeAmountExceedsTransaction { .. } => "dispute_amount_exceeds_transaction",
+            Error::ResolveNonDisputedTransaction(_) => "resolve_non_disputed",
+            Error::ResolveAmountExceedsDisputed { .. } => "resolve_amount_exceeds_disputed",
+            Error::ChargeBackNonDisputedTransaction(_) => "chargeback_non_disputed",
+            Error::ChargeBackAmountExceedsDisputed { .. } => "chargeback_amount_exceeds_disputed",
+        }
+    }
+
+    /// Broad kind of the error
+    #[must_use]
+    pub fn category(&self) -> Category {
+        match self {
+            Error::CSVRowReadFailure(_)
+            | Error::JSONRowReadFailure(_)
+            | Error::SnapshotReadFailure(_)
+            | Error::HTTPRequestFailure(_)
+            | Error::UnsupportedSnapshotVersion { .. }
+            | Error::SnapshotChecksumMismatch { .. } => Category::Parse,
+            Error::ClientNotExist(_)
+            | Error::TransactionIdAlreadyExists(_)
+            | Error::TransactionNotExists(_)
+            | Error::TransactionWithWrongClientId(..)
+            | Error::TransactionWithWrongCurrency(..)
+            | Error::NegativeAmount(_)
+            | Error::ZeroAmount(_)
+            | Error::AmountTooPrecise { .. }
+            | Error::AmountTooLarge { .. }
+            | Error::DepositTransactionMissingAmount(_)
+            | Error::WithdrawalTransactionMissingAmount(_)
+            | Error::TransferTransactionMissingAmount(_)
+            | Error::TransferTransactionMissingDestination(_)
+            | Error::TransferToSameClient(_)
+            | Error::AuthorizeTransactionMissingAmount(_)
+            | Error::NotAHold(_)
+            | Error::CaptureAmountExceedsHold { .. }
+            | Error::LimitTransactionMissingAmount(_)
+            | Error::NegativeCreditLimit(_)
+            | Error::RuleViolation { .. }
+            | Error::AdminTransactionMissingReason(_)
+            | Error::AdminTransactionMissingOperator(_)
+            | Error::DisputeNonDepositTransaction(_)
+            | Error::DisputeAmountExceedsTransaction { .. }
+            | Error::ResolveAmountExceedsDisputed { .. }
+            | Error::ChargeBackAmountExceedsDisputed { .. } => Category::Validation,
+            Error::ClientCannotWithdrawl { .. }
+            | Error::ClientCannotDispute { .. }
+            | Error::ClientCannotAuthorize { .. }
+            | Error::ClientCannotCapture { .. }
+            | Error::ClientCannotResolve { .. }
+            | Error::ClientCannotChargeBack { .. }
+            | Error::HouseCannotRefund { .. } => Category::Balance,
+            Error::ClientStatusChangeNotAllowed { .. }
+            | Error::ClientCannotClose { .. }
+            | Error::DisputeAlreadyDisputedTransaction(_)
+            | Error::DisputeWindowExpired(_)
+            | Error::HoldClosed(_)
+            | Error::ResolveNonDisputedTransaction(_)
+            | Error::ChargeBackNonDisputedTransaction(_) => Category::State,
+            Error::ClientLocked(_) | Error::ClientFrozen(_) | Error::ClientClosed(_) => {
+                Category::Lock
+            }
+            Error::CSVRowWriteFailure(_)
+            | Error::JSONRowWriteFailure(_)
+            | Error::EventLogDisabled
+            | Error::JournalDisabled
+            | Error::UnbalancedJournalEntry { .. }
+            | Error::JournalMismatch { .. }
+            | Error::StoreFailure(_)
+            | Error::RulesConfigFailure(_)
+            | Error::InvalidFee(_)
+            | Error::InvalidConfig(_)
+            | Error::SnapshotWriteFailure(_)
+            | Error::EngineNotEmpty
+            | Error::ParallelUnsupported(_)
+            | Error::ProcessorStopped
+            | Error::ServerFailure(_) => Category::System,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_serialize() -> Result<(), serde_json::Error> {
+        assert_eq!(
+            serde_json::to_string(&Error::ClientCannotWithdrawl {
+                id: 2,
+                amount: dec!(3),
+                available: dec!(2.001),
+            })?,
+            r#"{"code":"client_cannot_withdraw","category":"balance","message":"client 2 cannot withdrawl 3 as available amount is 2.001","id":2,"amount":"3","available":"2.001"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Error::ClientLocked(4))?,
+            r#"{"code":"client_locked","category":"lock","message":"client 4 is locked","id":4}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&Error::TransactionWithWrongClientId(7, 1))?,
+            r#"{"code":"transaction_with_wrong_client_id","category":"validation","message":"transaction 7 is not for client 1","id":7,"client_id":1}"#
+        );
+        assert_eq!(
+            Error::JournalMismatch {
+                account: "client 1 available".to_string(),
+                journal: dec!(1),
+                store: dec!(2),
+            }
+            .category(),
+            Category::System,
+            "a journal out of line with the stores is a fault of the engine, not of the record"
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,262 @@
+use crate::{ClientID, Error};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+/// Number of decimal places percentage fees are rounded to
+const FEE_SCALE: u32 = 4;
+
+/// Fee charged on a single transaction
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Fee {
+    /// The same amount whatever the transaction amount
+    Flat(Decimal),
+    /// A percentage of the transaction amount, `Percentage(dec!(1.5))` is 1.5%
+    Percentage(Decimal),
+}
+
+impl Fee {
+    /// Checks the fee can only be charged, never paid out: flat fees cannot be negative and percentages must be
+    /// between 0 and 100
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the fee is out of bounds
+    pub fn validate(self) -> Result<Self, Error> {
+        match self {
+            Fee::Flat(fee) if fee < Decimal::ZERO => {
+                Err(Error::InvalidFee(format!("flat fee {fee} is negative")))
+            }
+            Fee::Percentage(rate) if rate < Decimal::ZERO || rate > Decimal::ONE_HUNDRED => Err(
+                Error::InvalidFee(format!("percentage fee {rate} is not between 0 and 100")),
+            ),
+            _ => Ok(self),
+        }
+    }
+
+    fn on(self, amount: Decimal) -> Result<Decimal, Error> {
+        Ok(match self.validate()? {
+            Fee::Flat(fee) => fee,
+            Fee::Percentage(rate) => (amount * rate / Decimal::ONE_HUNDRED).round_dp(FEE_SCALE),
+        })
+    }
+}
+
+/// Fees by transaction type, `None` means the type is free
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Fees {
+    pub deposit: Option<Fee>,
+    pub withdrawal: Option<Fee>,
+}
+
+impl Fees {
+    fn validate(&self) -> Result<(), Error> {
+        for fee in self.deposit.iter().chain(&self.withdrawal) {
+            fee.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// What happens to the fee of a deposit when it is charged back
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FeeRefundPolicy {
+    /// The house keeps the fee
+    #[default]
+    Keep,
+    /// The house refunds the share of the fee matching the charged back amount. The refund is paid out of the house
+    /// available funds like a withdrawal, so a chargeback the house cannot cover the refund of is rejected with
+    /// `HouseCannotRefund`, and can be submitted again once it can.
+    Refund,
+}
+
+/// Fees the engine charges on deposits and withdrawals. Collected fees are credited to the house client, so they show
+/// up in the export like any other balance.
+#[derive(Clone, Debug)]
+pub struct FeeSchedule {
+    /// Client the fees are credited to. Its own transactions are never charged.
+    pub house_client_id: ClientID,
+    /// Fees of clients without an override
+    pub default: Fees,
+    /// Per client fees, used instead of `default`
+    pub overrides: HashMap<ClientID, Fees>,
+    pub refund_policy: FeeRefundPolicy,
+}
+
+impl FeeSchedule {
+    /// Creates a schedule without any fee, crediting the given house client
+    #[must_use]
+    pub fn new(house_client_id: ClientID) -> Self {
+        Self {
+            house_client_id,
+            default: Fees::default(),
+            overrides: HashMap::new(),
+            refund_policy: FeeRefundPolicy::default(),
+        }
+    }
+
+    /// Checks every fee of the schedule, see `Fee::validate`. The engine also rejects the records an invalid fee would
+    /// be charged on, so a schedule built without this check cannot pay clients out of the house balance.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a default or per client fee is out of bounds
+    pub fn validate(&self) -> Result<(), Error> {
+        self.default.validate()?;
+        for fees in self.overrides.values() {
+            fees.validate()?;
+        }
+        Ok(())
+    }
+
+    fn fees(&self, client_id: ClientID) -> Fees {
+        if client_id == self.house_client_id {
+            return Fees::default();
+        }
+        self.overrides
+            .get(&client_id)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Fee of a deposit, never more than the deposited amount
+    pub(crate) fn deposit_fee(
+        &self,
+        client_id: ClientID,
+        amount: Decimal,
+    ) -> Result<Decimal, Error> {
+        self.fees(client_id)
+            .deposit
+            .map_or(Ok(Decimal::ZERO), |fee| Ok(fee.on(amount)?.min(amount)))
+    }
+
+    /// Fee of a withdrawal, charged on top of the withdrawn amount
+    pub(crate) fn withdrawal_fee(
+        &self,
+        client_id: ClientID,
+        amount: Decimal,
+    ) -> Result<Decimal, Error> {
+        self.fees(client_id)
+            .withdrawal
+            .map_or(Ok(Decimal::ZERO), |fee| fee.on(amount))
+    }
+
+    /// Part of a deposit fee to refund when `amount` more of the deposit is charged back, `deposited` being the amount
+    /// the deposit credited net of its fee. The refunds of successive partial chargebacks add up to the whole fee once
+    /// everything the deposit credited is charged back.
+    pub(crate) fn refund(
+        &self,
+        fee: Decimal,
+        deposited: Decimal,
+        charged_back: Decimal,
+        amount: Decimal,
+    ) -> Decimal {
+        if self.refund_policy == FeeRefundPolicy::Keep || deposited.is_zero() {
+            return Decimal::ZERO;
+        }
+        let share = |charged_back: Decimal| (fee * charged_back / deposited).round_dp(FEE_SCALE);
+        share(charged_back + amount) - share(charged_back)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_fees() -> Result<(), Error> {
+        let mut schedule = FeeSchedule {
+            default: Fees {
+                deposit: Some(Fee::Percentage(dec!(1.5))),
+                withdrawal: Some(Fee::Flat(dec!(0.25))),
+            },
+            refund_policy: FeeRefundPolicy::Refund,
+            ..FeeSchedule::new(0)
+        };
+        schedule.overrides.insert(
+            2,
+            Fees {
+                deposit: None,
+                withdrawal: Some(Fee::Percentage(dec!(10))),
+            },
+        );
+
+        assert_eq!(schedule.deposit_fee(1, dec!(200))?, dec!(3));
+        assert_eq!(
+            schedule.deposit_fee(1, dec!(0.0001))?,
+            dec!(0),
+            "percentage fees should be rounded"
+        );
+        assert_eq!(schedule.withdrawal_fee(1, dec!(200))?, dec!(0.25));
+        assert_eq!(
+            schedule.deposit_fee(2, dec!(200))?,
+            dec!(0),
+            "overrides should replace the default fees"
+        );
+        assert_eq!(schedule.withdrawal_fee(2, dec!(200))?, dec!(20));
+        assert_eq!(
+            schedule.withdrawal_fee(0, dec!(200))?,
+            dec!(0),
+            "the house should not be charged"
+        );
+
+        schedule.default.deposit = Some(Fee::Flat(dec!(5)));
+        assert_eq!(
+            schedule.deposit_fee(1, dec!(2))?,
+            dec!(2),
+            "deposit fees should be capped at the deposited amount"
+        );
+
+        assert_eq!(
+            [dec!(1), dec!(1), dec!(1)]
+                .iter()
+                .scan(dec!(0), |charged_back, amount| {
+                    let refund = schedule.refund(dec!(1), dec!(3), *charged_back, *amount);
+                    *charged_back += amount;
+                    Some(refund)
+                })
+                .sum::<Decimal>(),
+            dec!(1),
+            "partial refunds should add up to the whole fee"
+        );
+        schedule.refund_policy = FeeRefundPolicy::Keep;
+        assert_eq!(schedule.refund(dec!(1), dec!(3), dec!(0), dec!(3)), dec!(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_fees() -> Result<(), Error> {
+        assert_eq!(Fee::Flat(dec!(0)).validate()?, Fee::Flat(dec!(0)));
+        assert_eq!(
+            Fee::Percentage(dec!(100)).validate()?,
+            Fee::Percentage(dec!(100))
+        );
+        for fee in [
+            Fee::Flat(dec!(-0.01)),
+            Fee::Percentage(dec!(-1)),
+            Fee::Percentage(dec!(100.5)),
+        ] {
+            assert!(
+                matches!(fee.validate(), Err(Error::InvalidFee(_))),
+                "{fee:?} should be rejected"
+            );
+        }
+
+        let mut schedule = FeeSchedule::new(0);
+        schedule.overrides.insert(
+            1,
+            Fees {
+                deposit: Some(Fee::Flat(dec!(-5))),
+                withdrawal: None,
+            },
+        );
+        assert!(matches!(schedule.validate(), Err(Error::InvalidFee(_))));
+        assert!(
+            matches!(schedule.deposit_fee(1, dec!(10)), Err(Error::InvalidFee(_))),
+            "a negative fee should never be credited to the client"
+        );
+        assert_eq!(schedule.deposit_fee(2, dec!(10))?, dec!(0));
+        Ok(())
+    }
+}
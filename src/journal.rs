@@ -0,0 +1,404 @@
+use crate::stores::clients::Client;
+use crate::{errors::Error, ClientID};
+use rust_decimal::Decimal;
+use std::{collections::BTreeMap, fmt};
+
+/// Accounts of the double-entry journal.
+///
+/// Client accounts are liabilities (we owe the funds to the client), so their balance is credits minus debits. The
+/// settlement and chargeback loss accounts are assets/expenses, their balance is debits minus credits.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Account {
+    ClientAvailable(ClientID),
+    ClientHeld(ClientID),
+    /// Funds moving in and out of the system (deposits, withdrawals and deposit chargebacks)
+    Settlement,
+    /// Funds credited back to clients on withdrawal disputes, which we have to absorb
+    ChargebackLoss,
+}
+
+impl Account {
+    fn is_credit_normal(self) -> bool {
+        matches!(self, Account::ClientAvailable(_) | Account::ClientHeld(_))
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Account::ClientAvailable(id) => write!(f, "client {id} available"),
+            Account::ClientHeld(id) => write!(f, "client {id} held"),
+            Account::Settlement => f.write_str("settlement"),
+            Account::ChargebackLoss => f.write_str("chargeback loss"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Debit,
+    Credit,
+}
+
+/// Every account exists once per currency, so postings name the currency of their amount
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Posting {
+    pub account: Account,
+    pub currency: String,
+    pub side: Side,
+    pub amount: Decimal,
+}
+
+impl Posting {
+    /// Debits `debit` and credits `credit` with the same amount
+    #[must_use]
+    pub fn transfer(
+        currency: &str,
+        debit: Account,
+        credit: Account,
+        amount: Decimal,
+    ) -> [Posting; 2] {
+        [
+            Posting {
+                account: debit,
+                currency: currency.to_string(),
+                side: Side::Debit,
+                amount,
+            },
+            Posting {
+                account: credit,
+                currency: currency.to_string(),
+                side: Side::Credit,
+                amount,
+            },
+        ]
+    }
+}
+
+/// Balanced set of postings made for a single record
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct JournalEntry {
+    /// Sequence number of the record that caused the entry, as counted by the engine
+    pub sequence: u64,
+    pub postings: Vec<Posting>,
+}
+
+/// Totals of the journal, per currency and account
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TrialBalance {
+    /// Total debits, per currency
+    pub debits: BTreeMap<String, Decimal>,
+    /// Total credits, per currency
+    pub credits: BTreeMap<String, Decimal>,
+    /// Balance of every account, in the account normal direction
+    pub balances: BTreeMap<(String, Account), Decimal>,
+}
+
+/// Double-entry journal. Every entry has to balance in each of its currencies, so the total debits always equal the
+/// total credits.
+#[derive(Default, Clone, Debug)]
+pub struct Journal {
+    entries: Vec<JournalEntry>,
+    // Debits minus credits, per currency and account
+    totals: BTreeMap<(String, Account), Decimal>,
+    debits: BTreeMap<String, Decimal>,
+    credits: BTreeMap<String, Decimal>,
+}
+
+impl Journal {
+    /// Appends an entry to the journal
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the debits and credits of the postings do not match in one of their currencies
+    pub fn post(&mut self, sequence: u64, postings: Vec<Posting>) -> Result<(), Error> {
+        let mut sums: BTreeMap<&str, (Decimal, Decimal)> = BTreeMap::new();
+        for posting in &postings {
+            let (debits, credits) = sums.entry(&posting.currency).or_default();
+            match posting.side {
+                Side::Debit => *debits += posting.amount,
+                Side::Credit => *credits += posting.amount,
+            }
+        }
+        if let Some((currency, (debits, credits))) = sums
+            .into_iter()
+            .find(|(_, (debits, credits))| debits != credits)
+        {
+            return Err(Error::UnbalancedJournalEntry {
+                currency: currency.to_string(),
+                debits,
+                credits,
+            });
+        }
+
+        for posting in &postings {
+            self.apply(posting, posting.amount);
+        }
+        self.entries.push(JournalEntry { sequence, postings });
+
+        Ok(())
+    }
+
+    /// Removes the last entry, undoing its postings
+    pub(crate) fn unpost(&mut self) {
+        if let Some(entry) = self.entries.pop() {
+            for posting in &entry.postings {
+                self.apply(posting, -posting.amount);
+            }
+        }
+    }
+
+    fn apply(&mut self, posting: &Posting, amount: Decimal) {
+        let total = self
+            .totals
+            .entry((posting.currency.clone(), posting.account))
+            .or_default();
+        let sums = match posting.side {
+            Side::Debit => {
+                *total += amount;
+                &mut self.debits
+            }
+            Side::Credit => {
+                *total -= amount;
+                &mut self.credits
+            }
+        };
+        *sums.entry(posting.currency.clone()).or_default() += amount;
+    }
+
+    /// Every entry posted so far, in order
+    #[must_use]
+    pub fn entries(&self) -> &[JournalEntry] {
+        &self.entries
+    }
+
+    /// Balance of an account in the given currency, in the account normal direction
+    #[must_use]
+    pub fn balance(&self, currency: &str, account: Account) -> Decimal {
+        let total = self
+            .totals
+            .get(&(currency.to_string(), account))
+            .copied()
+            .unwrap_or_default();
+        if account.is_credit_normal() {
+            -total
+        } else {
+            total
+        }
+    }
+
+    /// Checks that the journal balances in every currency and that the client accounts match the given client
+    /// balances
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the total debits do not equal the total credits, or if the journal disagrees with the
+    /// balance of one of the clients
+    pub fn trial_balance<'a>(
+        &self,
+        clients: impl IntoIterator<Item = (ClientID, &'a Client)>,
+    ) -> Result<TrialBalance, Error> {
+        for (currency, debits) in &self.debits {
+            let credits = self.credits.get(currency).copied().unwrap_or_default();
+            if *debits != credits {
+                return Err(Error::UnbalancedJournalEntry {
+                    currency: currency.clone(),
+                    debits: *debits,
+                    credits,
+                });
+            }
+        }
+
+        // Every client account of the journal has to match the store, and every store balance the journal
+        let mut expected = BTreeMap::new();
+        for (id, client) in clients {
+            for (currency, balance) in &client.balances {
+                expected.insert(
+                    (currency.clone(), Account::ClientAvailable(id)),
+                    balance.available_amount,
+                );
+                expected.insert(
+                    (currency.clone(), Account::ClientHeld(id)),
+                    balance.held_amount,
+                );
+            }
+        }
+        for (currency, account) in self.totals.keys() {
+            if !account.is_credit_normal() {
+                continue;
+            }
+            expected
+                .entry((currency.clone(), *account))
+                .or_insert(Decimal::ZERO);
+        }
+        for ((currency, account), store) in expected {
+            let journal = self.balance(&currency, account);
+            if journal != store {
+                return Err(Error::JournalMismatch {
+                    account: if currency.is_empty() {
+                        account.to_string()
+                    } else {
+                        format!("{account} {currency}")
+                    },
+                    journal,
+                    store,
+                });
+            }
+        }
+
+        Ok(TrialBalance {
+            debits: self.debits.clone(),
+            credits: self.credits.clone(),
+            balances: self
+                .totals
+                .keys()
+                .map(|(currency, account)| {
+                    (
+                        (currency.clone(), *account),
+                        self.balance(currency, *account),
+                    )
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::stores::clients::Balance;
+
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_post() {
+        let mut journal = Journal::default();
+
+        assert_eq!(
+            journal.post(
+                1,
+                vec![
+                    Posting {
+                        account: Account::Settlement,
+                        currency: "USD".to_string(),
+                        side: Side::Debit,
+                        amount: dec!(2),
+                    },
+                    Posting {
+                        account: Account::ClientAvailable(1),
+                        currency: "USD".to_string(),
+                        side: Side::Credit,
+                        amount: dec!(1),
+                    },
+                ],
+            ),
+            Err(Error::UnbalancedJournalEntry {
+                currency: "USD".to_string(),
+                debits: dec!(2),
+                credits: dec!(1),
+            }),
+            "unbalanced entries should be rejected"
+        );
+        assert!(journal.entries().is_empty());
+
+        let postings = Posting::transfer(
+            "USD",
+            Account::Settlement,
+            Account::ClientAvailable(1),
+            dec!(2),
+        );
+        assert_eq!(journal.post(1, postings.to_vec()), Ok(()));
+        assert_eq!(journal.balance("USD", Account::Settlement), dec!(2));
+        assert_eq!(
+            journal.balance("USD", Account::ClientAvailable(1)),
+            dec!(2),
+            "client accounts should be credit normal"
+        );
+
+        journal.unpost();
+        assert!(journal.entries().is_empty());
+        assert_eq!(journal.balance("USD", Account::Settlement), dec!(0));
+        assert_eq!(journal.balance("USD", Account::ClientAvailable(1)), dec!(0));
+    }
+
+    #[test]
+    fn test_trial_balance() {
+        let mut journal = Journal::default();
+        for (sequence, postings) in [
+            Posting::transfer(
+                "USD",
+                Account::Settlement,
+                Account::ClientAvailable(1),
+                dec!(5),
+            ),
+            Posting::transfer(
+                "USD",
+                Account::ClientAvailable(1),
+                Account::ClientHeld(1),
+                dec!(2),
+            ),
+            Posting::transfer(
+                "EUR",
+                Account::Settlement,
+                Account::ClientAvailable(1),
+                dec!(4),
+            ),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            journal
+                .post(sequence as u64, postings.to_vec())
+                .expect("entry should balance");
+        }
+
+        let mut client = Client::default();
+        client.balances.insert(
+            "USD".to_string(),
+            Balance {
+                available_amount: dec!(3),
+                held_amount: dec!(2),
+            },
+        );
+        client.balances.insert(
+            "EUR".to_string(),
+            Balance {
+                available_amount: dec!(4),
+                held_amount: dec!(0),
+            },
+        );
+        let trial_balance = journal
+            .trial_balance([(1, &client)])
+            .expect("journal should agree with the client");
+        assert_eq!(
+            trial_balance.debits,
+            BTreeMap::from([("EUR".to_string(), dec!(4)), ("USD".to_string(), dec!(7))]),
+            "debits should be totalled per currency"
+        );
+        assert_eq!(trial_balance.credits, trial_balance.debits);
+        assert_eq!(
+            trial_balance.balances[&("USD".to_string(), Account::ClientHeld(1))],
+            dec!(2)
+        );
+
+        client
+            .balances
+            .get_mut("USD")
+            .expect("client should hold USD")
+            .held_amount = dec!(1);
+        assert_eq!(
+            journal.trial_balance([(1, &client)]).map(|_| ()),
+            Err(Error::JournalMismatch {
+                account: "client 1 held USD".to_string(),
+                journal: dec!(2),
+                store: dec!(1),
+            })
+        );
+
+        assert!(
+            journal.trial_balance([]).is_err(),
+            "journal balances missing from the store should be reported"
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use crate::stores::TransactionStore;
+use crate::stores::{
+    clients::{Client, Clients, Status},
+    ClientStore,
+};
+use crate::{dtos::TransactionRecord, engine::Engine, errors::Error, ClientID};
+use rust_decimal::Decimal;
+
+/// Change to the balance of a client in a single currency
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct AssetDelta {
+    pub currency: String,
+    pub available: Decimal,
+    pub held: Decimal,
+}
+
+/// Change to the balances of a single client caused by an event
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BalanceDelta {
+    pub client_id: ClientID,
+    /// One entry per currency the event changed (or opened a balance in)
+    pub assets: Vec<AssetDelta>,
+    /// New status of the client, if the event changed it
+    pub status: Option<Status>,
+    /// New credit limit of the client, if the event changed it
+    pub credit_limit: Option<Decimal>,
+}
+
+impl BalanceDelta {
+    fn between(client_id: ClientID, before: Option<&Client>, after: &Client) -> Self {
+        let before = before.cloned().unwrap_or_default();
+        Self {
+            client_id,
+            assets: after
+                .balances
+                .iter()
+                .filter(|(currency, balance)| before.balances.get(*currency) != Some(balance))
+                .map(|(currency, balance)| {
+                    let previous = before.balance(currency);
+                    AssetDelta {
+                        currency: currency.clone(),
+                        available: balance.available_amount - previous.available_amount,
+                        held: balance.held_amount - previous.held_amount,
+                    }
+                })
+                .collect(),
+            status: (after.status != before.status).then_some(after.status),
+            credit_limit: (after.credit_limit != before.credit_limit).then_some(after.credit_limit),
+        }
+    }
+}
+
+/// An accepted transaction record, along with the balance changes it caused
+#[derive(Clone, Debug)]
+pub struct Event {
+    /// Position of the record among all the records handled by the engine, starting at 1. Rejected records are not
+    /// logged, so sequence numbers can have gaps.
+    pub sequence: u64,
+    pub record: TransactionRecord,
+    pub deltas: Vec<BalanceDelta>,
+}
+
+/// Append-only log of every record accepted by the engine
+#[derive(Default, Clone, Debug)]
+pub struct Ledger {
+    // Balances the events apply to, only set when the engine was restored from a snapshot
+    opening: Vec<(ClientID, Client)>,
+    events: Vec<Event>,
+}
+
+impl Ledger {
+    /// An empty log whose events apply on top of the given client balances
+    pub(crate) fn starting_from(opening: Vec<(ClientID, Client)>) -> Self {
+        Self {
+            opening,
+            events: Vec::new(),
+        }
+    }
+
+    pub(crate) fn append(
+        &mut self,
+        sequence: u64,
+        record: &TransactionRecord,
+        changes: &[(ClientID, Option<Client>, Client)],
+    ) {
+        debug_assert!(self.events.last().is_none_or(|e| e.sequence < sequence));
+
+        self.events.push(Event {
+            sequence,
+            record: record.clone(),
+            deltas: changes
+                .iter()
+                .map(|(client_id, before, after)| {
+                    BalanceDelta::between(*client_id, before.as_ref(), after)
+                })
+                .collect(),
+        });
+    }
+
+    /// Every logged event, in the order they were accepted
+    #[must_use]
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Rebuilds the client balances as they were right after the record with the given sequence number was handled.
+    /// After a snapshot restore, balances before the snapshot are the restored ones.
+    #[must_use]
+    pub fn clients_at(&self, sequence: u64) -> Clients {
+        let mut clients = Clients::default();
+        clients.database.extend(self.opening.iter().cloned());
+        for event in self.events.iter().take_while(|e| e.sequence <= sequence) {
+            for delta in &event.deltas {
+                let client = clients.database.entry(delta.client_id).or_default();
+                for asset in &delta.assets {
+                    let balance = client.balances.entry(asset.currency.clone()).or_default();
+                    balance.available_amount += asset.available;
+                    balance.held_amount += asset.held;
+                }
+                if let Some(status) = delta.status {
+                    client.status = status;
+                }
+                if let Some(credit_limit) = delta.credit_limit {
+                    client.credit_limit = credit_limit;
+                }
+            }
+        }
+
+        clients
+    }
+
+    /// Feeds every logged record, in order, to the given engine. After a snapshot restore, the engine has to be
+    /// restored from the same snapshot first.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the engine rejects one of the records, which means its state diverged from the engine
+    /// the events were logged by
+    pub fn replay<C: ClientStore, T: TransactionStore>(
+        &self,
+        engine: &mut Engine<C, T>,
+    ) -> Result<(), Error> {
+        self.events
+            .iter()
+            .try_for_each(|event| engine.handle(&event.record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dtos::TransactionType, engine::Config, stores::clients::Balance};
+
+    use rust_decimal_macros::dec;
+
+    fn record(
+        transaction_type: TransactionType,
+        client_id: ClientID,
+        transaction_id: u32,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type,
+            client_id,
+            transaction_id,
+            amount,
+            ..TransactionRecord::default()
+        }
+    }
+
+    #[test]
+    fn test_clients_at() -> Result<(), Error> {
+        let mut engine = Engine::default().with_config(Config {
+            event_log: true,
+            ..Config::default()
+        })?;
+
+        engine.handle(&record(TransactionType::Deposit, 7, 1, Some(dec!(10))))?;
+        engine.handle(&record(TransactionType::Deposit, 8, 2, Some(dec!(3))))?;
+        assert!(engine
+            .handle(&record(TransactionType::Withdrawal, 7, 3, Some(dec!(100))))
+            .is_err());
+        engine.handle(&record(TransactionType::Dispute, 7, 1, Some(dec!(4))))?;
+        engine.handle(&record(TransactionType::Chargeback, 7, 1, None))?;
+
+        let ledger = engine.ledger().expect("event log should be enabled");
+        assert_eq!(
+            ledger
+                .events()
+                .iter()
+                .map(|e| e.sequence)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 4, 5],
+            "rejected records should not be logged"
+        );
+        assert_eq!(
+            ledger.events()[2].deltas,
+            vec![BalanceDelta {
+                client_id: 7,
+                assets: vec![AssetDelta {
+                    currency: String::new(),
+                    available: dec!(-4),
+                    held: dec!(4),
+                }],
+                status: None,
+                credit_limit: None,
+            }],
+            "dispute delta should move funds from available to held"
+        );
+
+        let clients = ledger.clients_at(0);
+        assert!(
+            clients.database.is_empty(),
+            "no client before the first record"
+        );
+
+        let clients = ledger.clients_at(3);
+        assert_eq!(clients.database[&7].balance("").available_amount, dec!(10));
+        assert_eq!(clients.database[&8].balance("").available_amount, dec!(3));
+
+        let clients = ledger.clients_at(5);
+        assert_eq!(
+            clients.database[&7],
+            Client {
+                balances: [(
+                    String::new(),
+                    Balance {
+                        available_amount: dec!(6),
+                        held_amount: dec!(0),
+                    }
+                )]
+                .into(),
+                status: Status::Locked,
+                ..Client::default()
+            },
+            "balances after the chargeback should match"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay() -> Result<(), Error> {
+        let mut engine = Engine::default().with_config(Config {
+            event_log: true,
+            ..Config::default()
+        })?;
+
+        engine.handle(&record(TransactionType::Deposit, 1, 1, Some(dec!(10))))?;
+        engine.handle(&record(TransactionType::Withdrawal, 1, 2, Some(dec!(2.5))))?;
+        engine.handle(&record(TransactionType::Dispute, 1, 1, Some(dec!(1))))?;
+
+        let mut replayed = Engine::default();
+        engine
+            .ledger()
+            .expect("event log should be enabled")
+            .replay(&mut replayed)?;
+
+        assert_eq!(
+            replayed.get_clients()?,
+            engine.get_clients()?,
+            "replaying should rebuild the same clients"
+        );
+
+        Ok(())
+    }
+}
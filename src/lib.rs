@@ -4,67 +4,355 @@
 mod dtos;
 mod engine;
 mod errors;
-mod stores;
+mod fees;
+mod journal;
+mod ledger;
+mod ndjson;
+mod output;
+mod parallel;
+mod rules;
+mod server;
+mod snapshot;
+pub mod stores;
+#[cfg(feature = "async")]
+mod streams;
 
-use engine::Engine;
-use errors::Error;
-use std::io::{Read, Write};
+pub use dtos::TransactionRecord;
+pub use engine::{AmountLimits, Config, DisputePolicy, Engine, JournalMode};
+pub use errors::{Category, Error};
+pub use fees::{Fee, FeeRefundPolicy, FeeSchedule, Fees};
+pub use journal::{Account, Journal, JournalEntry, Posting, Side, TrialBalance};
+pub use ledger::{AssetDelta, BalanceDelta, Event, Ledger};
+pub use ndjson::NDJSONProcessor;
+pub use output::{OutputPolicy, Rounding};
+pub use rules::{Check, Rule, Rules};
+pub use server::Server;
+pub use snapshot::{Snapshot, SNAPSHOT_VERSION};
+use std::{
+    io::{self, Read, Write},
+    num::NonZeroUsize,
+};
+use stores::{clients::Status, ClientStore, TransactionStore};
+#[cfg(feature = "async")]
+pub use streams::{Ingest, StreamProcessor, StreamReport, DEFAULT_QUEUE_CAPACITY};
 
-type ClientID = u16;
-type TransactionID = u32;
+pub type ClientID = u16;
+pub type TransactionID = u32;
 
-#[derive(Default)]
-pub struct CSVProcessor {
-    engine: Engine,
+/// How errors are written to the error output
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorFormat {
+    /// `error: {message}` lines
+    #[default]
+    Text,
+    /// One json object per line, see the `Serialize` implementation of `Error`
+    Json,
 }
 
-impl CSVProcessor {
+pub struct CSVProcessor<C = stores::clients::Clients, T = stores::transactions::Transactions> {
+    engine: Engine<C, T>,
+    error_format: ErrorFormat,
+    output_policy: OutputPolicy,
+}
+
+impl Default for CSVProcessor {
+    fn default() -> Self {
+        Self::new(Engine::default())
+    }
+}
+
+impl<C: ClientStore, T: TransactionStore> CSVProcessor<C, T> {
+    /// Creates a processor that feeds the given engine
+    pub fn new(engine: Engine<C, T>) -> Self {
+        Self {
+            engine,
+            error_format: ErrorFormat::default(),
+            output_policy: OutputPolicy::default(),
+        }
+    }
+
+    /// Sets how errors are written to the error output
+    #[must_use]
+    pub fn with_error_format(mut self, error_format: ErrorFormat) -> Self {
+        self.error_format = error_format;
+        self
+    }
+
+    /// Sets how amounts are rounded in the export
+    #[must_use]
+    pub fn with_output_policy(mut self, output_policy: OutputPolicy) -> Self {
+        self.output_policy = output_policy;
+        self
+    }
+
     /// Deserializes the reader as a csv and processes each record
-    pub fn process(&mut self, csv_input: impl Read, mut err_output: impl Write) {
-        let mut csv_reader = csv::ReaderBuilder::new()
-            .trim(csv::Trim::All)
-            .from_reader(csv_input);
+    pub fn process(&mut self, csv_input: impl Read, err_output: impl Write) {
+        self.process_rows(csv_input, err_output, None::<csv::Writer<io::Sink>>);
+    }
 
-        for res in csv_reader.deserialize() {
-            if let Err(error) = res
-                .map_err(|e| Error::CSVRowReadFailure(e.to_string()))
-                .and_then(|r| self.engine.handle(&r))
-            {
-                if let Err(error_error) = writeln!(err_output, "error: {}", error) {
-                    eprintln!("error: {} for error: {}", error_error, error);
+    /// Same as `process`, also writing every rejected row to `rejects_output` as csv.
+    ///
+    /// Rejected rows keep the input columns and get four more: `line` (line number of the row in the input),
+    /// `error_code` (see `Error::code`), `error_category` and `error_message`. The extra columns are ignored on input,
+    /// so the rejects can be fixed and fed back in as they are.
+    pub fn process_with_rejects(
+        &mut self,
+        csv_input: impl Read,
+        err_output: impl Write,
+        rejects_output: impl Write,
+    ) {
+        self.process_rows(
+            csv_input,
+            err_output,
+            Some(
+                csv::WriterBuilder::new()
+                    .flexible(true)
+                    .from_writer(rejects_output),
+            ),
+        );
+    }
+
+    fn process_rows<W: Write>(
+        &mut self,
+        csv_input: impl Read,
+        mut err_output: impl Write,
+        mut rejects: Option<csv::Writer<W>>,
+    ) {
+        let mut csv_reader = csv_reader(csv_input);
+        let headers = match csv_reader.byte_headers() {
+            Ok(headers) => headers.clone(),
+            Err(e) => {
+                report(
+                    &mut err_output,
+                    self.error_format,
+                    &Error::CSVRowReadFailure(e.to_string()),
+                );
+                return;
+            }
+        };
+        if let Some(rejects) = &mut rejects {
+            let mut header = headers.clone();
+            header.push_field(b"line");
+            header.push_field(b"error_code");
+            header.push_field(b"error_category");
+            header.push_field(b"error_message");
+            if let Err(e) = rejects.write_byte_record(&header) {
+                report(
+                    &mut err_output,
+                    self.error_format,
+                    &Error::CSVRowWriteFailure(e.to_string()),
+                );
+            }
+        }
+
+        for res in csv_reader.byte_records() {
+            let (row, result) = match res {
+                Ok(row) => {
+                    let result = match row.deserialize(Some(&headers)) {
+                        Ok(record) => self.engine.handle(&record),
+                        Err(e) => {
+                            self.engine.skip_record();
+                            Err(Error::CSVRowReadFailure(e.to_string()))
+                        }
+                    };
+                    (Some(row), result)
+                }
+                Err(e) => {
+                    self.engine.skip_record();
+                    (None, Err(Error::CSVRowReadFailure(e.to_string())))
+                }
+            };
+
+            if let Err(error) = result {
+                report(&mut err_output, self.error_format, &error);
+                if let (Some(rejects), Some(row)) = (&mut rejects, row) {
+                    if let Err(e) = write_reject(rejects, &headers, row, &error) {
+                        report(&mut err_output, self.error_format, &e);
+                    }
                 }
             }
         }
+
+        if let Some(rejects) = &mut rejects {
+            if let Err(e) = rejects.flush() {
+                report(
+                    &mut err_output,
+                    self.error_format,
+                    &Error::CSVRowWriteFailure(e.to_string()),
+                );
+            }
+        }
     }
 
-    /// Serializes the processed transactions into csv format
+    /// Serializes the processed transactions into csv format. When the journal is enabled, the trial balance is checked
+    /// first and nothing is written if it fails.
     ///
     /// # Errors
     ///
-    /// Will return `Err` if the csv writer is unable to write the serialized rows to the passed in writer
+    /// Will return `Err` if the trial balance fails, or if the csv writer is unable to write the serialized rows to the
+    /// passed in writer
     pub fn export_clients(&self, writer: impl Write) -> Result<(), Error> {
-        let mut csv_writer = csv::Writer::from_writer(writer);
-        csv_writer
-            .write_record(&["client", "available", "held", "total", "locked"])
-            .map_err(|e| Error::CSVRowWriteFailure(e.to_string()))?;
-        for (client_id, client) in self.engine.get_clients() {
+        write_clients(self.engine.get_clients()?, self.output_policy, writer)
+    }
+
+    /// Serializes the client balances as they were right after the record with the given sequence number was processed.
+    /// Every data row of the input takes a number, counting from 1, rows that fail to parse included.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the event log is not enabled, or if the csv writer is unable to write the serialized rows
+    /// to the passed in writer
+    pub fn export_clients_at(&self, sequence: u64, writer: impl Write) -> Result<(), Error> {
+        write_clients(
+            self.engine.clients_at(sequence)?,
+            self.output_policy,
+            writer,
+        )
+    }
+
+    /// The engine records are fed to
+    pub fn engine(&self) -> &Engine<C, T> {
+        &self.engine
+    }
+}
+
+impl CSVProcessor {
+    /// Same as `process`, spreading the records over `workers` threads by client id. Each client is processed by a
+    /// single worker in input order, and records involving several clients (transfers, fee charges or records reusing
+    /// a transaction id seen with other clients) wait for every worker to catch up, so the balances and errors are
+    /// exactly those of `process`. Errors are written once every record is processed.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the engine relies on the global order of records (event log, journal, hold expiry,
+    /// dispute windows or withdrawal velocity rules), in which case no record is processed
+    pub fn process_parallel(
+        &mut self,
+        csv_input: impl Read,
+        mut err_output: impl Write,
+        workers: NonZeroUsize,
+    ) -> Result<(), Error> {
+        let mut csv_reader = csv_reader(csv_input);
+        let headers = match csv_reader.byte_headers() {
+            Ok(headers) => headers.clone(),
+            Err(e) => {
+                report(
+                    &mut err_output,
+                    self.error_format,
+                    &Error::CSVRowReadFailure(e.to_string()),
+                );
+                return Ok(());
+            }
+        };
+
+        let records = csv_reader.byte_records().map(|res| {
+            let record = res
+                .map_err(|e| Error::CSVRowReadFailure(e.to_string()))
+                .and_then(|row| {
+                    row.deserialize(Some(&headers))
+                        .map_err(|e| Error::CSVRowReadFailure(e.to_string()))
+                });
+            ((), record)
+        });
+        let error_format = self.error_format;
+        parallel::process(&mut self.engine, workers, records, |(), error| {
+            report(&mut err_output, error_format, error);
+        })
+    }
+}
+
+// Optional trailing columns (like the admin reason and operator) can be left out of rows that do not need them
+fn csv_reader<R: Read>(csv_input: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(csv_input)
+}
+
+pub(crate) fn report(err_output: &mut impl Write, error_format: ErrorFormat, error: &Error) {
+    let written = match error_format {
+        ErrorFormat::Text => writeln!(err_output, "error: {error}"),
+        ErrorFormat::Json => serde_json::to_writer(&mut *err_output, error)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(err_output)),
+    };
+    if let Err(error_error) = written {
+        eprintln!("error: {error_error} for error: {error}");
+    }
+}
+
+fn write_reject<W: Write>(
+    rejects: &mut csv::Writer<W>,
+    headers: &csv::ByteRecord,
+    mut row: csv::ByteRecord,
+    error: &Error,
+) -> Result<(), Error> {
+    let line = row
+        .position()
+        .map(|position| position.line().to_string())
+        .unwrap_or_default();
+    // Rows that left out optional trailing columns are padded so the extra columns line up
+    while row.len() < headers.len() {
+        row.push_field(b"");
+    }
+    row.push_field(line.as_bytes());
+    row.push_field(error.code().as_bytes());
+    row.push_field(error.category().as_str().as_bytes());
+    row.push_field(error.to_string().as_bytes());
+
+    rejects
+        .write_byte_record(&row)
+        .map_err(|e| Error::CSVRowWriteFailure(e.to_string()))
+}
+
+fn write_clients(
+    clients: Vec<(ClientID, stores::clients::Client)>,
+    output_policy: OutputPolicy,
+    writer: impl Write,
+) -> Result<(), Error> {
+    let amount = |amount| output_policy.apply(amount).to_string();
+    let mut csv_writer = csv::Writer::from_writer(writer);
+    csv_writer
+        .write_record([
+            "client",
+            "available",
+            "held",
+            "total",
+            "locked",
+            "status",
+            "currency",
+            "credit_limit",
+            "overdrawn",
+            "remaining_credit",
+        ])
+        .map_err(|e| Error::CSVRowWriteFailure(e.to_string()))?;
+    // One row per client and currency
+    for (client_id, client) in clients {
+        for (currency, balance) in client.exported_balances() {
             csv_writer
-                .write_record(&[
+                .write_record([
                     client_id.to_string(),
-                    client.available_amount.to_string(),
-                    client.held_amount.to_string(),
-                    (client.available_amount + client.held_amount).to_string(),
-                    client.locked.to_string(),
+                    amount(balance.available_amount),
+                    amount(balance.held_amount),
+                    output_policy
+                        .total(balance.available_amount, balance.held_amount)
+                        .to_string(),
+                    (client.status != Status::Active).to_string(),
+                    client.status.to_string(),
+                    currency.to_string(),
+                    amount(client.credit_limit),
+                    amount(balance.overdrawn()),
+                    amount(client.remaining_credit()),
                 ])
                 .map_err(|e| Error::CSVRowWriteFailure(e.to_string()))?;
         }
+    }
 
-        csv_writer
-            .flush()
-            .map_err(|e| Error::CSVRowWriteFailure(e.to_string()))?;
+    csv_writer
+        .flush()
+        .map_err(|e| Error::CSVRowWriteFailure(e.to_string()))?;
 
-        Ok(())
-    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -73,7 +361,11 @@ mod tests {
     use rust_decimal::Decimal;
     use rust_decimal_macros::dec;
     use serde::Deserialize;
-    use std::{collections::HashMap, fs::File, io::BufReader};
+    use std::{
+        collections::HashMap,
+        fs::File,
+        io::{self, BufReader},
+    };
 
     #[derive(Deserialize, PartialEq, Debug)]
     struct ClientRecord {
@@ -142,4 +434,415 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn replay_integration_test() {
+        let file = File::open("resources/test/test1.csv").expect("Unable to open file");
+        let reader = BufReader::new(file);
+
+        let mut processor = CSVProcessor::new(
+            Engine::default()
+                .with_config(Config {
+                    event_log: true,
+                    ..Config::default()
+                })
+                .expect("config should be valid"),
+        );
+        processor.process(reader, io::sink());
+
+        let mut replayed = Engine::default();
+        processor
+            .engine()
+            .ledger()
+            .expect("event log should be enabled")
+            .replay(&mut replayed)
+            .expect("replaying accepted records should not fail");
+        let replayed = CSVProcessor::new(replayed);
+
+        let mut output = Vec::new();
+        processor
+            .export_clients(&mut output)
+            .expect("exporting clients should not fail");
+        let mut replayed_output = Vec::new();
+        replayed
+            .export_clients(&mut replayed_output)
+            .expect("exporting clients should not fail");
+        assert_eq!(
+            String::from_utf8(replayed_output),
+            String::from_utf8(output.clone()),
+            "replayed engine should export the same balances"
+        );
+
+        let mut rebuilt_output = Vec::new();
+        processor
+            .export_clients_at(u64::MAX, &mut rebuilt_output)
+            .expect("exporting clients should not fail");
+        assert_eq!(
+            String::from_utf8(rebuilt_output),
+            String::from_utf8(output),
+            "balances rebuilt from the ledger should match the current balances"
+        );
+    }
+
+    #[test]
+    fn as_of_integration_test() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,x,2,5
+deposit,1,3,5
+withdrawal,1,4,20
+deposit,1,5,1
+";
+        let mut processor = CSVProcessor::new(
+            Engine::default()
+                .with_config(Config {
+                    event_log: true,
+                    ..Config::default()
+                })
+                .expect("config should be valid"),
+        );
+        processor.process(input.as_bytes(), io::sink());
+        assert_eq!(
+            processor.engine().record_counts(),
+            (5, 3),
+            "rows that fail to parse should be counted"
+        );
+        let sequences: Vec<_> = processor
+            .engine()
+            .ledger()
+            .expect("the event log should be enabled")
+            .events()
+            .iter()
+            .map(|event| (event.sequence, event.record.transaction_id))
+            .collect();
+        assert_eq!(
+            sequences,
+            vec![(1, 1), (3, 3), (5, 5)],
+            "records should keep their row number after a row that fails to parse"
+        );
+
+        let balances_at = |sequence| {
+            let mut output = Vec::new();
+            processor
+                .export_clients_at(sequence, &mut output)
+                .expect("exporting clients should not fail");
+            String::from_utf8(output).expect("output should be utf8 characters")
+        };
+        assert_eq!(
+            balances_at(2),
+            balances_at(1),
+            "the unparsed row should not change the balances"
+        );
+        assert_eq!(
+            balances_at(3),
+            "client,available,held,total,locked,status,currency,credit_limit,overdrawn,remaining_credit\n1,15,0,15,false,active,,0,0,0\n"
+        );
+        assert_eq!(balances_at(4), balances_at(3));
+        assert_eq!(
+            balances_at(5),
+            "client,available,held,total,locked,status,currency,credit_limit,overdrawn,remaining_credit\n1,16,0,16,false,active,,0,0,0\n"
+        );
+    }
+
+    #[test]
+    fn rejects_integration_test() {
+        let input = "\
+type,client,tx,amount,reason,operator
+deposit,1,1,10
+withdrawal,1,2,25
+deposit,1,3,abc
+dispute,1,1
+freeze,1,4
+resolve,1,1,,
+";
+        let mut processor = CSVProcessor::default();
+        let mut rejects = Vec::new();
+        processor.process_with_rejects(input.as_bytes(), io::sink(), &mut rejects);
+        let rejects = String::from_utf8(rejects).expect("rejects should be utf8 characters");
+        assert_eq!(
+            rejects,
+            "\
+type,client,tx,amount,reason,operator,line,error_code,error_category,error_message
+withdrawal,1,2,25,,,3,client_cannot_withdraw,balance,client 1 cannot withdrawl 25 as available amount is 10
+deposit,1,3,abc,,,4,deposit_missing_amount,validation,deposit transaction 3 missing amount field
+freeze,1,4,,,,6,admin_missing_reason,validation,admin transaction 4 missing reason field
+",
+            "rejected rows should be written with their line, error code and message"
+        );
+
+        // Fixing a reject and feeding the file back in should ignore the extra columns
+        let fixed = rejects.replace("withdrawal,1,2,25", "withdrawal,1,2,5");
+        let mut err_output = Vec::new();
+        processor.process(fixed.as_bytes(), &mut err_output);
+        let err_msg = String::from_utf8(err_output).expect("error logs should be utf8 characters");
+        assert_eq!(
+            err_msg.lines().count(),
+            2,
+            "only the rows that were not fixed should fail again: {err_msg}"
+        );
+        assert_eq!(
+            processor
+                .engine()
+                .get_clients()
+                .map(|clients| clients[0].1.balance("").available_amount),
+            Ok(dec!(5))
+        );
+    }
+
+    #[test]
+    fn json_errors_test() {
+        let input = "type,client,tx,amount\nwithdrawal,3,1,2\n";
+        let mut processor = CSVProcessor::default().with_error_format(ErrorFormat::Json);
+        let mut err_output = Vec::new();
+        processor.process(input.as_bytes(), &mut err_output);
+        assert_eq!(
+            String::from_utf8(err_output).expect("error logs should be utf8 characters"),
+            "{\"code\":\"client_not_exist\",\"category\":\"validation\",\"message\":\"client 3 not exist\",\"id\":3}\n"
+        );
+    }
+
+    #[test]
+    fn multi_currency_export_test() {
+        let input = "\
+type,client,tx,amount,currency
+deposit,2,1,10,USD
+deposit,1,2,1.5,
+deposit,2,3,4,EUR
+dispute,2,3,1,
+";
+        let mut processor = CSVProcessor::default();
+        let mut err_output = Vec::new();
+        processor.process(input.as_bytes(), &mut err_output);
+        assert!(err_output.is_empty());
+
+        let mut output = Vec::new();
+        processor
+            .export_clients(&mut output)
+            .expect("exporting clients should not fail");
+        assert_eq!(
+            String::from_utf8(output).expect("output should be utf8 characters"),
+            "\
+client,available,held,total,locked,status,currency,credit_limit,overdrawn,remaining_credit
+1,1.5,0,1.5,false,active,,0,0,0
+2,3,1,4,false,active,EUR,0,0,0
+2,10,0,10,false,active,USD,0,0,0
+",
+            "there should be one row per client and currency"
+        );
+    }
+
+    #[test]
+    fn credit_limit_export_test() {
+        let input = "\
+type,client,tx,amount
+limit,1,1,50
+deposit,1,2,10
+withdrawal,1,3,40
+deposit,2,4,5
+dispute,2,4,
+withdrawal,1,5,25
+limit,3,6,100
+";
+        let mut processor = CSVProcessor::default();
+        let mut err_output = Vec::new();
+        processor.process(input.as_bytes(), &mut err_output);
+        assert_eq!(
+            String::from_utf8(err_output).expect("error logs should be utf8 characters"),
+            "error: client 1 cannot withdrawl 25 as available amount is -30\n",
+            "withdrawals should stop at the credit limit"
+        );
+
+        let mut output = Vec::new();
+        processor
+            .export_clients(&mut output)
+            .expect("exporting clients should not fail");
+        assert_eq!(
+            String::from_utf8(output).expect("output should be utf8 characters"),
+            "\
+client,available,held,total,locked,status,currency,credit_limit,overdrawn,remaining_credit
+1,-30,0,-30,false,active,,50,30,20
+2,0,5,5,false,active,,0,0,0
+3,0,0,0,false,active,,100,0,100
+"
+        );
+    }
+
+    #[test]
+    fn snapshot_integration_test() {
+        let day1 = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,5.50
+dispute,2,2,
+withdrawal,1,3,2
+";
+        let day2 = "\
+type,client,tx,amount
+resolve,2,2,
+deposit,1,1,10
+withdrawal,2,4,1
+deposit,3,5,7
+";
+        let export = |processor: &CSVProcessor| {
+            let mut output = Vec::new();
+            processor
+                .export_clients(&mut output)
+                .expect("exporting clients should not fail");
+            String::from_utf8(output).expect("output should be utf8 characters")
+        };
+
+        let mut yesterday = CSVProcessor::default();
+        yesterday.process(day1.as_bytes(), io::sink());
+        let mut snapshot = Vec::new();
+        yesterday
+            .engine()
+            .write_snapshot(&mut snapshot)
+            .expect("writing the snapshot should not fail");
+
+        let mut engine = Engine::default();
+        engine
+            .restore_snapshot(&snapshot[..])
+            .expect("restoring the snapshot should not fail");
+        assert_eq!(
+            engine.restore_snapshot(&snapshot[..]),
+            Err(Error::EngineNotEmpty)
+        );
+        let mut today = CSVProcessor::new(engine);
+        assert_eq!(export(&today), export(&yesterday));
+        let mut errors = Vec::new();
+        today.process(day2.as_bytes(), &mut errors);
+
+        let mut everything = CSVProcessor::default();
+        everything.process(day1.as_bytes(), io::sink());
+        let mut expected_errors = Vec::new();
+        everything.process(day2.as_bytes(), &mut expected_errors);
+
+        assert_eq!(
+            export(&today),
+            export(&everything),
+            "starting from a snapshot should match reprocessing every file"
+        );
+        assert_eq!(
+            String::from_utf8(errors),
+            String::from_utf8(expected_errors),
+            "transaction ids from before the snapshot should still be known"
+        );
+    }
+
+    #[test]
+    fn parallel_integration_test() {
+        let input = "\
+type,client,tx,amount,to
+deposit,1,1,10,
+deposit,2,2,20,
+deposit,3,3,30,
+deposit,4,1,5,
+dispute,3,2,,
+dispute,2,2,,
+chargeback,2,2,,
+deposit,2,8,1,
+transfer,1,4,5,3
+withdrawal,3,5,40,
+withdrawal,3,6,34,
+dispute,1,4,,
+deposit,4,7,1,
+resolve,1,4,,
+";
+        let run = |processor: &mut CSVProcessor, parallel: bool| {
+            let mut errors = Vec::new();
+            if parallel {
+                processor
+                    .process_parallel(
+                        input.as_bytes(),
+                        &mut errors,
+                        NonZeroUsize::new(3).expect("3 is not zero"),
+                    )
+                    .expect("the default config should run in parallel");
+            } else {
+                processor.process(input.as_bytes(), &mut errors);
+            }
+            let mut output = Vec::new();
+            processor
+                .export_clients(&mut output)
+                .expect("exporting clients should not fail");
+            (
+                String::from_utf8(output).expect("output should be utf8 characters"),
+                String::from_utf8(errors).expect("error logs should be utf8 characters"),
+            )
+        };
+
+        let sequential = run(&mut CSVProcessor::default(), false);
+        let mut processor = CSVProcessor::default();
+        assert_eq!(run(&mut processor, true), sequential);
+        assert_eq!(
+            sequential.1,
+            "\
+error: transaction 1 already exist
+error: transaction 2 is not for client 3
+error: client 2 is locked
+error: client 3 cannot withdrawl 40 as available amount is 35
+error: client 3 cannot dispute 5 as available amount is 1
+error: transaction 4 cannot be resolved as it is not in dispute
+"
+        );
+        assert_eq!(
+            processor.engine().record_counts(),
+            (14, 8),
+            "the record counts should add up over the workers"
+        );
+
+        let mut journaled = CSVProcessor::new(
+            Engine::default()
+                .with_config(Config {
+                    journal: JournalMode::OnDemand,
+                    ..Config::default()
+                })
+                .expect("config should be valid"),
+        );
+        assert_eq!(
+            journaled.process_parallel(
+                input.as_bytes(),
+                io::sink(),
+                NonZeroUsize::new(2).expect("2 is not zero")
+            ),
+            Err(Error::ParallelUnsupported("the journal".to_string()))
+        );
+    }
+
+    #[test]
+    fn output_policy_export_test() {
+        let file = File::open("resources/test/test1.csv").expect("Unable to open file");
+        let mut processor = CSVProcessor::default().with_output_policy(OutputPolicy {
+            scale: Some(2),
+            rounding: Rounding::HalfUp,
+            pad: true,
+        });
+        processor.process(BufReader::new(file), io::sink());
+
+        let mut output = Vec::new();
+        processor
+            .export_clients(&mut output)
+            .expect("exporting clients should not fail");
+        assert_eq!(
+            String::from_utf8(output).expect("output should be utf8 characters"),
+            "\
+client,available,held,total,locked,status,currency,credit_limit,overdrawn,remaining_credit
+1,0.50,0.00,0.50,true,locked,,0.00,0.00,0.00
+2,9.99,2.00,11.99,false,active,,0.00,0.00,0.00
+",
+            "every amount should have the same scale"
+        );
+        assert_eq!(
+            processor
+                .engine()
+                .get_clients()
+                .expect("clients should be readable")[1]
+                .1
+                .balance("")
+                .available_amount,
+            dec!(9.9874),
+            "balances should stay exact"
+        );
+    }
 }
@@ -3,23 +3,365 @@
 
 use std::{
     env,
-    fs::File,
-    io::{self, BufReader},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read},
+    num::NonZeroUsize,
+    str::FromStr,
 };
-use transaction_action::CSVProcessor;
+use transaction_action::{
+    stores::{ClientStore, TransactionStore},
+    CSVProcessor, ClientID, Config, Engine, Error, ErrorFormat, Fee, FeeRefundPolicy, FeeSchedule,
+    Fees, JournalMode, NDJSONProcessor, OutputPolicy, Rounding, Rules, Server,
+};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8080";
+
+#[derive(Default)]
+struct Options {
+    /// Serve the HTTP API instead of processing a file, `path` is then the address to listen on
+    serve: bool,
+    path: Option<String>,
+    config: Config,
+    as_of: Option<u64>,
+    rejects: Option<String>,
+    ndjson: bool,
+    error_format: ErrorFormat,
+    output_policy: OutputPolicy,
+    from_snapshot: Option<String>,
+    save_snapshot: Option<String>,
+    workers: Option<NonZeroUsize>,
+    #[cfg(feature = "sqlite")]
+    database: Option<String>,
+}
+
+// `0.5` is a flat fee, `1.5%` a percentage of the transaction amount
+fn parse_fee(arg: Option<String>) -> Option<Fee> {
+    let arg = arg?;
+    let fee = match arg.strip_suffix('%') {
+        Some(rate) => rate.parse().ok().map(Fee::Percentage),
+        None => arg.parse().ok().map(Fee::Flat),
+    };
+    fee?.validate().ok()
+}
+
+fn parse_rounding(arg: Option<String>) -> Option<Rounding> {
+    match arg?.as_str() {
+        "bankers" => Some(Rounding::Bankers),
+        "half-up" => Some(Rounding::HalfUp),
+        "truncate" => Some(Rounding::Truncate),
+        _ => None,
+    }
+}
+
+fn next_value<T: FromStr>(args: &mut impl Iterator<Item = String>, message: &str) -> T {
+    args.next().and_then(|s| s.parse().ok()).expect(message)
+}
+
+// Flags bounding record amounts and how long holds and transactions are kept, returns false if `arg` is not one of them
+fn parse_limit_option(
+    arg: &str,
+    args: &mut impl Iterator<Item = String>,
+    config: &mut Config,
+) -> bool {
+    match arg {
+        "--hold-expiry" => {
+            config.hold_expiry = Some(next_value(
+                args,
+                "--hold-expiry should be followed by a number of records",
+            ));
+        }
+        "--dispute-window" => {
+            config.dispute_window = Some(next_value(
+                args,
+                "--dispute-window should be followed by a number of records",
+            ));
+        }
+        "--max-scale" => {
+            config.amount_limits.max_scale = next_value(
+                args,
+                "--max-scale should be followed by a number of decimal places",
+            );
+        }
+        "--max-amount" => {
+            config.amount_limits.max_amount = Some(next_value(
+                args,
+                "--max-amount should be followed by an amount",
+            ));
+        }
+        _ => return false,
+    }
+    true
+}
+
+// Flags of the exported amounts, returns false if `arg` is not one of them
+fn parse_output_option(
+    arg: &str,
+    args: &mut impl Iterator<Item = String>,
+    policy: &mut OutputPolicy,
+) -> bool {
+    match arg {
+        "--scale" => {
+            policy.scale = Some(next_value(
+                args,
+                "--scale should be followed by a number of decimal places",
+            ));
+        }
+        "--rounding" => {
+            policy.rounding = parse_rounding(args.next())
+                .expect("--rounding should be followed by bankers, half-up or truncate");
+        }
+        "--pad" => policy.pad = true,
+        _ => return false,
+    }
+    true
+}
+
+fn parse_options() -> Options {
+    let mut options = Options::default();
+    let mut house: Option<ClientID> = None;
+    let mut fees = Fees::default();
+    let mut refund_policy = FeeRefundPolicy::default();
+
+    let mut args = env::args().skip(1).peekable();
+    options.serve = args.next_if(|arg| arg == "serve").is_some();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--as-of" => {
+                let sequence =
+                    next_value(&mut args, "--as-of should be followed by a record number");
+                options.as_of = Some(sequence);
+                options.config.event_log = true;
+            }
+            "--rejects" => {
+                options.rejects =
+                    Some(args.next().expect("--rejects should be followed by a path"));
+            }
+            "--ndjson" => options.ndjson = true,
+            "--json-errors" => options.error_format = ErrorFormat::Json,
+            "--journal" => options.config.journal = JournalMode::OnDemand,
+            "--idempotent" => options.config.idempotent = true,
+            "--house" => {
+                house = Some(next_value(
+                    &mut args,
+                    "--house should be followed by a client id",
+                ));
+            }
+            "--deposit-fee" => {
+                fees.deposit = Some(
+                    parse_fee(args.next())
+                        .expect("--deposit-fee should be followed by a non-negative amount or a percentage from 0% to 100%"),
+                );
+            }
+            "--withdrawal-fee" => {
+                fees.withdrawal = Some(
+                    parse_fee(args.next())
+                        .expect("--withdrawal-fee should be followed by a non-negative amount or a percentage from 0% to 100%"),
+                );
+            }
+            "--refund-fees" => refund_policy = FeeRefundPolicy::Refund,
+            "--from-snapshot" => {
+                options.from_snapshot = Some(next_value(
+                    &mut args,
+                    "--from-snapshot should be followed by a path",
+                ));
+            }
+            "--save-snapshot" => {
+                options.save_snapshot = Some(next_value(
+                    &mut args,
+                    "--save-snapshot should be followed by a path",
+                ));
+            }
+            "--workers" => {
+                options.workers = Some(next_value(
+                    &mut args,
+                    "--workers should be followed by a number of threads",
+                ));
+            }
+            "--rules" => {
+                let path = args.next().expect("--rules should be followed by a path");
+                let config = fs::read_to_string(path).expect("Unable to read rules file");
+                options.config.rules = Rules::from_toml(&config).expect("Invalid rules file");
+            }
+            #[cfg(feature = "sqlite")]
+            "--db" => {
+                options.database = Some(args.next().expect("--db should be followed by a path"));
+            }
+            _ if parse_limit_option(&arg, &mut args, &mut options.config) => {}
+            _ if parse_output_option(&arg, &mut args, &mut options.output_policy) => {}
+            _ => {
+                assert!(!arg.starts_with('-'), "Unknown option {arg}");
+                assert!(
+                    options.path.is_none(),
+                    "Only one path (or address in serve mode) should be given"
+                );
+                options.path = Some(arg);
+            }
+        }
+    }
+
+    if fees != Fees::default() {
+        let schedule = FeeSchedule {
+            default: fees,
+            refund_policy,
+            ..FeeSchedule::new(house.expect("--house should be set to charge fees"))
+        };
+        schedule.validate().expect("Invalid fee schedule");
+        options.config.fees = Some(schedule);
+    }
+
+    options
+}
 
 fn main() {
-    let path = env::args()
-        .nth(1)
-        .expect("First argument should be path to transactions csv file");
+    let options = parse_options();
+
+    if options.serve {
+        assert!(
+            options.rejects.is_none()
+                && options.workers.is_none()
+                && options.as_of.is_none()
+                && options.save_snapshot.is_none(),
+            "--rejects, --workers, --as-of and --save-snapshot are not supported in serve mode"
+        );
+    }
+    assert!(
+        !(options.ndjson && options.rejects.is_some()),
+        "--rejects is only supported for csv input"
+    );
+    assert!(
+        options.workers.is_none() || !options.ndjson && options.rejects.is_none(),
+        "--workers is only supported for csv input without --rejects"
+    );
+
+    #[cfg(feature = "sqlite")]
+    if let Some(database) = &options.database {
+        assert!(
+            options.workers.is_none(),
+            "--workers is only supported with the in-memory stores"
+        );
+        let (clients, transactions) = transaction_action::stores::sqlite::open(database)
+            .expect("Unable to open sqlite database");
+        let engine =
+            Engine::new(clients, transactions).expect("Unable to load the sqlite database");
+        start(engine, &options, process_csv);
+        return;
+    }
+
+    if options.workers.is_some() {
+        start(Engine::default(), &options, process_csv_parallel);
+    } else {
+        start(Engine::default(), &options, process_csv);
+    }
+}
+
+fn start<C: ClientStore + Send, T: TransactionStore + Send>(
+    engine: Engine<C, T>,
+    options: &Options,
+    process_csv: fn(&mut CSVProcessor<C, T>, BufReader<File>, &Options),
+) {
+    let mut engine = engine
+        .with_config(options.config.clone())
+        .unwrap_or_else(|error| panic!("Invalid config: {error}"));
+    if let Some(path) = &options.from_snapshot {
+        let file = File::open(path).expect("Unable to open snapshot file");
+        engine
+            .restore_snapshot(BufReader::new(file))
+            .unwrap_or_else(|error| panic!("Unable to restore snapshot: {error}"));
+    }
 
+    if options.serve {
+        serve(engine, options);
+        return;
+    }
+    let path = options
+        .path
+        .as_ref()
+        .expect("First argument should be path to transactions csv file");
     let file = File::open(path).expect("Unable to open file");
-    let reader = BufReader::new(file);
+    run(engine, BufReader::new(file), options, process_csv);
+}
 
-    let mut processor = CSVProcessor::default();
+fn serve<C: ClientStore + Send, T: TransactionStore + Send>(
+    engine: Engine<C, T>,
+    options: &Options,
+) {
+    let address = options.path.as_deref().unwrap_or(DEFAULT_ADDRESS);
+    let server = Server::bind(engine, address)
+        .unwrap_or_else(|error| panic!("Unable to start server: {error}"))
+        .with_output_policy(options.output_policy);
+    if let Ok(address) = server.local_addr() {
+        eprintln!("listening on http://{address}");
+    }
+    server.serve();
+}
 
-    processor.process(reader, io::stderr());
-    if let Err(error) = processor.export_clients(io::stdout()) {
-        eprintln!("{}", error);
+fn process_csv<C: ClientStore, T: TransactionStore, R: Read>(
+    processor: &mut CSVProcessor<C, T>,
+    reader: R,
+    options: &Options,
+) {
+    match &options.rejects {
+        Some(path) => {
+            let rejects = File::create(path).expect("Unable to create rejects file");
+            processor.process_with_rejects(reader, io::stderr(), BufWriter::new(rejects));
+        }
+        None => processor.process(reader, io::stderr()),
     }
 }
+
+fn process_csv_parallel<R: Read>(processor: &mut CSVProcessor, reader: R, options: &Options) {
+    let workers = options.workers.expect("--workers should be set");
+    processor
+        .process_parallel(reader, io::stderr(), workers)
+        .unwrap_or_else(|error| panic!("Unable to process in parallel: {error}"));
+}
+
+fn run<C: ClientStore, T: TransactionStore, R: Read>(
+    engine: Engine<C, T>,
+    reader: R,
+    options: &Options,
+    process_csv: fn(&mut CSVProcessor<C, T>, R, &Options),
+) {
+    let exported = if options.ndjson {
+        let mut processor = NDJSONProcessor::new(engine)
+            .with_error_format(options.error_format)
+            .with_output_policy(options.output_policy);
+        processor.process(BufReader::new(reader), io::stderr());
+        match options.as_of {
+            Some(sequence) => processor.export_clients_at(sequence, io::stdout()),
+            None => processor.export_clients(io::stdout()),
+        }
+        .and_then(|()| save_snapshot(processor.engine(), options))
+    } else {
+        let mut processor = CSVProcessor::new(engine)
+            .with_error_format(options.error_format)
+            .with_output_policy(options.output_policy);
+        process_csv(&mut processor, reader, options);
+        match options.as_of {
+            Some(sequence) => processor.export_clients_at(sequence, io::stdout()),
+            None => processor.export_clients(io::stdout()),
+        }
+        .and_then(|()| save_snapshot(processor.engine(), options))
+    };
+
+    if let Err(error) = exported {
+        match options.error_format {
+            ErrorFormat::Text => eprintln!("{error}"),
+            ErrorFormat::Json => eprintln!(
+                "{}",
+                serde_json::to_string(&error).unwrap_or_else(|_| error.to_string())
+            ),
+        }
+    }
+}
+
+fn save_snapshot<C: ClientStore, T: TransactionStore>(
+    engine: &Engine<C, T>,
+    options: &Options,
+) -> Result<(), Error> {
+    let Some(path) = &options.save_snapshot else {
+        return Ok(());
+    };
+    let file = File::create(path).map_err(|e| Error::SnapshotWriteFailure(e.to_string()))?;
+    engine.write_snapshot(BufWriter::new(file))
+}
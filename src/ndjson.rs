@@ -0,0 +1,215 @@
+use crate::{
+    dtos::{TaggedTransactionRecord, TransactionRecord},
+    report,
+    stores::{
+        clients::{Balance, Client, Clients, Status},
+        transactions::Transactions,
+        ClientStore, TransactionStore,
+    },
+    ClientID, Engine, Error, ErrorFormat, OutputPolicy,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::{BufRead, Write};
+
+/// NDJSON (newline delimited json) front end to the `Engine`, the json counterpart of `CSVProcessor`.
+///
+/// Each input line is a json object tagged by its `type`, with the same field names as the csv columns:
+/// `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`. Amounts can be json strings or numbers, numbers are
+/// read exactly as written.
+pub struct NDJSONProcessor<C = Clients, T = Transactions> {
+    engine: Engine<C, T>,
+    error_format: ErrorFormat,
+    output_policy: OutputPolicy,
+}
+
+impl Default for NDJSONProcessor {
+    fn default() -> Self {
+        Self::new(Engine::default())
+    }
+}
+
+// Json representation of a client balance
+#[derive(Serialize)]
+pub(crate) struct ClientBalance {
+    client: ClientID,
+    currency: String,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    status: &'static str,
+    credit_limit: Decimal,
+    overdrawn: Decimal,
+    remaining_credit: Decimal,
+}
+
+impl<C: ClientStore, T: TransactionStore> NDJSONProcessor<C, T> {
+    /// Creates a processor that feeds the given engine
+    pub fn new(engine: Engine<C, T>) -> Self {
+        Self {
+            engine,
+            error_format: ErrorFormat::default(),
+            output_policy: OutputPolicy::default(),
+        }
+    }
+
+    /// Sets how errors are written to the error output
+    #[must_use]
+    pub fn with_error_format(mut self, error_format: ErrorFormat) -> Self {
+        self.error_format = error_format;
+        self
+    }
+
+    /// Sets how amounts are rounded in the export
+    #[must_use]
+    pub fn with_output_policy(mut self, output_policy: OutputPolicy) -> Self {
+        self.output_policy = output_policy;
+        self
+    }
+
+    /// Deserializes each line of the reader as a json record and processes it. Blank lines are skipped.
+    pub fn process(&mut self, ndjson_input: impl BufRead, mut err_output: impl Write) {
+        for (index, line) in ndjson_input.lines().enumerate() {
+            let line_number = index + 1;
+            let record = line
+                .map_err(|e| Error::JSONRowReadFailure(format!("line {line_number}: {e}")))
+                .and_then(|line| {
+                    if line.trim().is_empty() {
+                        return Ok(None);
+                    }
+                    serde_json::from_str::<TaggedTransactionRecord>(&line)
+                        .map(Some)
+                        .map_err(|e| Error::JSONRowReadFailure(format!("line {line_number}: {e}")))
+                });
+            let result = match record {
+                Ok(Some(record)) => self.engine.handle(&TransactionRecord::from(record)),
+                Ok(None) => Ok(()),
+                Err(error) => {
+                    self.engine.skip_record();
+                    Err(error)
+                }
+            };
+
+            if let Err(error) = result {
+                report(&mut err_output, self.error_format, &error);
+            }
+        }
+    }
+
+    /// Serializes the client balances as one json object per line and currency, ordered by client id then currency.
+    /// When the journal is enabled, the trial balance is checked first and nothing is written if it fails.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the trial balance fails, or if the balances cannot be written to the passed in writer
+    pub fn export_clients(&self, writer: impl Write) -> Result<(), Error> {
+        write_clients(self.engine.get_clients()?, self.output_policy, writer)
+    }
+
+    /// Serializes the client balances as they were right after the record with the given sequence number was
+    /// processed, see `CSVProcessor::export_clients_at`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the event log is not enabled, or if the balances cannot be written to the passed in writer
+    pub fn export_clients_at(&self, sequence: u64, writer: impl Write) -> Result<(), Error> {
+        write_clients(
+            self.engine.clients_at(sequence)?,
+            self.output_policy,
+            writer,
+        )
+    }
+
+    /// The engine records are fed to
+    pub fn engine(&self) -> &Engine<C, T> {
+        &self.engine
+    }
+}
+
+impl ClientBalance {
+    pub(crate) fn new(
+        client_id: ClientID,
+        client: &Client,
+        currency: &str,
+        balance: &Balance,
+        output_policy: OutputPolicy,
+    ) -> Self {
+        let amount = |amount| output_policy.apply(amount);
+        Self {
+            client: client_id,
+            currency: currency.to_string(),
+            available: amount(balance.available_amount),
+            held: amount(balance.held_amount),
+            total: output_policy.total(balance.available_amount, balance.held_amount),
+            locked: client.status != Status::Active,
+            status: client.status.as_str(),
+            credit_limit: amount(client.credit_limit),
+            overdrawn: amount(balance.overdrawn()),
+            remaining_credit: amount(client.remaining_credit()),
+        }
+    }
+}
+
+fn write_clients(
+    clients: Vec<(ClientID, Client)>,
+    output_policy: OutputPolicy,
+    mut writer: impl Write,
+) -> Result<(), Error> {
+    for (client_id, client) in clients {
+        for (currency, balance) in client.exported_balances() {
+            let row = ClientBalance::new(client_id, &client, currency, &balance, output_policy);
+            serde_json::to_writer(&mut writer, &row)
+                .map_err(|e| Error::JSONRowWriteFailure(e.to_string()))?;
+            writeln!(writer).map_err(|e| Error::JSONRowWriteFailure(e.to_string()))?;
+        }
+    }
+
+    writer
+        .flush()
+        .map_err(|e| Error::JSONRowWriteFailure(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5000}
+{"type": "deposit", "client": 2, "tx": 2, "amount": "2"}
+
+{"type": "withdrawal", "client": 2, "tx": 3, "amount": 0.25}
+{"type": "dispute", "client": 1, "tx": 1, "amount": 0.5}
+{"type": "deposit", "client": 1, "tx": 4}
+{"type": "freeze", "client": 2, "tx": 5, "reason": "kyc", "operator": "ops"}
+{"type": "unknown", "client": 2, "tx": 6}
+{"type": "deposit", "client": 1, "tx": 1, "amount": 1}
+"#;
+        let mut processor = NDJSONProcessor::default();
+        let mut err_output = Vec::new();
+        processor.process(input.as_bytes(), &mut err_output);
+
+        let errors = String::from_utf8(err_output).expect("error logs should be utf8 characters");
+        assert_eq!(
+            errors.lines().collect::<Vec<_>>(),
+            vec![
+                "error: json row parsing failure: line 6: missing field `amount`",
+                "error: json row parsing failure: line 8: unknown variant `unknown`, expected one of `deposit`, `withdrawal`, `transfer`, `dispute`, `resolve`, `chargeback`, `authorize`, `capture`, `void`, `limit`, `unlock`, `freeze`, `close` at line 1 column 18",
+                "error: transaction 1 already exist",
+            ],
+            "each type should require its own fields"
+        );
+
+        let mut output = Vec::new();
+        processor
+            .export_clients(&mut output)
+            .expect("exporting clients should not fail");
+        assert_eq!(
+            String::from_utf8(output).expect("output should be utf8 characters"),
+            r#"{"client":1,"currency":"","available":"1.0000","held":"0.5","total":"1.5000","locked":false,"status":"active","credit_limit":"0","overdrawn":"0","remaining_credit":"0"}
+{"client":2,"currency":"","available":"1.75","held":"0","total":"1.75","locked":true,"status":"frozen","credit_limit":"0","overdrawn":"0","remaining_credit":"0"}
+"#
+        );
+    }
+}
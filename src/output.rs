@@ -0,0 +1,124 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+
+/// How exported amounts are rounded to the output scale
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Rounding {
+    /// Half way amounts round to the nearest even digit
+    #[default]
+    Bankers,
+    /// Half way amounts round away from zero
+    HalfUp,
+    /// Extra digits are dropped
+    Truncate,
+}
+
+impl Rounding {
+    fn strategy(self) -> RoundingStrategy {
+        match self {
+            Rounding::Bankers => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+/// How amounts are written by the exports. Only the written amounts are rounded, balances in the stores stay exact.
+///
+/// The default policy writes amounts exactly as they are stored, so their scale follows the inputs.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct OutputPolicy {
+    /// Number of decimal places amounts are rounded to, unrounded if unset
+    pub scale: Option<u32>,
+    pub rounding: Rounding,
+    /// Pad amounts with trailing zeros up to `scale`, so every amount has exactly `scale` decimal places. Without it,
+    /// trailing zeros are dropped.
+    pub pad: bool,
+}
+
+impl OutputPolicy {
+    /// Applies the policy to an amount about to be written
+    #[must_use]
+    pub fn apply(&self, amount: Decimal) -> Decimal {
+        let Some(scale) = self.scale else {
+            return amount;
+        };
+
+        let mut rounded = amount.round_dp_with_strategy(scale, self.rounding.strategy());
+        if self.pad {
+            rounded.rescale(scale);
+        } else {
+            rounded = rounded.normalize();
+        }
+        // Amounts that round to zero are written without a sign
+        if rounded.is_zero() {
+            rounded.set_sign_positive(true);
+        }
+        rounded
+    }
+
+    /// Applies the policy to the total of two amounts. The total is added up from the rounded amounts, so it always
+    /// matches the amounts written next to it.
+    #[must_use]
+    pub fn total(&self, first: Decimal, second: Decimal) -> Decimal {
+        self.apply(self.apply(first) + self.apply(second))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_apply() {
+        let exact = OutputPolicy::default();
+        assert_eq!(exact.apply(dec!(2.0010)).to_string(), "2.0010");
+
+        let mut policy = OutputPolicy {
+            scale: Some(2),
+            ..OutputPolicy::default()
+        };
+        assert_eq!(policy.apply(dec!(2.125)).to_string(), "2.12");
+        assert_eq!(policy.apply(dec!(2.135)).to_string(), "2.14");
+        assert_eq!(policy.apply(dec!(0.5)).to_string(), "0.5");
+        assert_eq!(
+            policy.apply(dec!(-0.001)).to_string(),
+            "0",
+            "amounts rounding to zero should not keep their sign"
+        );
+
+        policy.rounding = Rounding::HalfUp;
+        assert_eq!(policy.apply(dec!(2.125)).to_string(), "2.13");
+        assert_eq!(policy.apply(dec!(-2.125)).to_string(), "-2.13");
+
+        policy.rounding = Rounding::Truncate;
+        assert_eq!(policy.apply(dec!(2.129)).to_string(), "2.12");
+        assert_eq!(policy.apply(dec!(-2.129)).to_string(), "-2.12");
+
+        policy.pad = true;
+        assert_eq!(policy.apply(dec!(0.5)).to_string(), "0.50");
+        assert_eq!(policy.apply(dec!(3)).to_string(), "3.00");
+        assert_eq!(policy.apply(dec!(-0.001)).to_string(), "0.00");
+    }
+
+    #[test]
+    fn test_total() {
+        let exact = OutputPolicy::default();
+        assert_eq!(exact.total(dec!(1.005), dec!(2.005)).to_string(), "3.010");
+
+        let policy = OutputPolicy {
+            scale: Some(2),
+            rounding: Rounding::HalfUp,
+            ..OutputPolicy::default()
+        };
+        // Rounded from the exact balances, the totals would be 3.01 both times
+        assert_eq!(policy.total(dec!(1.005), dec!(2.005)).to_string(), "3.02");
+        assert_eq!(
+            policy.total(dec!(1.004), dec!(2.004)).to_string(),
+            "3",
+            "the total should be the sum of the rounded amounts"
+        );
+        assert_eq!(policy.total(dec!(0.004), dec!(-0.004)).to_string(), "0");
+    }
+}
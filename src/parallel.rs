@@ -0,0 +1,703 @@
+use crate::{
+    dtos::{TransactionRecord, TransactionType},
+    engine::{Config, Engine},
+    errors::Error,
+    rules::Check,
+    stores::{
+        clients::{Client, Clients, StatusChange},
+        transactions::{Transaction, Transactions},
+        ClientStore, TransactionStore,
+    },
+    ClientID, TransactionID,
+};
+use std::{
+    collections::HashMap,
+    mem,
+    num::NonZeroUsize,
+    panic,
+    sync::{mpsc, Mutex, MutexGuard},
+    thread,
+};
+
+// Records a worker can have queued before the reader waits for it
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Clients of a shard, along with the transactions they own
+#[derive(Default)]
+struct Shard {
+    clients: Clients,
+    transactions: Transactions,
+}
+
+fn shard_of(client_id: ClientID, shards: usize) -> usize {
+    usize::from(client_id) % shards
+}
+
+fn lock(shard: &Mutex<Shard>) -> Result<MutexGuard<'_, Shard>, Error> {
+    shard.lock().map_err(|e| Error::StoreFailure(e.to_string()))
+}
+
+// Shards written since `begin`. A shard only starts a store transaction once it is written to, so engines working on
+// different shards never touch each other's savepoints.
+#[derive(Default)]
+struct Touched {
+    open: bool,
+    shards: Vec<usize>,
+}
+
+impl Touched {
+    // Returns true if the shard has to start a store transaction before being written to
+    fn needs_begin(&mut self, shard: usize) -> bool {
+        if !self.open || self.shards.contains(&shard) {
+            return false;
+        }
+        self.shards.push(shard);
+        true
+    }
+
+    fn begin(&mut self) {
+        self.open = true;
+        self.shards.clear();
+    }
+
+    fn end(&mut self) -> Vec<usize> {
+        self.open = false;
+        mem::take(&mut self.shards)
+    }
+}
+
+/// Client store of an engine of a parallel run, each client living in the shard of its id
+struct ShardedClients<'a> {
+    shards: &'a [Mutex<Shard>],
+    touched: Touched,
+}
+
+impl<'a> ShardedClients<'a> {
+    fn new(shards: &'a [Mutex<Shard>]) -> Self {
+        Self {
+            shards,
+            touched: Touched::default(),
+        }
+    }
+
+    fn read(&self, id: ClientID) -> Result<MutexGuard<'a, Shard>, Error> {
+        lock(&self.shards[shard_of(id, self.shards.len())])
+    }
+
+    fn write(&mut self, id: ClientID) -> Result<MutexGuard<'a, Shard>, Error> {
+        let index = shard_of(id, self.shards.len());
+        let mut shard = lock(&self.shards[index])?;
+        if self.touched.needs_begin(index) {
+            shard.clients.begin()?;
+        }
+        Ok(shard)
+    }
+}
+
+impl ClientStore for ShardedClients<'_> {
+    fn find_client(&self, id: ClientID) -> Result<Option<Client>, Error> {
+        self.read(id)?.clients.find_client(id)
+    }
+
+    fn upsert_client(&mut self, id: ClientID, client: Client) -> Result<(), Error> {
+        self.write(id)?.clients.upsert_client(id, client)
+    }
+
+    fn get_all(&self) -> Result<Vec<(ClientID, Client)>, Error> {
+        let mut clients = Vec::new();
+        for shard in self.shards {
+            clients.extend(lock(shard)?.clients.get_all()?);
+        }
+        clients.sort_unstable_by_key(|(id, _)| *id);
+        Ok(clients)
+    }
+
+    fn save_status_change(&mut self, id: ClientID, change: StatusChange) -> Result<(), Error> {
+        self.write(id)?.clients.save_status_change(id, change)
+    }
+
+    fn get_status_changes(&self, id: ClientID) -> Result<Vec<StatusChange>, Error> {
+        self.read(id)?.clients.get_status_changes(id)
+    }
+
+    fn begin(&mut self) -> Result<(), Error> {
+        self.touched.begin();
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        for index in self.touched.end() {
+            lock(&self.shards[index])?.clients.commit()?;
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), Error> {
+        for index in self.touched.end() {
+            lock(&self.shards[index])?.clients.rollback()?;
+        }
+        Ok(())
+    }
+}
+
+/// Transaction store of an engine of a parallel run, each transaction living in the shard of the client it belongs
+/// to. Lookups by id start with the home shard of the engine, where the transactions it works on are.
+struct ShardedTransactions<'a> {
+    shards: &'a [Mutex<Shard>],
+    home: usize,
+    touched: Touched,
+}
+
+impl<'a> ShardedTransactions<'a> {
+    fn new(shards: &'a [Mutex<Shard>], home: usize) -> Self {
+        Self {
+            shards,
+            home,
+            touched: Touched::default(),
+        }
+    }
+
+    // Shard indexes, home shard first
+    fn search_order(&self) -> impl Iterator<Item = usize> {
+        let (home, count) = (self.home, self.shards.len());
+        (0..count).map(move |offset| (home + offset) % count)
+    }
+
+    fn write(&mut self, index: usize) -> Result<MutexGuard<'a, Shard>, Error> {
+        let mut shard = lock(&self.shards[index])?;
+        if self.touched.needs_begin(index) {
+            shard.transactions.begin()?;
+        }
+        Ok(shard)
+    }
+}
+
+impl TransactionStore for ShardedTransactions<'_> {
+    fn find_transaction(
+        &self,
+        transaction_id: TransactionID,
+    ) -> Result<Option<Transaction>, Error> {
+        for index in self.search_order() {
+            let found = lock(&self.shards[index])?
+                .transactions
+                .find_transaction(transaction_id)?;
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+
+    fn upsert_transaction(
+        &mut self,
+        transaction_id: TransactionID,
+        transaction: Transaction,
+    ) -> Result<(), Error> {
+        let index = shard_of(transaction.client_id, self.shards.len());
+        self.write(index)?
+            .transactions
+            .upsert_transaction(transaction_id, transaction)
+    }
+
+    fn get_client_transactions(&self, client_id: ClientID) -> Result<Vec<Transaction>, Error> {
+        lock(&self.shards[shard_of(client_id, self.shards.len())])?
+            .transactions
+            .get_client_transactions(client_id)
+    }
+
+    fn get_all_transactions(&self) -> Result<Vec<(TransactionID, Transaction)>, Error> {
+        let mut transactions = Vec::new();
+        for shard in self.shards {
+            transactions.extend(lock(shard)?.transactions.get_all_transactions()?);
+        }
+        transactions.sort_unstable_by_key(|(transaction_id, _)| *transaction_id);
+        Ok(transactions)
+    }
+
+    fn evict_transaction(&mut self, transaction_id: TransactionID) -> Result<(), Error> {
+        let index = match self.find_transaction(transaction_id)? {
+            Some(transaction) => shard_of(transaction.client_id, self.shards.len()),
+            None => self.home,
+        };
+        self.write(index)?
+            .transactions
+            .evict_transaction(transaction_id)
+    }
+
+    fn get_evicted_ids(&self) -> Result<Vec<TransactionID>, Error> {
+        let mut ids = Vec::new();
+        for shard in self.shards {
+            ids.extend(lock(shard)?.transactions.get_evicted_ids()?);
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn is_evicted(&self, transaction_id: TransactionID) -> Result<bool, Error> {
+        for shard in self.shards {
+            if lock(shard)?.transactions.is_evicted(transaction_id)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn begin(&mut self) -> Result<(), Error> {
+        self.touched.begin();
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        for index in self.touched.end() {
+            lock(&self.shards[index])?.transactions.commit()?;
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), Error> {
+        for index in self.touched.end() {
+            lock(&self.shards[index])?.transactions.rollback()?;
+        }
+        Ok(())
+    }
+}
+
+/// Rejects the configurations whose outcome depends on the global order of records, which shards do not share
+fn check_config(config: &Config) -> Result<(), Error> {
+    let unsupported = if config.event_log {
+        "the event log"
+    } else if config.journal != crate::JournalMode::Disabled {
+        "the journal"
+    } else if config.hold_expiry.is_some() {
+        "hold expiry"
+    } else if config.dispute_window.is_some() {
+        "dispute windows"
+    } else if config
+        .rules
+        .rules
+        .iter()
+        .any(|rule| matches!(rule.check, Check::MaxWithdrawals { .. }))
+    {
+        "withdrawal velocity rules"
+    } else {
+        return Ok(());
+    };
+    Err(Error::ParallelUnsupported(unsupported.to_string()))
+}
+
+fn split(clients: Clients, transactions: Transactions, count: usize) -> Result<Vec<Shard>, Error> {
+    let mut shards: Vec<Shard> = (0..count).map(|_| Shard::default()).collect();
+    for (id, client) in clients.database {
+        shards[shard_of(id, count)]
+            .clients
+            .database
+            .insert(id, client);
+    }
+    for (id, changes) in clients.status_changes {
+        shards[shard_of(id, count)]
+            .clients
+            .status_changes
+            .insert(id, changes);
+    }
+    for id in transactions.get_evicted_ids()? {
+        shards[0].transactions.evict_transaction(id)?;
+    }
+    for (id, transaction) in transactions.database {
+        shards[shard_of(transaction.client_id, count)]
+            .transactions
+            .insert(id, transaction);
+    }
+    Ok(shards)
+}
+
+fn merge(shards: Vec<Shard>) -> Result<(Clients, Transactions), Error> {
+    let mut clients = Clients::default();
+    let mut transactions = Transactions::default();
+    for shard in shards {
+        clients.database.extend(shard.clients.database);
+        clients.status_changes.extend(shard.clients.status_changes);
+        for id in shard.transactions.get_evicted_ids()? {
+            transactions.evict_transaction(id)?;
+        }
+        for (id, transaction) in shard.transactions.database {
+            transactions.insert(id, transaction);
+        }
+    }
+    Ok((clients, transactions))
+}
+
+/// Shard a record can be processed on along with the other records of that shard, `None` if it involves clients of
+/// several shards. Records sharing a transaction id stay on the shard of the first one, and once they spread over
+/// several shards, every later record with that id is processed on its own.
+fn route(
+    record: &TransactionRecord,
+    claims: &mut HashMap<TransactionID, Option<usize>>,
+    house: Option<ClientID>,
+    count: usize,
+) -> Option<usize> {
+    let other = match record.transaction_type {
+        TransactionType::Transfer => record.to_client_id,
+        // Fees are credited to the house, and refunded by it on chargebacks
+        TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Chargeback => {
+            house
+        }
+        _ => None,
+    };
+    let mut shard = Some(shard_of(record.client_id, count));
+    if other.is_some_and(|other| Some(shard_of(other, count)) != shard) {
+        shard = None;
+    }
+
+    // Credit limits and status changes do not refer to transactions
+    if !matches!(
+        record.transaction_type,
+        TransactionType::Limit
+            | TransactionType::Unlock
+            | TransactionType::Freeze
+            | TransactionType::Close
+    ) {
+        let claim = claims.entry(record.transaction_id).or_insert(shard);
+        if *claim != shard {
+            *claim = None;
+        }
+        shard = *claim;
+    }
+    shard
+}
+
+enum Job<P> {
+    Record(usize, P, TransactionRecord),
+    // Answered once every record queued before it is processed
+    Sync(mpsc::SyncSender<()>),
+}
+
+type Rejected<P> = (usize, P, Error);
+
+// Handles the record as the record at `index` in the input, returning whether it was accepted. An engine cannot know
+// how many of the records before it were accepted on other shards, so the transactions it saves are stamped as if all
+// of them were, and `renumber` fixes the stamps once every record is processed.
+fn handle_at<C: ClientStore, T: TransactionStore>(
+    engine: &mut Engine<C, T>,
+    (handled, accepted): (u64, u64),
+    index: usize,
+    record: &TransactionRecord,
+) -> (bool, Result<(), Error>) {
+    let index = index as u64;
+    engine.set_record_counts(handled + index, accepted + index);
+    let result = engine.handle(record);
+    (engine.record_counts().1 > accepted + index, result)
+}
+
+// Returns the input indexes of the records the worker accepted
+fn work<P>(
+    engine: &mut Engine<ShardedClients, ShardedTransactions>,
+    counts: (u64, u64),
+    jobs: mpsc::Receiver<Job<P>>,
+    errors: &mpsc::Sender<Rejected<P>>,
+) -> Vec<usize> {
+    let mut accepted = Vec::new();
+    for job in jobs {
+        match job {
+            Job::Record(index, payload, record) => {
+                match handle_at(engine, counts, index, &record) {
+                    (true, _) => accepted.push(index),
+                    (false, Err(error)) => {
+                        let _ = errors.send((index, payload, error));
+                    }
+                    (false, Ok(())) => {}
+                }
+            }
+            Job::Sync(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+    accepted
+}
+
+// Stamps the transactions saved by the run with the number of records accepted up to the one that saved them, as in a
+// sequential run. `accepted` holds the sorted input indexes of the accepted records.
+fn renumber(transactions: &mut Transactions, accepted: &[usize], base: u64) {
+    for transaction in transactions.database.values_mut() {
+        if transaction.sequence > base {
+            let index = usize::try_from(transaction.sequence - base - 1).unwrap_or(usize::MAX);
+            let rank = accepted.binary_search(&index).unwrap_or_else(|rank| rank);
+            transaction.sequence = base + rank as u64 + 1;
+        }
+    }
+}
+
+// Waits until every worker has processed the records queued so far
+fn sync<P>(queues: &[mpsc::SyncSender<Job<P>>]) {
+    let synced: Vec<_> = queues
+        .iter()
+        .filter_map(|queue| {
+            let (done, synced) = mpsc::sync_channel(1);
+            queue.send(Job::Sync(done)).ok().map(|()| synced)
+        })
+        .collect();
+    for synced in synced {
+        let _ = synced.recv();
+    }
+}
+
+/// Processes records on `workers` threads, each one owning the clients of a shard and processing their records in
+/// order. Records involving the clients of several shards wait for every worker to catch up and are processed on
+/// their own, so every record sees the same state as in a sequential run.
+///
+/// `records` pairs each record with a payload handed back to `on_error`, which is called for every rejected record in
+/// input order once all records are processed.
+pub(crate) fn process<P: Send>(
+    engine: &mut Engine,
+    workers: NonZeroUsize,
+    records: impl Iterator<Item = (P, Result<TransactionRecord, Error>)>,
+    mut on_error: impl FnMut(P, &Error),
+) -> Result<(), Error> {
+    check_config(engine.config())?;
+    let config = engine.config().clone();
+    let house = config.fees.as_ref().map(|fees| fees.house_client_id);
+    let (handled, accepted) = engine.record_counts();
+    let count = workers.get();
+
+    let shards: Vec<Mutex<Shard>> = {
+        let (clients, transactions) = engine.stores_mut();
+        split(mem::take(clients), mem::take(transactions), count)?
+            .into_iter()
+            .map(Mutex::new)
+            .collect()
+    };
+    let new_engine = |home| {
+        Engine::with_stores(
+            ShardedClients::new(&shards),
+            ShardedTransactions::new(&shards, home),
+        )
+        .with_config(config.clone())
+        .expect("the config was already accepted by the engine")
+    };
+
+    let mut errors = Vec::new();
+    let mut rows = 0;
+    let mut accepted_indexes = thread::scope(|scope| {
+        let (error_sender, error_receiver) = mpsc::channel();
+        let mut queues = Vec::with_capacity(count);
+        let mut threads = Vec::with_capacity(count);
+        for home in 0..count {
+            let (queue, jobs) = mpsc::sync_channel::<Job<P>>(QUEUE_CAPACITY);
+            let mut worker = new_engine(home);
+            let error_sender = error_sender.clone();
+            threads.push(
+                scope.spawn(move || work(&mut worker, (handled, accepted), jobs, &error_sender)),
+            );
+            queues.push(queue);
+        }
+        drop(error_sender);
+
+        let mut own = new_engine(0);
+        let mut accepted_indexes = Vec::new();
+        let mut claims = HashMap::new();
+        for (index, (payload, record)) in records.enumerate() {
+            rows = index + 1;
+            let record = match record {
+                Ok(record) => record,
+                Err(error) => {
+                    errors.push((index, payload, error));
+                    continue;
+                }
+            };
+            if let Some(shard) = route(&record, &mut claims, house, count) {
+                // A worker only stops early if it panicked, which the scope reports
+                if queues[shard]
+                    .send(Job::Record(index, payload, record))
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+
+            sync(&queues);
+            match handle_at(&mut own, (handled, accepted), index, &record) {
+                (true, _) => accepted_indexes.push(index),
+                (false, Err(error)) => errors.push((index, payload, error)),
+                (false, Ok(())) => {}
+            }
+        }
+        drop(queues);
+
+        for thread in threads {
+            accepted_indexes.extend(
+                thread
+                    .join()
+                    .unwrap_or_else(|panic| panic::resume_unwind(panic)),
+            );
+        }
+        errors.extend(error_receiver);
+        accepted_indexes
+    });
+    accepted_indexes.sort_unstable();
+
+    let (clients, mut transactions) = merge(
+        shards
+            .into_iter()
+            .map(|shard| {
+                shard
+                    .into_inner()
+                    .map_err(|e| Error::StoreFailure(e.to_string()))
+            })
+            .collect::<Result<_, _>>()?,
+    )?;
+    renumber(&mut transactions, &accepted_indexes, accepted);
+    let (clients_store, transactions_store) = engine.stores_mut();
+    *clients_store = clients;
+    *transactions_store = transactions;
+    engine.set_record_counts(
+        handled + rows as u64,
+        accepted + accepted_indexes.len() as u64,
+    );
+
+    errors.sort_by_key(|(index, _, _)| *index);
+    for (_, payload, error) in errors {
+        on_error(payload, &error);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fees::{Fee, FeeSchedule, Fees},
+        rules::{Rule, Rules},
+        DisputePolicy,
+    };
+
+    use proptest::prelude::*;
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    fn record_strategy() -> impl Strategy<Value = TransactionRecord> {
+        let transaction_type = prop_oneof![
+            4 => Just(TransactionType::Deposit),
+            3 => Just(TransactionType::Withdrawal),
+            2 => Just(TransactionType::Transfer),
+            3 => Just(TransactionType::Dispute),
+            2 => Just(TransactionType::Resolve),
+            2 => Just(TransactionType::Chargeback),
+            1 => Just(TransactionType::Authorize),
+            1 => Just(TransactionType::Capture),
+            1 => Just(TransactionType::Limit),
+            1 => Just(TransactionType::Unlock),
+            1 => Just(TransactionType::Freeze),
+        ];
+        let amount = proptest::option::weighted(
+            0.8,
+            (1i64..5_000, 0u32..3).prop_map(|(mantissa, scale)| Decimal::new(mantissa, scale)),
+        );
+
+        (
+            transaction_type,
+            1..9u16,
+            1..24u32,
+            amount,
+            proptest::option::weighted(0.9, 1..9u16),
+        )
+            .prop_map(
+                |(transaction_type, client_id, transaction_id, amount, to_client_id)| {
+                    TransactionRecord {
+                        transaction_type,
+                        client_id,
+                        transaction_id,
+                        amount,
+                        to_client_id,
+                        reason: Some("ops".to_string()),
+                        operator: Some("ops".to_string()),
+                        ..TransactionRecord::default()
+                    }
+                },
+            )
+    }
+
+    #[test]
+    fn test_check_config() {
+        assert_eq!(check_config(&Config::default()), Ok(()));
+        assert_eq!(
+            check_config(&Config {
+                hold_expiry: Some(3),
+                ..Config::default()
+            }),
+            Err(Error::ParallelUnsupported("hold expiry".to_string()))
+        );
+        assert_eq!(
+            check_config(&Config {
+                rules: Rules {
+                    rules: vec![Rule {
+                        name: "velocity".to_string(),
+                        check: Check::MaxWithdrawals {
+                            count: 1,
+                            records: 2
+                        },
+                    }],
+                },
+                ..Config::default()
+            }),
+            Err(Error::ParallelUnsupported(
+                "withdrawal velocity rules".to_string()
+            ))
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_parallel_matches_sequential(
+            workers in 1..5usize,
+            charge_fees in any::<bool>(),
+            withdrawal_disputes in any::<bool>(),
+            idempotent in any::<bool>(),
+            records in proptest::collection::vec(record_strategy(), 1..200),
+        ) {
+            let config = Config {
+                dispute_policy: if withdrawal_disputes {
+                    DisputePolicy::DepositsAndWithdrawals
+                } else {
+                    DisputePolicy::DepositsOnly
+                },
+                fees: charge_fees.then(|| FeeSchedule {
+                    default: Fees {
+                        deposit: Some(Fee::Flat(dec!(0.5))),
+                        withdrawal: None,
+                    },
+                    ..FeeSchedule::new(3)
+                }),
+                rules: Rules {
+                    rules: vec![Rule {
+                        name: "disputes".to_string(),
+                        check: Check::FreezeAfterDisputes { disputes: 3 },
+                    }],
+                },
+                idempotent,
+                ..Config::default()
+            };
+
+            let mut sequential = Engine::default().with_config(config.clone())?;
+            let expected_errors: Vec<_> = records
+                .iter()
+                .enumerate()
+                .filter_map(|(index, record)| sequential.handle(record).err().map(|error| (index, format!("{error:?}"))))
+                .collect();
+
+            let mut parallel = Engine::default().with_config(config)?;
+            let mut errors = Vec::new();
+            process(
+                &mut parallel,
+                NonZeroUsize::new(workers).expect("workers should not be zero"),
+                records.iter().cloned().enumerate().map(|(index, record)| (index, Ok(record))),
+                |index, error| errors.push((index, format!("{error:?}"))),
+            )?;
+
+            prop_assert_eq!(errors, expected_errors);
+            // Snapshots hold the balances, the transactions with the sequence numbers they are stamped with and the
+            // record counts
+            prop_assert_eq!(parallel.snapshot()?, sequential.snapshot()?);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{
+    dtos::{TransactionRecord, TransactionType},
+    errors::Error,
+    stores::transactions::{Kind, Transaction},
+};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+/// What a rule checks
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Check {
+    /// Rejects a withdrawal once the client made `count` withdrawals within the last `records` accepted records
+    MaxWithdrawals { count: usize, records: u64 },
+    /// Rejects a deposit, withdrawal, transfer or authorization of more than `amount`
+    MaxAmount { amount: Decimal },
+    /// Freezes the client with the dispute that brings its accepted disputes to `disputes`
+    FreezeAfterDisputes { disputes: u32 },
+}
+
+/// A named fraud or velocity rule. The name is reported by the `RuleViolation` errors of the rule.
+#[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Rule {
+    pub name: String,
+    #[serde(flatten)]
+    pub check: Check,
+}
+
+/// Rules the engine runs before a record changes anything, in the order they are defined.
+///
+/// Rules are evaluated against the history of the record client in the transactions store, which is only read when
+/// a rule needs it.
+#[derive(Deserialize, Default, Clone, PartialEq, Eq, Debug)]
+pub struct Rules {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Parses rules from a toml config file made of `[[rule]]` tables, like
+    ///
+    /// ```toml
+    /// [[rule]]
+    /// name = "withdrawal-velocity"
+    /// kind = "max_withdrawals"
+    /// count = 3
+    /// records = 100
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the config is not valid toml or does not describe valid rules
+    pub fn from_toml(config: &str) -> Result<Self, Error> {
+        toml::from_str(config).map_err(|e| Error::RulesConfigFailure(e.to_string()))
+    }
+
+    /// Runs the rules that can reject `record`, the `sequence`-th accepted record if it passes. `history` loads the
+    /// transactions of the record client.
+    pub(crate) fn check(
+        &self,
+        record: &TransactionRecord,
+        sequence: u64,
+        history: impl FnOnce() -> Result<Vec<Transaction>, Error>,
+    ) -> Result<(), Error> {
+        let mut history = Some(history);
+        let mut transactions = Vec::new();
+
+        for rule in &self.rules {
+            let hit = match rule.check {
+                Check::MaxAmount { amount } => {
+                    matches!(
+                        record.transaction_type,
+                        TransactionType::Deposit
+                            | TransactionType::Withdrawal
+                            | TransactionType::Transfer
+                            | TransactionType::Authorize
+                    ) && record.amount.is_some_and(|value| value > amount)
+                }
+                Check::MaxWithdrawals { count, records } => {
+                    if record.transaction_type != TransactionType::Withdrawal {
+                        continue;
+                    }
+                    if let Some(history) = history.take() {
+                        transactions = history()?;
+                    }
+                    let recent = transactions
+                        .iter()
+                        .filter(|transaction| {
+                            transaction.kind == Kind::Withdrawal
+                                && transaction.sequence + records > sequence
+                        })
+                        .count();
+                    recent >= count
+                }
+                Check::FreezeAfterDisputes { .. } => false,
+            };
+            if hit {
+                return Err(Error::RuleViolation {
+                    rule: rule.name.clone(),
+                    id: record.transaction_id,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if a rule freezes clients after a number of disputes
+    pub(crate) fn freezes(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| matches!(rule.check, Check::FreezeAfterDisputes { .. }))
+    }
+
+    /// Name of the first rule that freezes a client with `disputes` accepted disputes
+    pub(crate) fn freezing_rule(&self, disputes: u32) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|rule| {
+                matches!(rule.check, Check::FreezeAfterDisputes { disputes: limit } if disputes >= limit)
+            })
+            .map(|rule| rule.name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_from_toml() -> Result<(), Error> {
+        let rules = Rules::from_toml(
+            r#"
+            [[rule]]
+            name = "velocity"
+            kind = "max_withdrawals"
+            count = 3
+            records = 100
+
+            [[rule]]
+            name = "big"
+            kind = "max_amount"
+            amount = "10000.5"
+
+            [[rule]]
+            name = "disputes"
+            kind = "freeze_after_disputes"
+            disputes = 2
+            "#,
+        )?;
+        assert_eq!(
+            rules.rules,
+            vec![
+                Rule {
+                    name: "velocity".to_string(),
+                    check: Check::MaxWithdrawals {
+                        count: 3,
+                        records: 100
+                    },
+                },
+                Rule {
+                    name: "big".to_string(),
+                    check: Check::MaxAmount {
+                        amount: dec!(10000.5)
+                    },
+                },
+                Rule {
+                    name: "disputes".to_string(),
+                    check: Check::FreezeAfterDisputes { disputes: 2 },
+                },
+            ]
+        );
+        assert_eq!(Rules::from_toml("")?, Rules::default());
+        assert!(matches!(
+            Rules::from_toml("[[rule]]\nname = \"x\"\nkind = \"unknown\""),
+            Err(Error::RulesConfigFailure(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() -> Result<(), Error> {
+        let rules = Rules {
+            rules: vec![
+                Rule {
+                    name: "big".to_string(),
+                    check: Check::MaxAmount { amount: dec!(100) },
+                },
+                Rule {
+                    name: "velocity".to_string(),
+                    check: Check::MaxWithdrawals {
+                        count: 2,
+                        records: 10,
+                    },
+                },
+            ],
+        };
+        let record = |transaction_type, amount| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id: 7,
+            amount: Some(amount),
+            ..TransactionRecord::default()
+        };
+        let withdrawal = |sequence| Transaction {
+            sequence,
+            ..Transaction::new(Kind::Withdrawal, 1, dec!(1))
+        };
+        let no_history =
+            || -> Result<Vec<Transaction>, Error> { panic!("history should not be read") };
+
+        rules.check(&record(TransactionType::Deposit, dec!(100)), 1, no_history)?;
+        assert_eq!(
+            rules.check(
+                &record(TransactionType::Transfer, dec!(100.01)),
+                1,
+                no_history
+            ),
+            Err(Error::RuleViolation {
+                rule: "big".to_string(),
+                id: 7
+            })
+        );
+        rules.check(&record(TransactionType::Dispute, dec!(500)), 1, no_history)?;
+
+        // Only the withdrawals within the last 10 records count
+        let history = || Ok(vec![withdrawal(5), withdrawal(12)]);
+        rules.check(&record(TransactionType::Withdrawal, dec!(1)), 15, history)?;
+        assert_eq!(
+            rules.check(&record(TransactionType::Withdrawal, dec!(1)), 14, history),
+            Err(Error::RuleViolation {
+                rule: "velocity".to_string(),
+                id: 7
+            })
+        );
+
+        assert!(!rules.freezes());
+        assert_eq!(rules.freezing_rule(100), None);
+
+        Ok(())
+    }
+}
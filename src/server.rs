@@ -0,0 +1,718 @@
+use crate::{
+    dtos::{TaggedTransactionRecord, TransactionRecord},
+    ndjson::ClientBalance,
+    stores::{
+        clients::{Client, Clients},
+        transactions::{Kind, Transactions},
+        ClientStore, TransactionStore,
+    },
+    write_clients, Category, ClientID, Engine, Error, OutputPolicy, TransactionID,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    num::NonZeroUsize,
+    sync::{mpsc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+
+// Bounds on what a request can make the server read
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+const MAX_BODY_BYTES: usize = 64 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Connections served at once unless set with `Server::with_workers`
+const DEFAULT_WORKERS: NonZeroUsize = match NonZeroUsize::new(8) {
+    Some(workers) => workers,
+    None => unreachable!(),
+};
+
+/// HTTP front end to the `Engine`, serving a small REST API over plain HTTP/1.1:
+///
+/// - `POST /transactions` processes one record, a json object like the lines read by `NDJSONProcessor`
+/// - `GET /clients/{id}` returns the balances of a client, one json object per currency
+/// - `GET /clients` returns every balance as json, or as csv with `?format=csv` or an `Accept: text/csv` header
+/// - `GET /transactions/{id}` returns the dispute status of a transaction
+///
+/// Errors are returned as `{"error": ...}` with the json representation of `Error`. Connections are served by a fixed
+/// pool of worker threads and closed after one response, records are processed one at a time.
+pub struct Server<C = Clients, T = Transactions> {
+    listener: TcpListener,
+    engine: Mutex<Engine<C, T>>,
+    output_policy: OutputPolicy,
+    workers: NonZeroUsize,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Option<String>,
+    // Header names are lowercased
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+// Outcome of a posted record
+#[derive(Serialize)]
+struct Processed<'a> {
+    status: &'static str,
+    client: ClientID,
+    tx: TransactionID,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a Error>,
+}
+
+#[derive(Serialize)]
+struct Failure<'a> {
+    error: &'a Error,
+}
+
+// Json representation of the dispute state of a transaction
+#[derive(Serialize)]
+struct DisputeStatus {
+    tx: TransactionID,
+    client: ClientID,
+    #[serde(rename = "type")]
+    kind: Kind,
+    currency: String,
+    amount: Decimal,
+    disputed: Decimal,
+    resolved: Decimal,
+    charged_back: Decimal,
+    /// `disputed` while some of it is under dispute, then `charged_back` or `resolved`, `undisputed` otherwise
+    status: &'static str,
+}
+
+impl<C, T> Server<C, T>
+where
+    C: ClientStore + Send,
+    T: TransactionStore + Send,
+{
+    /// Binds the server to the given address. Binding to port 0 picks a free port, see `local_addr`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the address cannot be bound
+    pub fn bind(engine: Engine<C, T>, address: impl ToSocketAddrs) -> Result<Self, Error> {
+        let listener =
+            TcpListener::bind(address).map_err(|e| Error::ServerFailure(e.to_string()))?;
+        Ok(Self {
+            listener,
+            engine: Mutex::new(engine),
+            output_policy: OutputPolicy::default(),
+            workers: DEFAULT_WORKERS,
+        })
+    }
+
+    /// Sets how many connections are served at once, 8 by default. Further connections wait until a worker is free.
+    #[must_use]
+    pub fn with_workers(mut self, workers: NonZeroUsize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Sets how amounts are rounded in the responses
+    #[must_use]
+    pub fn with_output_policy(mut self, output_policy: OutputPolicy) -> Self {
+        self.output_policy = output_policy;
+        self
+    }
+
+    /// The address the server listens on
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the address of the listener cannot be read
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.listener
+            .local_addr()
+            .map_err(|e| Error::ServerFailure(e.to_string()))
+    }
+
+    /// Accepts and serves connections until the process exits
+    pub fn serve(&self) {
+        // Accepted connections wait here for a free worker. Once it is full, the server stops accepting and new
+        // connections wait in the backlog of the listener.
+        let (queue, connections) = mpsc::sync_channel::<TcpStream>(self.workers.get());
+        let connections = Mutex::new(connections);
+        thread::scope(|scope| {
+            for _ in 0..self.workers.get() {
+                scope.spawn(|| loop {
+                    // The receiver is only locked while waiting for the next connection
+                    let next = match connections.lock() {
+                        Ok(connections) => connections.recv(),
+                        Err(_) => return,
+                    };
+                    match next {
+                        Ok(stream) => self.serve_connection(stream),
+                        Err(_) => return,
+                    }
+                });
+            }
+
+            // Failing to accept a connection only affects that connection
+            for stream in self.listener.incoming().flatten() {
+                if queue.send(stream).is_err() {
+                    break;
+                }
+            }
+            drop(queue);
+        });
+    }
+
+    fn serve_connection(&self, mut stream: TcpStream) {
+        let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+        let response = match read_request(&mut BufReader::new(&mut stream)) {
+            Ok(request) => self.route(&request),
+            Err(response) => response,
+        };
+        // The client may be gone, there is no one left to tell
+        let _ = write_response(&mut stream, &response);
+    }
+
+    fn route(&self, request: &Request) -> Response {
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["transactions"]) => self.post_transaction(&request.body),
+            ("GET", ["clients"]) => self.get_clients(request),
+            ("GET", ["clients", id]) => match id.parse() {
+                Ok(id) => self.get_client(id),
+                Err(e) => failure(
+                    400,
+                    &Error::HTTPRequestFailure(format!("invalid client id {id}: {e}")),
+                ),
+            },
+            ("GET", ["transactions", id]) => match id.parse() {
+                Ok(id) => self.get_transaction(id),
+                Err(e) => failure(
+                    400,
+                    &Error::HTTPRequestFailure(format!("invalid transaction id {id}: {e}")),
+                ),
+            },
+            (_, ["transactions" | "clients"] | ["transactions" | "clients", _]) => failure(
+                405,
+                &Error::HTTPRequestFailure(format!(
+                    "method {} is not allowed on {}",
+                    request.method, request.path
+                )),
+            ),
+            _ => failure(
+                404,
+                &Error::HTTPRequestFailure(format!("no route for {}", request.path)),
+            ),
+        }
+    }
+
+    fn engine(&self) -> Result<MutexGuard<'_, Engine<C, T>>, Response> {
+        self.engine
+            .lock()
+            .map_err(|e| failure(500, &Error::ServerFailure(e.to_string())))
+    }
+
+    fn post_transaction(&self, body: &[u8]) -> Response {
+        let record: TransactionRecord =
+            match serde_json::from_slice::<TaggedTransactionRecord>(body) {
+                Ok(record) => record.into(),
+                Err(e) => return failure(400, &Error::JSONRowReadFailure(e.to_string())),
+            };
+        let result = match self.engine() {
+            Ok(mut engine) => engine.handle(&record),
+            Err(response) => return response,
+        };
+
+        let processed = Processed {
+            status: if result.is_ok() {
+                "accepted"
+            } else {
+                "rejected"
+            },
+            client: record.client_id,
+            tx: record.transaction_id,
+            error: result.as_ref().err(),
+        };
+        json(result.as_ref().map_or_else(status_of, |()| 200), &processed)
+    }
+
+    fn get_clients(&self, request: &Request) -> Response {
+        let csv = match request.query_param("format") {
+            Some("csv") => true,
+            Some("json") => false,
+            Some(format) => {
+                return failure(
+                    400,
+                    &Error::HTTPRequestFailure(format!("unsupported format {format}")),
+                )
+            }
+            None => request
+                .header("accept")
+                .is_some_and(|accept| accept.contains("text/csv")),
+        };
+        let clients = match self.engine().map(|engine| engine.get_clients()) {
+            Ok(Ok(clients)) => clients,
+            Ok(Err(error)) => return failure(status_of(&error), &error),
+            Err(response) => return response,
+        };
+
+        if csv {
+            let mut body = Vec::new();
+            return match write_clients(clients, self.output_policy, &mut body) {
+                Ok(()) => Response {
+                    status: 200,
+                    content_type: "text/csv",
+                    body,
+                },
+                Err(error) => failure(500, &error),
+            };
+        }
+        let balances: Vec<_> = clients
+            .iter()
+            .flat_map(|(id, client)| self.balances(*id, client))
+            .collect();
+        json(200, &balances)
+    }
+
+    fn get_client(&self, id: ClientID) -> Response {
+        let client = match self
+            .engine()
+            .map(|engine| engine.stores().0.find_client(id))
+        {
+            Ok(Ok(Some(client))) => client,
+            Ok(Ok(None)) => return failure(404, &Error::ClientNotExist(id)),
+            Ok(Err(error)) => return failure(status_of(&error), &error),
+            Err(response) => return response,
+        };
+        json(200, &self.balances(id, &client))
+    }
+
+    fn get_transaction(&self, id: TransactionID) -> Response {
+        let found = self.engine().map(|engine| {
+            let transactions = engine.stores().1;
+            Ok::<_, Error>((
+                transactions.find_transaction(id)?,
+                transactions.is_evicted(id)?,
+            ))
+        });
+        let transaction = match found {
+            Ok(Ok((Some(transaction), _))) => transaction,
+            // Evicted transactions can no longer be disputed, only their id is left
+            Ok(Ok((None, true))) => return failure(410, &Error::DisputeWindowExpired(id)),
+            Ok(Ok((None, false))) => return failure(404, &Error::TransactionNotExists(id)),
+            Ok(Err(error)) => return failure(status_of(&error), &error),
+            Err(response) => return response,
+        };
+
+        let status = if transaction.disputed_amount > Decimal::ZERO {
+            "disputed"
+        } else if transaction.charged_back_amount > Decimal::ZERO {
+            "charged_back"
+        } else if transaction.resolved_amount > Decimal::ZERO {
+            "resolved"
+        } else {
+            "undisputed"
+        };
+        let amount = |amount| self.output_policy.apply(amount);
+        json(
+            200,
+            &DisputeStatus {
+                tx: id,
+                client: transaction.client_id,
+                kind: transaction.kind,
+                amount: amount(transaction.amount),
+                disputed: amount(transaction.disputed_amount),
+                resolved: amount(transaction.resolved_amount),
+                charged_back: amount(transaction.charged_back_amount),
+                currency: transaction.currency,
+                status,
+            },
+        )
+    }
+
+    fn balances(&self, id: ClientID, client: &Client) -> Vec<ClientBalance> {
+        client
+            .exported_balances()
+            .into_iter()
+            .map(|(currency, balance)| {
+                ClientBalance::new(id, client, currency, &balance, self.output_policy)
+            })
+            .collect()
+    }
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.as_deref()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (key == name).then_some(value)
+        })
+    }
+}
+
+// Rejected records are the client's fault, unless the server itself failed
+fn status_of(error: &Error) -> u16 {
+    match error.category() {
+        Category::Parse | Category::Validation => 400,
+        Category::Balance | Category::State | Category::Lock => 422,
+        Category::System => 500,
+    }
+}
+
+fn json(status: u16, value: &impl Serialize) -> Response {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response {
+            status,
+            content_type: "application/json",
+            body,
+        },
+        Err(e) => failure(500, &Error::JSONRowWriteFailure(e.to_string())),
+    }
+}
+
+fn failure(status: u16, error: &Error) -> Response {
+    Response {
+        status,
+        content_type: "application/json",
+        // An error always serializes, the fallback only keeps this infallible
+        body: serde_json::to_vec(&Failure { error }).unwrap_or_default(),
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request, Response> {
+    let bad_request = |detail: String| failure(400, &Error::HTTPRequestFailure(detail));
+    let mut head = reader.take(MAX_HEAD_BYTES);
+    let mut read_line = || {
+        let mut line = String::new();
+        match head.read_line(&mut line) {
+            Ok(_) if line.ends_with('\n') => Ok(line.trim_end().to_string()),
+            Ok(_) => Err(bad_request("incomplete request head".to_string())),
+            Err(e) => Err(bad_request(e.to_string())),
+        }
+    };
+
+    let request_line = read_line()?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(_version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request(format!("invalid request line {request_line}")));
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+
+    loop {
+        let line = read_line()?;
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(bad_request(format!("invalid header {line}")));
+        };
+        request
+            .headers
+            .push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    let length = match request.header("content-length") {
+        Some(length) => length
+            .parse()
+            .map_err(|_| bad_request(format!("invalid content length {length}")))?,
+        None => 0,
+    };
+    if length > MAX_BODY_BYTES {
+        return Err(failure(
+            413,
+            &Error::HTTPRequestFailure(format!(
+                "body of {length} bytes is larger than {MAX_BODY_BYTES} bytes"
+            )),
+        ));
+    }
+    request.body.resize(length, 0);
+    reader
+        .read_exact(&mut request.body)
+        .map_err(|e| bad_request(e.to_string()))?;
+    Ok(request)
+}
+
+fn write_response(stream: &mut impl Write, response: &Response) -> std::io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    use serde_json::{json, Value};
+
+    fn start(engine: Engine) -> SocketAddr {
+        let server = Server::bind(engine, "127.0.0.1:0").expect("server should bind");
+        let address = server.local_addr().expect("server should have an address");
+        // The server runs until the test process exits
+        thread::spawn(move || server.serve());
+        address
+    }
+
+    fn request(address: SocketAddr, head: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).expect("server should accept connections");
+        write!(
+            stream,
+            "{head} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .expect("request should be written");
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .expect("response should be read");
+
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .expect("response should have a head");
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .expect("response should have a status");
+        (status, body.to_string())
+    }
+
+    fn request_json(address: SocketAddr, head: &str, body: &str) -> (u16, Value) {
+        let (status, body) = request(address, head, body);
+        (
+            status,
+            serde_json::from_str(&body).expect("body should be json"),
+        )
+    }
+
+    #[test]
+    fn test_transactions_and_balances() {
+        let address = start(Engine::default());
+
+        for record in [
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#,
+            r#"{"type": "deposit", "client": 2, "tx": 2, "amount": 2.5}"#,
+            r#"{"type": "dispute", "client": 1, "tx": 1, "amount": "4"}"#,
+        ] {
+            let (status, body) = request_json(address, "POST /transactions", record);
+            assert_eq!(status, 200, "{body}");
+            assert_eq!(body["status"], "accepted");
+        }
+
+        let (status, body) = request_json(
+            address,
+            "POST /transactions",
+            r#"{"type": "withdrawal", "client": 2, "tx": 3, "amount": "3"}"#,
+        );
+        assert_eq!(status, 422);
+        assert_eq!(
+            body,
+            json!({
+                "status": "rejected",
+                "client": 2,
+                "tx": 3,
+                "error": {
+                    "code": "client_cannot_withdraw",
+                    "category": "balance",
+                    "message": "client 2 cannot withdrawl 3 as available amount is 2.5",
+                    "id": 2,
+                    "amount": "3",
+                    "available": "2.5",
+                },
+            })
+        );
+
+        let (status, body) = request_json(address, "POST /transactions", r#"{"type": "deposit"}"#);
+        assert_eq!(status, 400);
+        assert_eq!(body["error"]["code"], "json_row_read_failure");
+
+        let (status, body) = request_json(address, "GET /clients/1", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            json!([{
+                "client": 1,
+                "currency": "",
+                "available": "6",
+                "held": "4",
+                "total": "10",
+                "locked": false,
+                "status": "active",
+                "credit_limit": "0",
+                "overdrawn": "0",
+                "remaining_credit": "0",
+            }])
+        );
+        let (status, body) = request_json(address, "GET /clients/3", "");
+        assert_eq!(status, 404);
+        assert_eq!(body["error"]["code"], "client_not_exist");
+
+        let (status, body) = request_json(address, "GET /clients", "");
+        assert_eq!(status, 200);
+        let clients: Vec<_> = body
+            .as_array()
+            .expect("balances should be an array")
+            .iter()
+            .map(|balance| (balance["client"].clone(), balance["total"].clone()))
+            .collect();
+        assert_eq!(
+            clients,
+            vec![(json!(1), json!("10")), (json!(2), json!("2.5"))]
+        );
+
+        let (status, body) = request(address, "GET /clients?format=csv", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            "client,available,held,total,locked,status,currency,credit_limit,overdrawn,remaining_credit\n\
+             1,6,4,10,false,active,,0,0,0\n\
+             2,2.5,0,2.5,false,active,,0,0,0\n"
+        );
+        let (status, _) = request(address, "GET /clients?format=xml", "");
+        assert_eq!(status, 400);
+    }
+
+    #[test]
+    fn test_dispute_status() {
+        let address = start(
+            Engine::default()
+                .with_config(Config {
+                    dispute_window: Some(2),
+                    ..Config::default()
+                })
+                .expect("config should be valid"),
+        );
+
+        for record in [
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#,
+            r#"{"type": "deposit", "client": 1, "tx": 2, "amount": "5"}"#,
+            r#"{"type": "dispute", "client": 1, "tx": 2, "amount": "3"}"#,
+            r#"{"type": "deposit", "client": 1, "tx": 3, "amount": "1"}"#,
+        ] {
+            let (status, body) = request(address, "POST /transactions", record);
+            assert_eq!(status, 200, "{body}");
+        }
+
+        let (status, body) = request_json(address, "GET /transactions/2", "");
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            json!({
+                "tx": 2,
+                "client": 1,
+                "type": "deposit",
+                "currency": "",
+                "amount": "5",
+                "disputed": "3",
+                "resolved": "0",
+                "charged_back": "0",
+                "status": "disputed",
+            })
+        );
+        let (_, body) = request_json(address, "GET /transactions/3", "");
+        assert_eq!(body["status"], "undisputed");
+
+        let (status, body) = request_json(address, "GET /transactions/1", "");
+        assert_eq!(
+            status, 410,
+            "transaction 1 should be out of its dispute window"
+        );
+        assert_eq!(body["error"]["code"], "dispute_window_expired");
+        let (status, _) = request(address, "GET /transactions/9", "");
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let address = start(Engine::default());
+
+        assert_eq!(request(address, "GET /accounts", "").0, 404);
+        assert_eq!(request(address, "DELETE /clients/1", "").0, 405);
+        assert_eq!(request(address, "GET /transactions/abc", "").0, 400);
+
+        let mut stream = TcpStream::connect(address).expect("server should accept connections");
+        write!(
+            stream,
+            "POST /transactions HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_BYTES + 1
+        )
+        .expect("request should be written");
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .expect("response should be read");
+        assert!(response.starts_with("HTTP/1.1 413 "), "{response}");
+    }
+
+    #[test]
+    fn test_workers_bound_connections() {
+        let server = Server::bind(Engine::default(), "127.0.0.1:0")
+            .expect("server should bind")
+            .with_workers(NonZeroUsize::MIN);
+        let address = server.local_addr().expect("server should have an address");
+        thread::spawn(move || server.serve());
+
+        // An idle connection keeps the only worker busy
+        let idle = TcpStream::connect(address).expect("server should accept connections");
+        let mut waiting = TcpStream::connect(address).expect("server should accept connections");
+        write!(waiting, "GET /clients HTTP/1.1\r\n\r\n").expect("request should be written");
+        waiting
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .expect("read timeout should be set");
+        let mut response = String::new();
+        assert!(
+            waiting.read_to_string(&mut response).is_err() && response.is_empty(),
+            "the request should wait for a free worker"
+        );
+
+        // Closing the idle connection frees the worker for the waiting one
+        drop(idle);
+        waiting
+            .set_read_timeout(None)
+            .expect("read timeout should be cleared");
+        waiting
+            .read_to_string(&mut response)
+            .expect("response should be read");
+        assert!(response.starts_with("HTTP/1.1 200 "), "{response}");
+    }
+}
@@ -0,0 +1,250 @@
+use crate::{
+    errors::Error,
+    stores::{
+        clients::{Client, StatusChange},
+        transactions::Transaction,
+    },
+    ClientID, TransactionID,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write as _,
+    io::{Read, Write},
+};
+
+/// Version of the snapshot format written by `Snapshot::write`. Version 2 added the evicted transaction ids, and
+/// snapshots of other versions are rejected rather than loaded without them.
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct ClientState {
+    pub(crate) id: ClientID,
+    pub(crate) client: Client,
+    pub(crate) status_changes: Vec<StatusChange>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct TransactionState {
+    pub(crate) id: TransactionID,
+    pub(crate) transaction: Transaction,
+}
+
+/// Everything the engine needs to pick up where it left off
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Eq, Debug)]
+pub(crate) struct State {
+    pub(crate) records_handled: u64,
+    pub(crate) records_accepted: u64,
+    pub(crate) clients: Vec<ClientState>,
+    pub(crate) transactions: Vec<TransactionState>,
+    /// Ids of the transactions evicted after their dispute window
+    pub(crate) evicted: Vec<TransactionID>,
+}
+
+// Layout of a snapshot file. The state is kept as written so the checksum is computed over the exact same bytes.
+#[derive(Serialize, Deserialize)]
+struct Envelope<'a> {
+    version: u32,
+    checksum: String,
+    #[serde(borrow)]
+    state: &'a RawValue,
+}
+
+/// Full state of an `Engine`: every client with its status history, every stored transaction and the record counters
+/// hold expiry and rules rely on. Taken by `Engine::snapshot` and loaded back by `Engine::restore`.
+///
+/// Snapshots are written as a json object with a format `version`, the sha-256 `checksum` of the serialized state
+/// and the `state` itself. The journal and event log are not part of a snapshot.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Snapshot {
+    pub(crate) state: State,
+}
+
+fn checksum(state: &str) -> String {
+    Sha256::digest(state.as_bytes())
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+impl Snapshot {
+    /// Writes the snapshot as json
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the snapshot cannot be written to the passed in writer
+    pub fn write(&self, mut writer: impl Write) -> Result<(), Error> {
+        let state = serde_json::to_string(&self.state)
+            .map_err(|e| Error::SnapshotWriteFailure(e.to_string()))?;
+        let envelope = Envelope {
+            version: SNAPSHOT_VERSION,
+            checksum: checksum(&state),
+            state: &RawValue::from_string(state)
+                .map_err(|e| Error::SnapshotWriteFailure(e.to_string()))?,
+        };
+        serde_json::to_writer(&mut writer, &envelope)
+            .map_err(|e| Error::SnapshotWriteFailure(e.to_string()))?;
+        writeln!(writer).map_err(|e| Error::SnapshotWriteFailure(e.to_string()))?;
+        writer
+            .flush()
+            .map_err(|e| Error::SnapshotWriteFailure(e.to_string()))
+    }
+
+    /// Reads a snapshot written by `write`, checking its version and checksum
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the snapshot cannot be read or parsed, has another format version or does not match its
+    /// checksum
+    pub fn read(mut reader: impl Read) -> Result<Self, Error> {
+        let mut content = String::new();
+        reader
+            .read_to_string(&mut content)
+            .map_err(|e| Error::SnapshotReadFailure(e.to_string()))?;
+        let envelope: Envelope = serde_json::from_str(&content)
+            .map_err(|e| Error::SnapshotReadFailure(e.to_string()))?;
+
+        if envelope.version != SNAPSHOT_VERSION {
+            return Err(Error::UnsupportedSnapshotVersion {
+                version: envelope.version,
+                supported: SNAPSHOT_VERSION,
+            });
+        }
+        let computed = checksum(envelope.state.get());
+        if computed != envelope.checksum {
+            return Err(Error::SnapshotChecksumMismatch {
+                checksum: envelope.checksum,
+                computed,
+            });
+        }
+
+        let state = serde_json::from_str(envelope.state.get())
+            .map_err(|e| Error::SnapshotReadFailure(e.to_string()))?;
+        Ok(Self { state })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stores::{
+        clients::{Balance, Status},
+        transactions::Kind,
+    };
+
+    use rust_decimal_macros::dec;
+    use std::collections::BTreeMap;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            state: State {
+                records_handled: 4,
+                records_accepted: 3,
+                clients: vec![ClientState {
+                    id: 1,
+                    client: Client {
+                        balances: BTreeMap::from([(
+                            "EUR".to_string(),
+                            Balance {
+                                available_amount: dec!(1.50),
+                                held_amount: dec!(2),
+                            },
+                        )]),
+                        status: Status::Frozen,
+                        ..Client::default()
+                    },
+                    status_changes: vec![StatusChange {
+                        from: Status::Active,
+                        to: Status::Frozen,
+                        reason: "kyc".to_string(),
+                        operator: Some("ops".to_string()),
+                    }],
+                }],
+                transactions: vec![TransactionState {
+                    id: 7,
+                    transaction: Transaction {
+                        disputed_amount: dec!(2),
+                        ..Transaction::new(Kind::Deposit, 1, dec!(3.50)).with_currency("EUR")
+                    },
+                }],
+                evicted: vec![2],
+            },
+        }
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), Error> {
+        let mut written = Vec::new();
+        snapshot().write(&mut written)?;
+
+        let read = Snapshot::read(&written[..])?;
+        assert_eq!(read, snapshot());
+        assert_eq!(
+            read.state.clients[0]
+                .client
+                .balance("EUR")
+                .available_amount
+                .to_string(),
+            "1.50",
+            "amounts should keep their scale"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_rejects_invalid_snapshots() -> Result<(), Error> {
+        let mut written = Vec::new();
+        snapshot().write(&mut written)?;
+        let written = String::from_utf8(written).expect("snapshot should be utf8 characters");
+
+        let tampered = written.replace("\"1.50\"", "\"100.50\"");
+        assert!(matches!(
+            Snapshot::read(tampered.as_bytes()),
+            Err(Error::SnapshotChecksumMismatch { .. })
+        ));
+
+        let future = written.replace("\"version\":2", "\"version\":3");
+        assert_eq!(
+            Snapshot::read(future.as_bytes()),
+            Err(Error::UnsupportedSnapshotVersion {
+                version: 3,
+                supported: SNAPSHOT_VERSION
+            })
+        );
+
+        // Version 1 snapshots do not list the evicted transactions, whose ids could then be reused
+        let older = written.replace("\"version\":2", "\"version\":1");
+        assert_eq!(
+            Snapshot::read(older.as_bytes()),
+            Err(Error::UnsupportedSnapshotVersion {
+                version: 1,
+                supported: SNAPSHOT_VERSION
+            })
+        );
+        let state = serde_json::to_string(&snapshot().state)
+            .expect("state should serialize")
+            .replace(",\"evicted\":[2]", "");
+        let without_evicted = format!(
+            "{{\"version\":{SNAPSHOT_VERSION},\"checksum\":\"{}\",\"state\":{state}}}",
+            checksum(&state)
+        );
+        assert!(
+            matches!(
+                Snapshot::read(without_evicted.as_bytes()),
+                Err(Error::SnapshotReadFailure(_))
+            ),
+            "the evicted ids should be required"
+        );
+
+        assert!(matches!(
+            Snapshot::read(&b"{\"version\":2}"[..]),
+            Err(Error::SnapshotReadFailure(_))
+        ));
+
+        Ok(())
+    }
+}
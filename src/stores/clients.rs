@@ -1,130 +1,707 @@
 use crate::{errors::Error, ClientID};
 use rust_decimal::Decimal;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
 
-// DAO (representation of what would be our Clients table in the database)
-#[derive(Default)]
-pub(crate) struct Client {
-    pub(crate) available_amount: Decimal,
-    pub(crate) held_amount: Decimal,
-    pub(crate) locked: bool,
+/// Funds of a client in a single asset
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Eq, Debug)]
+pub struct Balance {
+    /// Negative when the client is using its credit limit
+    pub available_amount: Decimal,
+    pub held_amount: Decimal,
 }
 
-// In a proper implementation, the Clients store would connect to a database instead of being an in-memory store
-#[derive(Default)]
-pub(crate) struct Clients {
-    pub(crate) database: HashMap<ClientID, Client>,
+impl Balance {
+    /// How far the available funds are below zero
+    #[must_use]
+    pub fn overdrawn(&self) -> Decimal {
+        if self.available_amount < Decimal::ZERO {
+            -self.available_amount
+        } else {
+            Decimal::ZERO
+        }
+    }
+}
+
+// DAO (representation of what would be our Clients table in the database)
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Eq, Debug)]
+pub struct Client {
+    /// Balances by currency. Records without a currency use the empty string, so single asset inputs keep working.
+    pub balances: BTreeMap<String, Balance>,
+    pub status: Status,
+    /// Credit line shared by every currency: the overdrawn amounts of all currencies together can reach this limit.
+    /// Zero unless set by a `limit` record.
+    pub credit_limit: Decimal,
 }
 
-impl Clients {
-    pub(crate) fn deposit(&mut self, id: ClientID, amount: Decimal) -> Result<(), Error> {
-        let client = self.database.entry(id).or_insert_with(Client::default);
-        if client.locked {
-            return Err(Error::ClientLocked(id));
+impl Client {
+    /// Balance of the client in the given currency, zero if the client never held it
+    #[must_use]
+    pub fn balance(&self, currency: &str) -> Balance {
+        self.balances.get(currency).cloned().unwrap_or_default()
+    }
+
+    /// Balances to export. A client without any balance (one only given a credit limit, say) gets a zero balance in
+    /// the implicit currency, so every client shows up.
+    #[must_use]
+    pub fn exported_balances(&self) -> Vec<(&str, Balance)> {
+        if self.balances.is_empty() {
+            return vec![("", Balance::default())];
         }
+        self.balances
+            .iter()
+            .map(|(currency, balance)| (currency.as_str(), balance.clone()))
+            .collect()
+    }
 
-        client.available_amount += amount;
+    /// Credit used by the client, the overdrawn amounts of every currency added up
+    #[must_use]
+    pub fn overdrawn(&self) -> Decimal {
+        self.balances.values().map(Balance::overdrawn).sum()
+    }
 
-        Ok(())
+    /// Credit still available to the client, in any currency
+    #[must_use]
+    pub fn remaining_credit(&self) -> Decimal {
+        let remaining = self.credit_limit - self.overdrawn();
+        if remaining > Decimal::ZERO {
+            remaining
+        } else {
+            Decimal::ZERO
+        }
     }
 
-    pub(crate) fn withdrawal(&mut self, id: ClientID, amount: Decimal) -> Result<(), Error> {
-        let client = self
-            .database
-            .get_mut(&id)
-            .ok_or(Error::ClientNotExist(id))?;
-        if client.locked {
-            return Err(Error::ClientLocked(id));
+    /// Returns true if `amount` can be debited from the available funds of the given currency without the client
+    /// using more credit than its limit
+    fn can_debit(&self, currency: &str, amount: Decimal) -> bool {
+        let balance = self.balance(currency);
+        let new_available = balance.available_amount - amount;
+        if new_available >= Decimal::ZERO {
+            return true;
         }
+        self.overdrawn() - balance.overdrawn() - new_available <= self.credit_limit
+    }
+
+    fn balance_mut(&mut self, currency: &str) -> &mut Balance {
+        self.balances.entry(currency.to_string()).or_default()
+    }
+
+    /// Returns `Ok` if the client is allowed to transact
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client is frozen, locked or closed
+    pub fn ensure_active(&self, id: ClientID) -> Result<(), Error> {
+        match self.status {
+            Status::Active => Ok(()),
+            Status::Frozen => Err(Error::ClientFrozen(id)),
+            Status::Locked => Err(Error::ClientLocked(id)),
+            Status::Closed => Err(Error::ClientClosed(id)),
+        }
+    }
+}
+
+/// Account status of a client. Only active clients can transact.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    #[default]
+    Active,
+    /// Frozen by an operator
+    Frozen,
+    /// Locked automatically by a chargeback
+    Locked,
+    /// Closed by an operator, this is final
+    Closed,
+}
+
+impl Status {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Active => "active",
+            Status::Frozen => "frozen",
+            Status::Locked => "locked",
+            Status::Closed => "closed",
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
-        let new_amount = client.available_amount - amount;
-        if new_amount.is_sign_negative() {
+/// Audit entry recorded every time the status of a client changes
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct StatusChange {
+    pub from: Status,
+    pub to: Status,
+    pub reason: String,
+    /// Operator who requested the change, `None` when the engine changed the status on its own (chargebacks)
+    pub operator: Option<String>,
+}
+
+/// Storage backend for client balances.
+///
+/// Implementors only need to provide the `find_client`, `upsert_client` and `get_all` primitives (plus the status
+/// history) and the `begin`, `commit` and `rollback` transaction hooks, the balance operations used by the `Engine`
+/// are built on top of them. Balance operations act on the balance of a single currency and never convert between
+/// currencies.
+///
+/// Each balance operation validates its change before writing anything, so a rejected operation leaves the store
+/// untouched. A record can take several operations though (a deposit with a fee), which the `Engine` all checks before
+/// the first one writes, so `rollback` is only relied on when the storage itself fails partway through a record.
+pub trait ClientStore {
+    /// Looks up a client by id
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn find_client(&self, id: ClientID) -> Result<Option<Client>, Error>;
+
+    /// Inserts or replaces the client with the given id
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn upsert_client(&mut self, id: ClientID, client: Client) -> Result<(), Error>;
+
+    /// Returns every client in the store, ordered by id
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn get_all(&self) -> Result<Vec<(ClientID, Client)>, Error>;
+
+    /// Appends an entry to the status history of a client
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn save_status_change(&mut self, id: ClientID, change: StatusChange) -> Result<(), Error>;
+
+    /// Returns the status history of a client, oldest first
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn get_status_changes(&self, id: ClientID) -> Result<Vec<StatusChange>, Error>;
+
+    /// Called by the `Engine` before processing a record, starting a transaction (or savepoint) that `commit` or
+    /// `rollback` ends
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn begin(&mut self) -> Result<(), Error>;
+
+    /// Called by the `Engine` once a record has been successfully processed, keeping every change made since `begin`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn commit(&mut self) -> Result<(), Error>;
+
+    /// Called by the `Engine` when processing a record failed, discarding every change made since `begin`. A record
+    /// can write several times before it fails, so this has to restore the store as it was at `begin`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn rollback(&mut self) -> Result<(), Error>;
+
+    /// Credits `amount` to the client available funds, creating the client if it does not exist yet
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client is not active
+    fn deposit(&mut self, id: ClientID, currency: &str, amount: Decimal) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.unwrap_or_default();
+        client.ensure_active(id)?;
+        let balance = client.balance_mut(currency);
+
+        balance.available_amount += amount;
+
+        self.upsert_client(id, client)
+    }
+
+    /// Debits `amount` from the client available funds, which can go below zero as long as the client stays within
+    /// its credit limit
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client does not exist, is not active or does not have enough available funds
+    fn withdrawal(&mut self, id: ClientID, currency: &str, amount: Decimal) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.ok_or(Error::ClientNotExist(id))?;
+        client.ensure_active(id)?;
+        if !client.can_debit(currency, amount) {
             return Err(Error::ClientCannotWithdrawl {
                 id,
                 amount,
-                available: client.available_amount,
+                available: client.balance(currency).available_amount,
             });
         }
 
-        client.available_amount = new_amount;
+        client.balance_mut(currency).available_amount -= amount;
 
-        Ok(())
+        self.upsert_client(id, client)
     }
 
-    pub(crate) fn move_to_held(&mut self, id: ClientID, amount: Decimal) -> Result<(), Error> {
-        let client = self
-            .database
-            .get_mut(&id)
-            .ok_or(Error::ClientNotExist(id))?;
-        if client.locked {
-            return Err(Error::ClientLocked(id));
+    /// Moves `amount` from the available funds of `from` to the available funds of `to`, creating the destination
+    /// client if it does not exist yet. Both clients are checked before either is written, so a failed transfer
+    /// changes nothing.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the source client does not exist, either client is not active or the source client does
+    /// not have enough available funds
+    fn transfer(
+        &mut self,
+        from: ClientID,
+        to: ClientID,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), Error> {
+        debug_assert_ne!(from, to, "a client cannot transfer to itself");
+
+        let mut source = self.find_client(from)?.ok_or(Error::ClientNotExist(from))?;
+        source.ensure_active(from)?;
+        let mut destination = self.find_client(to)?.unwrap_or_default();
+        destination.ensure_active(to)?;
+
+        if !source.can_debit(currency, amount) {
+            return Err(Error::ClientCannotWithdrawl {
+                id: from,
+                amount,
+                available: source.balance(currency).available_amount,
+            });
         }
 
-        let new_available = client.available_amount - amount;
-        if new_available.is_sign_negative() {
+        source.balance_mut(currency).available_amount -= amount;
+        destination.balance_mut(currency).available_amount += amount;
+
+        self.upsert_client(from, source)?;
+        self.upsert_client(to, destination)
+    }
+
+    /// Moves `amount` from the client available funds to held funds. Like withdrawals, this can use the client credit
+    /// limit.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client does not exist, is not active or does not have enough available funds
+    fn move_to_held(&mut self, id: ClientID, currency: &str, amount: Decimal) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.ok_or(Error::ClientNotExist(id))?;
+        client.ensure_active(id)?;
+        if !client.can_debit(currency, amount) {
             return Err(Error::ClientCannotDispute {
                 id,
                 amount,
-                available: client.available_amount,
+                available: client.balance(currency).available_amount,
             });
         }
 
-        client.available_amount = new_available;
-        client.held_amount += amount;
+        let balance = client.balance_mut(currency);
+        balance.available_amount -= amount;
+        balance.held_amount += amount;
 
-        Ok(())
+        self.upsert_client(id, client)
     }
 
-    pub(crate) fn move_to_available(&mut self, id: ClientID, amount: Decimal) -> Result<(), Error> {
-        let client = self
-            .database
-            .get_mut(&id)
-            .ok_or(Error::ClientNotExist(id))?;
-        if client.locked {
-            return Err(Error::ClientLocked(id));
-        }
+    /// Moves `amount` from the client held funds back to available funds
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client does not exist, is not active or does not have enough held funds
+    fn move_to_available(
+        &mut self,
+        id: ClientID,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.ok_or(Error::ClientNotExist(id))?;
+        client.ensure_active(id)?;
+        let balance = client.balance_mut(currency);
 
-        let new_held = client.held_amount - amount;
+        let new_held = balance.held_amount - amount;
         if new_held.is_sign_negative() {
             return Err(Error::ClientCannotResolve {
                 id,
                 amount,
-                held: client.held_amount,
+                held: balance.held_amount,
             });
         }
 
-        client.held_amount = new_held;
-        client.available_amount += amount;
+        balance.held_amount = new_held;
+        balance.available_amount += amount;
 
-        Ok(())
+        self.upsert_client(id, client)
     }
 
-    pub(crate) fn chargeback(&mut self, id: ClientID, amount: Decimal) -> Result<(), Error> {
-        let client = self
-            .database
-            .get_mut(&id)
-            .ok_or(Error::ClientNotExist(id))?;
-        if client.locked {
-            return Err(Error::ClientLocked(id));
+    /// Removes `amount` from the client held funds and locks the client
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client does not exist, is not active or does not have enough held funds
+    fn chargeback(&mut self, id: ClientID, currency: &str, amount: Decimal) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.ok_or(Error::ClientNotExist(id))?;
+        client.ensure_active(id)?;
+        let balance = client.balance_mut(currency);
+
+        let new_held = balance.held_amount - amount;
+        if new_held.is_sign_negative() {
+            return Err(Error::ClientCannotChargeBack {
+                id,
+                amount,
+                held: balance.held_amount,
+            });
+        }
+
+        balance.held_amount = new_held;
+        client.status = Status::Locked;
+
+        self.upsert_client(id, client)?;
+        self.save_status_change(id, chargeback_lock())
+    }
+
+    /// Same as `chargeback`, also refunding `refund` from the available funds of `house` to the client available funds
+    /// (the fee of a charged back deposit). Both clients are checked before either is written.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if either client does not exist or is not active, the client does not have enough held funds
+    /// or the house does not have enough available funds (`HouseCannotRefund`)
+    fn chargeback_with_refund(
+        &mut self,
+        id: ClientID,
+        house: ClientID,
+        currency: &str,
+        amount: Decimal,
+        refund: Decimal,
+    ) -> Result<(), Error> {
+        debug_assert_ne!(id, house, "the house never refunds itself");
+
+        let mut house_client = self
+            .find_client(house)?
+            .ok_or(Error::ClientNotExist(house))?;
+        house_client.ensure_active(house)?;
+        if !house_client.can_debit(currency, refund) {
+            return Err(Error::HouseCannotRefund {
+                id: house,
+                amount: refund,
+                available: house_client.balance(currency).available_amount,
+            });
         }
+        self.chargeback(id, currency, amount)?;
 
-        client.locked = true;
+        // The client is locked by now, so the refund is credited directly
+        let mut client = self.find_client(id)?.ok_or(Error::ClientNotExist(id))?;
+        client.balance_mut(currency).available_amount += refund;
+        house_client.balance_mut(currency).available_amount -= refund;
 
-        let new_held = client.held_amount - amount;
+        self.upsert_client(house, house_client)?;
+        self.upsert_client(id, client)
+    }
+
+    /// Settles `amount` of an authorization hold, removing it from the client held funds
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client does not exist, is not active or does not have enough held funds
+    fn capture(&mut self, id: ClientID, currency: &str, amount: Decimal) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.ok_or(Error::ClientNotExist(id))?;
+        client.ensure_active(id)?;
+        let balance = client.balance_mut(currency);
+
+        let new_held = balance.held_amount - amount;
+        if new_held.is_sign_negative() {
+            return Err(Error::ClientCannotCapture {
+                id,
+                amount,
+                held: balance.held_amount,
+            });
+        }
+
+        balance.held_amount = new_held;
+
+        self.upsert_client(id, client)
+    }
+
+    /// Returns `amount` of a disputed transfer from the held funds of `to` to the available funds of `from`, and locks
+    /// `to` as a chargeback would
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if either client does not exist or is not active, or the destination client does not have
+    /// enough held funds
+    fn chargeback_transfer(
+        &mut self,
+        from: ClientID,
+        to: ClientID,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), Error> {
+        let mut source = self.find_client(from)?.ok_or(Error::ClientNotExist(from))?;
+        source.ensure_active(from)?;
+        self.chargeback(to, currency, amount)?;
+
+        source.balance_mut(currency).available_amount += amount;
+
+        self.upsert_client(from, source)
+    }
+
+    /// Holds `amount` as a pending credit while a withdrawal is disputed. Available funds are untouched.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client does not exist or is not active
+    fn hold_pending_credit(
+        &mut self,
+        id: ClientID,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.ok_or(Error::ClientNotExist(id))?;
+        client.ensure_active(id)?;
+        let balance = client.balance_mut(currency);
+
+        balance.held_amount += amount;
+
+        self.upsert_client(id, client)
+    }
+
+    /// Drops a pending credit previously held with `hold_pending_credit`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client does not exist, is not active or does not have enough held funds
+    fn release_pending_credit(
+        &mut self,
+        id: ClientID,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.ok_or(Error::ClientNotExist(id))?;
+        client.ensure_active(id)?;
+        let balance = client.balance_mut(currency);
+
+        let new_held = balance.held_amount - amount;
+        if new_held.is_sign_negative() {
+            return Err(Error::ClientCannotResolve {
+                id,
+                amount,
+                held: balance.held_amount,
+            });
+        }
+
+        balance.held_amount = new_held;
+
+        self.upsert_client(id, client)
+    }
+
+    /// Credits a pending credit back to the client available funds and locks the client
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client does not exist, is not active or does not have enough held funds
+    fn chargeback_pending_credit(
+        &mut self,
+        id: ClientID,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.ok_or(Error::ClientNotExist(id))?;
+        client.ensure_active(id)?;
+        let balance = client.balance_mut(currency);
+
+        let new_held = balance.held_amount - amount;
         if new_held.is_sign_negative() {
             return Err(Error::ClientCannotChargeBack {
                 id,
                 amount,
-                held: client.held_amount,
+                held: balance.held_amount,
             });
         }
 
-        client.held_amount = new_held;
+        balance.held_amount = new_held;
+        balance.available_amount += amount;
+        client.status = Status::Locked;
+
+        self.upsert_client(id, client)?;
+        self.save_status_change(id, chargeback_lock())
+    }
+
+    /// Sets the credit limit of a client, creating the client if it does not exist yet. Lowering the limit below what
+    /// the client already uses only prevents further debits.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client is closed
+    fn set_credit_limit(&mut self, id: ClientID, credit_limit: Decimal) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.unwrap_or_default();
+        if client.status == Status::Closed {
+            return Err(Error::ClientClosed(id));
+        }
+
+        client.credit_limit = credit_limit;
+
+        self.upsert_client(id, client)
+    }
+
+    /// Moves the client to a new status, recording who requested it and why.
+    ///
+    /// Frozen and locked clients can be unlocked back to active, in which case any held funds stay held so the
+    /// disputes still open can be resolved or charged back. Only active clients can be frozen. Any client that is not
+    /// closed yet can be closed as long as it has no held funds.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the client does not exist, the transition is not allowed or the client cannot be closed
+    /// because of held funds
+    fn change_status(
+        &mut self,
+        id: ClientID,
+        to: Status,
+        reason: String,
+        operator: Option<String>,
+    ) -> Result<(), Error> {
+        let mut client = self.find_client(id)?.ok_or(Error::ClientNotExist(id))?;
+        let from = client.status;
+
+        let allowed = matches!(
+            (from, to),
+            (Status::Frozen | Status::Locked, Status::Active)
+                | (Status::Active, Status::Frozen)
+                | (
+                    Status::Active | Status::Frozen | Status::Locked,
+                    Status::Closed
+                )
+        );
+        if !allowed {
+            return Err(Error::ClientStatusChangeNotAllowed { id, from, to });
+        }
+
+        if to == Status::Closed {
+            if let Some(balance) = client.balances.values().find(|b| !b.held_amount.is_zero()) {
+                return Err(Error::ClientCannotClose {
+                    id,
+                    held: balance.held_amount,
+                });
+            }
+        }
+
+        client.status = to;
+
+        self.upsert_client(id, client)?;
+        self.save_status_change(
+            id,
+            StatusChange {
+                from,
+                to,
+                reason,
+                operator,
+            },
+        )
+    }
+}
+
+fn chargeback_lock() -> StatusChange {
+    StatusChange {
+        from: Status::Active,
+        to: Status::Locked,
+        reason: "chargeback".to_string(),
+        operator: None,
+    }
+}
+
+// In a proper implementation, the Clients store would connect to a database instead of being an in-memory store
+#[derive(Default, Clone)]
+pub struct Clients {
+    pub(crate) database: HashMap<ClientID, Client>,
+    pub(crate) status_changes: HashMap<ClientID, Vec<StatusChange>>,
+    // Original state of everything touched since `begin`, restored on `rollback`
+    savepoint: Option<Savepoint>,
+}
+
+#[derive(Default, Clone)]
+struct Savepoint {
+    clients: HashMap<ClientID, Option<Client>>,
+    status_changes: HashMap<ClientID, usize>,
+}
+
+impl ClientStore for Clients {
+    fn find_client(&self, id: ClientID) -> Result<Option<Client>, Error> {
+        Ok(self.database.get(&id).cloned())
+    }
+
+    fn upsert_client(&mut self, id: ClientID, client: Client) -> Result<(), Error> {
+        if let Some(savepoint) = &mut self.savepoint {
+            savepoint
+                .clients
+                .entry(id)
+                .or_insert_with(|| self.database.get(&id).cloned());
+        }
+        self.database.insert(id, client);
         Ok(())
     }
 
-    pub(crate) fn get_all(&self) -> &HashMap<ClientID, Client> {
-        &self.database
+    fn get_all(&self) -> Result<Vec<(ClientID, Client)>, Error> {
+        let mut clients: Vec<_> = self
+            .database
+            .iter()
+            .map(|(id, client)| (*id, client.clone()))
+            .collect();
+        clients.sort_unstable_by_key(|(id, _)| *id);
+        Ok(clients)
+    }
+
+    fn save_status_change(&mut self, id: ClientID, change: StatusChange) -> Result<(), Error> {
+        let changes = self.status_changes.entry(id).or_default();
+        if let Some(savepoint) = &mut self.savepoint {
+            savepoint.status_changes.entry(id).or_insert(changes.len());
+        }
+        changes.push(change);
+        Ok(())
+    }
+
+    fn get_status_changes(&self, id: ClientID) -> Result<Vec<StatusChange>, Error> {
+        Ok(self.status_changes.get(&id).cloned().unwrap_or_default())
+    }
+
+    fn begin(&mut self) -> Result<(), Error> {
+        self.savepoint = Some(Savepoint::default());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        self.savepoint = None;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), Error> {
+        let Some(savepoint) = self.savepoint.take() else {
+            return Ok(());
+        };
+        for (id, client) in savepoint.clients {
+            match client {
+                Some(client) => self.database.insert(id, client),
+                None => self.database.remove(&id),
+            };
+        }
+        for (id, len) in savepoint.status_changes {
+            if let Some(changes) = self.status_changes.get_mut(&id) {
+                changes.truncate(len);
+                if changes.is_empty() {
+                    self.status_changes.remove(&id);
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -134,6 +711,20 @@ mod tests {
 
     use rust_decimal_macros::dec;
 
+    fn client(available_amount: Decimal, held_amount: Decimal, status: Status) -> Client {
+        Client {
+            balances: BTreeMap::from([(
+                String::new(),
+                Balance {
+                    available_amount,
+                    held_amount,
+                },
+            )]),
+            status,
+            ..Client::default()
+        }
+    }
+
     #[test]
     fn test_deposit() -> Result<(), Error> {
         let mut clients = Clients::default();
@@ -147,68 +738,72 @@ mod tests {
         let other_client_id = 321;
 
         // Should create new client with correct available amount, zero held and not locked
-        clients.deposit(client_id, dec!(222.12))?;
+        clients.deposit(client_id, "", dec!(222.12))?;
         assert_eq!(
             clients.database.len(),
             1,
             "one new client should be created"
         );
         assert_eq!(
-            clients.database[&client_id].available_amount,
+            clients.database[&client_id].balance("").available_amount,
             dec!(222.12),
             "new client should have deposited amount"
         );
         assert_eq!(
-            clients.database[&client_id].held_amount,
+            clients.database[&client_id].balance("").held_amount,
             dec!(0),
             "new client should have no held amount"
         );
         assert!(
-            !clients.database[&client_id].locked,
+            clients.database[&client_id].status == Status::Active,
             "new client should not be locked"
         );
 
         // Should update available amount
-        clients.deposit(client_id, dec!(95))?;
+        clients.deposit(client_id, "", dec!(95))?;
         assert_eq!(clients.database.len(), 1, "should update existing client");
         assert_eq!(
-            clients.database[&client_id].available_amount,
+            clients.database[&client_id].balance("").available_amount,
             dec!(317.12),
             "client available amount should be updated with new deposit"
         );
         assert_eq!(
-            clients.database[&client_id].held_amount,
+            clients.database[&client_id].balance("").held_amount,
             dec!(0),
             "client held amount should be unchanged"
         );
         assert!(
-            !clients.database[&client_id].locked,
+            clients.database[&client_id].status == Status::Active,
             "client should still be unlocked"
         );
 
         // Should create new client with correct available amount, without modifying previous client
-        clients.deposit(other_client_id, dec!(0.22))?;
+        clients.deposit(other_client_id, "", dec!(0.22))?;
         assert_eq!(clients.database.len(), 2, "new client should be created");
         assert_eq!(
-            clients.database[&client_id].available_amount,
+            clients.database[&client_id].balance("").available_amount,
             dec!(317.12),
             "existing client amount should be untouched"
         );
         assert_eq!(
-            clients.database[&other_client_id].available_amount,
+            clients.database[&other_client_id]
+                .balance("")
+                .available_amount,
             dec!(0.22),
             "new client should have deposited amount"
         );
 
         // Should fail to deposit money if client is locked
-        clients.database.get_mut(&other_client_id).unwrap().locked = true;
+        clients.database.get_mut(&other_client_id).unwrap().status = Status::Locked;
         assert_eq!(
-            clients.deposit(other_client_id, dec!(1)),
+            clients.deposit(other_client_id, "", dec!(1)),
             Err(Error::ClientLocked(other_client_id)),
             "should fail to deposit money as client is locked"
         );
         assert_eq!(
-            clients.database[&other_client_id].available_amount,
+            clients.database[&other_client_id]
+                .balance("")
+                .available_amount,
             dec!(0.22),
             "available amount should be unchanged"
         );
@@ -224,52 +819,41 @@ mod tests {
         let other_client_id = 112;
         let locked_client_id = 113;
         let non_exist_client_id = 114;
-        clients.database.insert(
-            client_id,
-            Client {
-                available_amount: dec!(95.6),
-                held_amount: dec!(0),
-                locked: false,
-            },
-        );
-        clients.database.insert(
-            other_client_id,
-            Client {
-                available_amount: dec!(0.6),
-                held_amount: dec!(0),
-                locked: false,
-            },
-        );
-        clients.database.insert(
-            locked_client_id,
-            Client {
-                available_amount: dec!(100),
-                held_amount: dec!(0),
-                locked: true,
-            },
-        );
+        clients
+            .database
+            .insert(client_id, client(dec!(95.6), dec!(0), Status::Active));
+        clients
+            .database
+            .insert(other_client_id, client(dec!(0.6), dec!(0), Status::Active));
+        clients
+            .database
+            .insert(locked_client_id, client(dec!(100), dec!(0), Status::Locked));
 
         // Can withdrawl without touching another client
-        clients.withdrawal(client_id, dec!(0.6))?;
+        clients.withdrawal(client_id, "", dec!(0.6))?;
         assert_eq!(
-            clients.database[&client_id].available_amount,
+            clients.database[&client_id].balance("").available_amount,
             dec!(95),
             "withdrawal should decrease available amount"
         );
         assert_eq!(
-            clients.database[&other_client_id].available_amount,
+            clients.database[&other_client_id]
+                .balance("")
+                .available_amount,
             dec!(0.6),
             "withdrawal shouldnt touch other clients"
         );
         assert_eq!(
-            clients.database[&locked_client_id].available_amount,
+            clients.database[&locked_client_id]
+                .balance("")
+                .available_amount,
             dec!(100),
             "withdrawal shouldnt touch other clients"
         );
 
         // Cannot withdrawl higher than available amount
         assert_eq!(
-            clients.withdrawal(other_client_id, dec!(1)),
+            clients.withdrawal(other_client_id, "", dec!(1)),
             Err(Error::ClientCannotWithdrawl {
                 id: other_client_id,
                 amount: dec!(1),
@@ -278,26 +862,28 @@ mod tests {
             "should fail to withdrawl higher than available amount"
         );
         assert_eq!(
-            clients.database[&client_id].available_amount,
+            clients.database[&client_id].balance("").available_amount,
             dec!(95),
             "available amount should be unmodified"
         );
 
         // Cannot withdrawl from locked account
         assert_eq!(
-            clients.withdrawal(locked_client_id, dec!(1)),
+            clients.withdrawal(locked_client_id, "", dec!(1)),
             Err(Error::ClientLocked(locked_client_id)),
             "should fail to withdrawl from locked account"
         );
         assert_eq!(
-            clients.database[&locked_client_id].available_amount,
+            clients.database[&locked_client_id]
+                .balance("")
+                .available_amount,
             dec!(100),
             "available amount should be unmodified"
         );
 
         // Cannot withdrawl from non-existant account
         assert_eq!(
-            clients.withdrawal(non_exist_client_id, dec!(1)),
+            clients.withdrawal(non_exist_client_id, "", dec!(1)),
             Err(Error::ClientNotExist(non_exist_client_id)),
             "should fail to withdrawl from non existant account"
         );
@@ -305,6 +891,182 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_transfer() -> Result<(), Error> {
+        let mut clients = Clients::default();
+
+        let source_id = 121;
+        let destination_id = 122;
+        let locked_client_id = 123;
+        let new_client_id = 124;
+        clients
+            .database
+            .insert(source_id, client(dec!(10), dec!(0), Status::Active));
+        clients
+            .database
+            .insert(destination_id, client(dec!(1), dec!(0), Status::Active));
+        clients
+            .database
+            .insert(locked_client_id, client(dec!(5), dec!(0), Status::Locked));
+
+        // Should debit the source and credit the destination
+        clients.transfer(source_id, destination_id, "", dec!(4))?;
+        assert_eq!(
+            clients.database[&source_id].balance("").available_amount,
+            dec!(6),
+            "transfer should decrease source available amount"
+        );
+        assert_eq!(
+            clients.database[&destination_id]
+                .balance("")
+                .available_amount,
+            dec!(5),
+            "transfer should increase destination available amount"
+        );
+
+        // Should create the destination client
+        clients.transfer(source_id, new_client_id, "", dec!(1))?;
+        assert_eq!(
+            clients.database[&new_client_id]
+                .balance("")
+                .available_amount,
+            dec!(1),
+            "new destination client should have transferred amount"
+        );
+
+        // Should change nothing if the source does not have enough funds
+        assert_eq!(
+            clients.transfer(source_id, destination_id, "", dec!(6)),
+            Err(Error::ClientCannotWithdrawl {
+                id: source_id,
+                amount: dec!(6),
+                available: dec!(5)
+            }),
+            "should fail to transfer more than available amount"
+        );
+
+        // Should change nothing if either side is locked
+        assert_eq!(
+            clients.transfer(source_id, locked_client_id, "", dec!(1)),
+            Err(Error::ClientLocked(locked_client_id)),
+            "should fail to transfer to locked client"
+        );
+        assert_eq!(
+            clients.transfer(locked_client_id, destination_id, "", dec!(1)),
+            Err(Error::ClientLocked(locked_client_id)),
+            "should fail to transfer from locked client"
+        );
+        assert_eq!(
+            clients.database[&source_id].balance("").available_amount,
+            dec!(5),
+            "failed transfers should not update source"
+        );
+        assert_eq!(
+            clients.database[&destination_id]
+                .balance("")
+                .available_amount,
+            dec!(5),
+            "failed transfers should not update destination"
+        );
+        assert_eq!(
+            clients.database[&locked_client_id]
+                .balance("")
+                .available_amount,
+            dec!(5),
+            "failed transfers should not update locked client"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_credit_limit() -> Result<(), Error> {
+        let mut clients = Clients::default();
+
+        let client_id = 115;
+        clients.set_credit_limit(client_id, dec!(50))?;
+        clients.deposit(client_id, "", dec!(10))?;
+
+        // Available funds can go below zero, down to the credit limit
+        clients.withdrawal(client_id, "", dec!(40))?;
+        assert_eq!(
+            clients.database[&client_id].balance("").available_amount,
+            dec!(-30),
+            "withdrawal should use the credit limit"
+        );
+        assert_eq!(
+            clients.database[&client_id].balance("").overdrawn(),
+            dec!(30)
+        );
+        assert_eq!(clients.database[&client_id].remaining_credit(), dec!(20));
+
+        // Disputes use the same limit
+        clients.move_to_held(client_id, "", dec!(20))?;
+        assert_eq!(
+            clients.move_to_held(client_id, "", dec!(0.01)),
+            Err(Error::ClientCannotDispute {
+                id: client_id,
+                amount: dec!(0.01),
+                available: dec!(-50)
+            }),
+            "should fail to dispute past the credit limit"
+        );
+
+        // Lowering the limit only prevents further debits
+        clients.set_credit_limit(client_id, dec!(10))?;
+        assert_eq!(
+            clients.withdrawal(client_id, "", dec!(1)),
+            Err(Error::ClientCannotWithdrawl {
+                id: client_id,
+                amount: dec!(1),
+                available: dec!(-50)
+            }),
+            "should fail to withdrawl past the lowered credit limit"
+        );
+        assert_eq!(clients.database[&client_id].remaining_credit(), dec!(0));
+
+        // The limit is a single credit line shared by every currency
+        let client_id = 116;
+        clients.set_credit_limit(client_id, dec!(50))?;
+        clients.withdrawal(client_id, "USD", dec!(30))?;
+        assert_eq!(
+            clients.withdrawal(client_id, "EUR", dec!(30)),
+            Err(Error::ClientCannotWithdrawl {
+                id: client_id,
+                amount: dec!(30),
+                available: dec!(0)
+            }),
+            "should fail to use more credit than the limit across currencies"
+        );
+        clients.withdrawal(client_id, "EUR", dec!(20))?;
+        assert_eq!(clients.database[&client_id].overdrawn(), dec!(50));
+        assert_eq!(clients.database[&client_id].remaining_credit(), dec!(0));
+        assert_eq!(
+            clients.transfer(client_id, 117, "GBP", dec!(1)),
+            Err(Error::ClientCannotWithdrawl {
+                id: client_id,
+                amount: dec!(1),
+                available: dec!(0)
+            })
+        );
+        clients.deposit(client_id, "USD", dec!(40))?;
+        clients.withdrawal(client_id, "GBP", dec!(30))?;
+        assert_eq!(
+            clients.database[&client_id].remaining_credit(),
+            dec!(0),
+            "repaid credit should be usable in another currency"
+        );
+
+        clients.database.get_mut(&client_id).unwrap().status = Status::Closed;
+        assert_eq!(
+            clients.set_credit_limit(client_id, dec!(100)),
+            Err(Error::ClientClosed(client_id)),
+            "should fail to change the limit of a closed client"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_move_to_held() -> Result<(), Error> {
         let mut clients = Clients::default();
@@ -312,47 +1074,33 @@ mod tests {
         let client_id = 94;
         let other_client_id = 95;
         let locked_client_id = 96;
-        clients.database.insert(
-            client_id,
-            Client {
-                available_amount: dec!(95.6),
-                held_amount: dec!(131.33),
-                locked: false,
-            },
-        );
-        clients.database.insert(
-            other_client_id,
-            Client {
-                available_amount: dec!(12),
-                held_amount: dec!(31),
-                locked: false,
-            },
-        );
+        clients
+            .database
+            .insert(client_id, client(dec!(95.6), dec!(131.33), Status::Active));
+        clients
+            .database
+            .insert(other_client_id, client(dec!(12), dec!(31), Status::Active));
         clients.database.insert(
             locked_client_id,
-            Client {
-                available_amount: dec!(100),
-                held_amount: dec!(11),
-                locked: true,
-            },
+            client(dec!(100), dec!(11), Status::Locked),
         );
 
         // Should correctly move funds from available to held
-        clients.move_to_held(client_id, dec!(30))?;
+        clients.move_to_held(client_id, "", dec!(30))?;
         assert_eq!(
-            clients.database[&client_id].available_amount,
+            clients.database[&client_id].balance("").available_amount,
             dec!(65.6),
             "client available amount should correctly decrease"
         );
         assert_eq!(
-            clients.database[&client_id].held_amount,
+            clients.database[&client_id].balance("").held_amount,
             dec!(161.33),
             "client held amount should correctly increase"
         );
 
         // Should fail to move more funds than available
         assert_eq!(
-            clients.move_to_held(other_client_id, dec!(12.01)),
+            clients.move_to_held(other_client_id, "", dec!(12.01)),
             Err(Error::ClientCannotDispute {
                 id: other_client_id,
                 amount: dec!(12.01),
@@ -361,29 +1109,33 @@ mod tests {
             "should fail to move more funds than available"
         );
         assert_eq!(
-            clients.database[&other_client_id].available_amount,
+            clients.database[&other_client_id]
+                .balance("")
+                .available_amount,
             dec!(12),
             "should not update available amount"
         );
         assert_eq!(
-            clients.database[&other_client_id].held_amount,
+            clients.database[&other_client_id].balance("").held_amount,
             dec!(31),
             "should not update held amount"
         );
 
         // Should fail to move funds on a locked account
         assert_eq!(
-            clients.move_to_held(locked_client_id, dec!(1)),
+            clients.move_to_held(locked_client_id, "", dec!(1)),
             Err(Error::ClientLocked(locked_client_id)),
             "should fail to move funds on a locked account"
         );
         assert_eq!(
-            clients.database[&locked_client_id].available_amount,
+            clients.database[&locked_client_id]
+                .balance("")
+                .available_amount,
             dec!(100),
             "should not update available amount"
         );
         assert_eq!(
-            clients.database[&locked_client_id].held_amount,
+            clients.database[&locked_client_id].balance("").held_amount,
             dec!(11),
             "should not update held amount"
         );
@@ -398,47 +1150,33 @@ mod tests {
         let client_id = 94;
         let other_client_id = 95;
         let locked_client_id = 96;
-        clients.database.insert(
-            client_id,
-            Client {
-                available_amount: dec!(95.6),
-                held_amount: dec!(131.33),
-                locked: false,
-            },
-        );
-        clients.database.insert(
-            other_client_id,
-            Client {
-                available_amount: dec!(12),
-                held_amount: dec!(31),
-                locked: false,
-            },
-        );
+        clients
+            .database
+            .insert(client_id, client(dec!(95.6), dec!(131.33), Status::Active));
+        clients
+            .database
+            .insert(other_client_id, client(dec!(12), dec!(31), Status::Active));
         clients.database.insert(
             locked_client_id,
-            Client {
-                available_amount: dec!(100),
-                held_amount: dec!(11),
-                locked: true,
-            },
+            client(dec!(100), dec!(11), Status::Locked),
         );
 
         // Should correctly move funds from available to held
-        clients.move_to_available(client_id, dec!(30))?;
+        clients.move_to_available(client_id, "", dec!(30))?;
         assert_eq!(
-            clients.database[&client_id].available_amount,
+            clients.database[&client_id].balance("").available_amount,
             dec!(125.6),
             "should correctly increase available funds"
         );
         assert_eq!(
-            clients.database[&client_id].held_amount,
+            clients.database[&client_id].balance("").held_amount,
             dec!(101.33),
             "should correctly decrease held funds"
         );
 
         // Should fail to move more funds than held
         assert_eq!(
-            clients.move_to_available(other_client_id, dec!(31.01)),
+            clients.move_to_available(other_client_id, "", dec!(31.01)),
             Err(Error::ClientCannotResolve {
                 id: other_client_id,
                 amount: dec!(31.01),
@@ -447,29 +1185,33 @@ mod tests {
             "should fail to move more funds than in held"
         );
         assert_eq!(
-            clients.database[&other_client_id].available_amount,
+            clients.database[&other_client_id]
+                .balance("")
+                .available_amount,
             dec!(12),
             "should not update available amount"
         );
         assert_eq!(
-            clients.database[&other_client_id].held_amount,
+            clients.database[&other_client_id].balance("").held_amount,
             dec!(31),
             "should not update held amount"
         );
 
         // Should fail to move funds on a locked account
         assert_eq!(
-            clients.move_to_available(locked_client_id, dec!(1)),
+            clients.move_to_available(locked_client_id, "", dec!(1)),
             Err(Error::ClientLocked(locked_client_id)),
             "should fail to move funds on a locked account"
         );
         assert_eq!(
-            clients.database[&locked_client_id].available_amount,
+            clients.database[&locked_client_id]
+                .balance("")
+                .available_amount,
             dec!(100),
             "should not update available amount"
         );
         assert_eq!(
-            clients.database[&locked_client_id].held_amount,
+            clients.database[&locked_client_id].balance("").held_amount,
             dec!(11),
             "should not update held amount"
         );
@@ -484,51 +1226,37 @@ mod tests {
         let client_id = 94;
         let other_client_id = 95;
         let locked_client_id = 96;
-        clients.database.insert(
-            client_id,
-            Client {
-                available_amount: dec!(95.6),
-                held_amount: dec!(131.33),
-                locked: false,
-            },
-        );
-        clients.database.insert(
-            other_client_id,
-            Client {
-                available_amount: dec!(12),
-                held_amount: dec!(31),
-                locked: false,
-            },
-        );
+        clients
+            .database
+            .insert(client_id, client(dec!(95.6), dec!(131.33), Status::Active));
+        clients
+            .database
+            .insert(other_client_id, client(dec!(12), dec!(31), Status::Active));
         clients.database.insert(
             locked_client_id,
-            Client {
-                available_amount: dec!(100),
-                held_amount: dec!(11),
-                locked: true,
-            },
+            client(dec!(100), dec!(11), Status::Locked),
         );
 
         // Should chargeback and lock account
-        clients.chargeback(client_id, dec!(100))?;
+        clients.chargeback(client_id, "", dec!(100))?;
         assert_eq!(
-            clients.database[&client_id].available_amount,
+            clients.database[&client_id].balance("").available_amount,
             dec!(95.6),
             "should not update available ammount"
         );
         assert_eq!(
-            clients.database[&client_id].held_amount,
+            clients.database[&client_id].balance("").held_amount,
             dec!(31.33),
             "should properly update held amount"
         );
         assert!(
-            clients.database[&client_id].locked,
+            clients.database[&client_id].status == Status::Locked,
             "client should now be locked"
         );
 
         // Should fail to chargeback if amount is higher than held
         assert_eq!(
-            clients.chargeback(other_client_id, dec!(31.01)),
+            clients.chargeback(other_client_id, "", dec!(31.01)),
             Err(Error::ClientCannotChargeBack {
                 id: other_client_id,
                 amount: dec!(31.01),
@@ -537,41 +1265,466 @@ mod tests {
             "should fail chargeback if not enough money in held"
         );
         assert_eq!(
-            clients.database[&other_client_id].available_amount,
+            clients.database[&other_client_id]
+                .balance("")
+                .available_amount,
             dec!(12),
             "should not update available amount"
         );
         assert_eq!(
-            clients.database[&other_client_id].held_amount,
+            clients.database[&other_client_id].balance("").held_amount,
             dec!(31),
             "should not update held amount"
         );
         assert!(
-            clients.database[&other_client_id].locked,
-            "client should now be locked"
+            clients.database[&other_client_id].status == Status::Active,
+            "rejected chargeback should not lock the client"
         );
 
         // Should fail to chargeback an already locked account
         assert_eq!(
-            clients.chargeback(locked_client_id, dec!(1)),
+            clients.chargeback(locked_client_id, "", dec!(1)),
             Err(Error::ClientLocked(locked_client_id)),
             "should fail to chargeback an already locked account"
         );
         assert_eq!(
-            clients.database[&locked_client_id].available_amount,
+            clients.database[&locked_client_id]
+                .balance("")
+                .available_amount,
             dec!(100),
             "should not update available amount"
         );
         assert_eq!(
-            clients.database[&locked_client_id].held_amount,
+            clients.database[&locked_client_id].balance("").held_amount,
             dec!(11),
             "should not update held amount"
         );
         assert!(
-            clients.database[&locked_client_id].locked,
+            clients.database[&locked_client_id].status == Status::Locked,
             "client should still be locked"
         );
 
         Ok(())
     }
+
+    #[test]
+    fn test_chargeback_with_refund() -> Result<(), Error> {
+        let mut clients = Clients::default();
+
+        let client_id = 94;
+        let house_id = 95;
+        clients
+            .database
+            .insert(client_id, client(dec!(0), dec!(98), Status::Active));
+        clients
+            .database
+            .insert(house_id, client(dec!(1), dec!(0), Status::Active));
+
+        // Should fail without touching the house if the chargeback is rejected
+        assert_eq!(
+            clients.chargeback_with_refund(client_id, house_id, "", dec!(99), dec!(1)),
+            Err(Error::ClientCannotChargeBack {
+                id: client_id,
+                amount: dec!(99),
+                held: dec!(98)
+            }),
+            "should fail chargeback if not enough money in held"
+        );
+        assert_eq!(
+            clients.database[&house_id].balance("").available_amount,
+            dec!(1),
+            "should not refund a rejected chargeback"
+        );
+
+        // Should fail without touching the client if the house cannot refund
+        assert_eq!(
+            clients.chargeback_with_refund(client_id, house_id, "", dec!(98), dec!(2)),
+            Err(Error::HouseCannotRefund {
+                id: house_id,
+                amount: dec!(2),
+                available: dec!(1)
+            }),
+            "should fail if the house cannot refund"
+        );
+        assert_eq!(
+            clients.database[&client_id].balance("").held_amount,
+            dec!(98),
+            "should not update held amount"
+        );
+        assert!(
+            clients.database[&client_id].status == Status::Active,
+            "rejected chargeback should not lock the client"
+        );
+
+        // Should chargeback, lock the client and refund it from the house
+        clients.chargeback_with_refund(client_id, house_id, "", dec!(98), dec!(1))?;
+        assert_eq!(
+            clients.database[&client_id].balance(""),
+            Balance {
+                available_amount: dec!(1),
+                held_amount: dec!(0),
+            }
+        );
+        assert!(
+            clients.database[&client_id].status == Status::Locked,
+            "client should now be locked"
+        );
+        assert_eq!(
+            clients.database[&house_id].balance("").available_amount,
+            dec!(0),
+            "should debit the refund from the house"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chargeback_transfer() -> Result<(), Error> {
+        let mut clients = Clients::default();
+
+        let source_id = 94;
+        let destination_id = 95;
+        clients
+            .database
+            .insert(source_id, client(dec!(0), dec!(0), Status::Frozen));
+        clients
+            .database
+            .insert(destination_id, client(dec!(0), dec!(10), Status::Active));
+
+        // Should change nothing if the source is not active
+        assert_eq!(
+            clients.chargeback_transfer(source_id, destination_id, "", dec!(10)),
+            Err(Error::ClientFrozen(source_id)),
+            "should fail to credit a frozen source"
+        );
+        assert_eq!(
+            clients.database[&destination_id].balance("").held_amount,
+            dec!(10),
+            "should not update the destination held amount"
+        );
+        assert!(
+            clients.database[&destination_id].status == Status::Active,
+            "rejected chargeback should not lock the destination"
+        );
+
+        // Should return the held funds to the source and lock the destination
+        clients.database.get_mut(&source_id).unwrap().status = Status::Active;
+        clients.chargeback_transfer(source_id, destination_id, "", dec!(10))?;
+        assert_eq!(
+            clients.database[&source_id].balance("").available_amount,
+            dec!(10)
+        );
+        assert_eq!(
+            clients.database[&destination_id].balance("").held_amount,
+            dec!(0)
+        );
+        assert!(
+            clients.database[&destination_id].status == Status::Locked,
+            "destination should now be locked"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture() -> Result<(), Error> {
+        let mut clients = Clients::default();
+
+        let client_id = 97;
+        let frozen_client_id = 98;
+        clients
+            .database
+            .insert(client_id, client(dec!(5), dec!(20), Status::Active));
+        clients
+            .database
+            .insert(frozen_client_id, client(dec!(5), dec!(20), Status::Frozen));
+
+        // Should settle held funds without locking the client
+        clients.capture(client_id, "", dec!(15))?;
+        assert_eq!(
+            clients.database[&client_id],
+            client(dec!(5), dec!(5), Status::Active),
+            "capture should only decrease held amount"
+        );
+
+        // Should fail to capture more than held
+        assert_eq!(
+            clients.capture(client_id, "", dec!(6)),
+            Err(Error::ClientCannotCapture {
+                id: client_id,
+                amount: dec!(6),
+                held: dec!(5)
+            }),
+            "should fail capture if not enough money in held"
+        );
+        assert_eq!(
+            clients.capture(frozen_client_id, "", dec!(1)),
+            Err(Error::ClientFrozen(frozen_client_id)),
+            "should fail capture on frozen client"
+        );
+        assert_eq!(
+            clients.database[&frozen_client_id],
+            client(dec!(5), dec!(20), Status::Frozen),
+            "failed capture should not update the client"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hold_pending_credit() -> Result<(), Error> {
+        let mut clients = Clients::default();
+
+        let client_id = 94;
+        let locked_client_id = 96;
+        clients
+            .database
+            .insert(client_id, client(dec!(10), dec!(1), Status::Active));
+        clients.database.insert(
+            locked_client_id,
+            client(dec!(100), dec!(11), Status::Locked),
+        );
+
+        // Should increase held amount without touching available
+        clients.hold_pending_credit(client_id, "", dec!(30))?;
+        assert_eq!(
+            clients.database[&client_id].balance("").available_amount,
+            dec!(10),
+            "should not update available amount"
+        );
+        assert_eq!(
+            clients.database[&client_id].balance("").held_amount,
+            dec!(31),
+            "should increase held amount"
+        );
+
+        // Should fail on a locked account
+        assert_eq!(
+            clients.hold_pending_credit(locked_client_id, "", dec!(1)),
+            Err(Error::ClientLocked(locked_client_id)),
+            "should fail to hold funds on a locked account"
+        );
+        assert_eq!(
+            clients.database[&locked_client_id].balance("").held_amount,
+            dec!(11),
+            "should not update held amount"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_release_pending_credit() -> Result<(), Error> {
+        let mut clients = Clients::default();
+
+        let client_id = 94;
+        clients
+            .database
+            .insert(client_id, client(dec!(10), dec!(31), Status::Active));
+
+        // Should decrease held amount without touching available
+        clients.release_pending_credit(client_id, "", dec!(30))?;
+        assert_eq!(
+            clients.database[&client_id].balance("").available_amount,
+            dec!(10),
+            "should not update available amount"
+        );
+        assert_eq!(
+            clients.database[&client_id].balance("").held_amount,
+            dec!(1),
+            "should decrease held amount"
+        );
+
+        // Should fail to release more than held
+        assert_eq!(
+            clients.release_pending_credit(client_id, "", dec!(1.01)),
+            Err(Error::ClientCannotResolve {
+                id: client_id,
+                amount: dec!(1.01),
+                held: dec!(1)
+            }),
+            "should fail to release more funds than held"
+        );
+        assert_eq!(
+            clients.database[&client_id].balance("").held_amount,
+            dec!(1),
+            "should not update held amount"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chargeback_pending_credit() -> Result<(), Error> {
+        let mut clients = Clients::default();
+
+        let client_id = 94;
+        let other_client_id = 95;
+        clients
+            .database
+            .insert(client_id, client(dec!(10), dec!(31), Status::Active));
+        clients
+            .database
+            .insert(other_client_id, client(dec!(12), dec!(5), Status::Active));
+
+        // Should credit the held amount back to available and lock the account
+        clients.chargeback_pending_credit(client_id, "", dec!(30))?;
+        assert_eq!(
+            clients.database[&client_id].balance("").available_amount,
+            dec!(40),
+            "should credit available amount"
+        );
+        assert_eq!(
+            clients.database[&client_id].balance("").held_amount,
+            dec!(1),
+            "should decrease held amount"
+        );
+        assert!(
+            clients.database[&client_id].status == Status::Locked,
+            "client should now be locked"
+        );
+
+        // Should fail to chargeback more than held, leaving the client untouched
+        assert_eq!(
+            clients.chargeback_pending_credit(other_client_id, "", dec!(5.01)),
+            Err(Error::ClientCannotChargeBack {
+                id: other_client_id,
+                amount: dec!(5.01),
+                held: dec!(5)
+            }),
+            "should fail chargeback if not enough money in held"
+        );
+        assert_eq!(
+            clients.database[&other_client_id]
+                .balance("")
+                .available_amount,
+            dec!(12),
+            "should not update available amount"
+        );
+        assert!(
+            clients.database[&other_client_id].status == Status::Active,
+            "client should not be locked"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_change_status() -> Result<(), Error> {
+        let mut clients = Clients::default();
+
+        let client_id = 94;
+        let non_exist_client_id = 97;
+        clients
+            .database
+            .insert(client_id, client(dec!(10), dec!(0), Status::Active));
+
+        // Should freeze and unlock an active client
+        clients.change_status(
+            client_id,
+            Status::Frozen,
+            "fraud review".to_string(),
+            Some("alice".to_string()),
+        )?;
+        assert_eq!(
+            clients.withdrawal(client_id, "", dec!(1)),
+            Err(Error::ClientFrozen(client_id)),
+            "frozen client cannot transact"
+        );
+        clients.change_status(
+            client_id,
+            Status::Active,
+            "review done".to_string(),
+            Some("alice".to_string()),
+        )?;
+        assert_eq!(clients.database[&client_id].status, Status::Active);
+
+        // Should reject transitions that make no sense
+        assert_eq!(
+            clients.change_status(client_id, Status::Active, "again".to_string(), None),
+            Err(Error::ClientStatusChangeNotAllowed {
+                id: client_id,
+                from: Status::Active,
+                to: Status::Active
+            }),
+            "active client cannot be unlocked"
+        );
+        assert_eq!(
+            clients.change_status(non_exist_client_id, Status::Frozen, "x".to_string(), None),
+            Err(Error::ClientNotExist(non_exist_client_id)),
+            "non existant client cannot change status"
+        );
+
+        // Closed is final
+        clients.change_status(client_id, Status::Closed, "gone".to_string(), None)?;
+        assert_eq!(
+            clients.change_status(client_id, Status::Active, "back".to_string(), None),
+            Err(Error::ClientStatusChangeNotAllowed {
+                id: client_id,
+                from: Status::Closed,
+                to: Status::Active
+            }),
+            "closed client cannot be reopened"
+        );
+
+        assert_eq!(
+            clients.get_status_changes(client_id)?,
+            vec![
+                StatusChange {
+                    from: Status::Active,
+                    to: Status::Frozen,
+                    reason: "fraud review".to_string(),
+                    operator: Some("alice".to_string()),
+                },
+                StatusChange {
+                    from: Status::Frozen,
+                    to: Status::Active,
+                    reason: "review done".to_string(),
+                    operator: Some("alice".to_string()),
+                },
+                StatusChange {
+                    from: Status::Active,
+                    to: Status::Closed,
+                    reason: "gone".to_string(),
+                    operator: None,
+                },
+            ],
+            "only successful changes should be recorded"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback() -> Result<(), Error> {
+        let mut clients = Clients::default();
+        clients.deposit(1, "", dec!(10))?;
+
+        clients.begin()?;
+        clients.move_to_held(1, "", dec!(4))?;
+        clients.chargeback(1, "", dec!(4))?;
+        clients.deposit(2, "", dec!(3))?;
+        clients.rollback()?;
+
+        assert_eq!(
+            clients.get_all()?,
+            vec![(1, client(dec!(10), dec!(0), Status::Active))],
+            "every change since begin should be discarded"
+        );
+        assert!(
+            clients.status_changes.is_empty(),
+            "status changes since begin should be discarded"
+        );
+
+        clients.begin()?;
+        clients.deposit(2, "", dec!(3))?;
+        clients.commit()?;
+        clients.rollback()?;
+        assert!(
+            clients.find_client(2)?.is_some(),
+            "committed changes should not be rolled back"
+        );
+
+        Ok(())
+    }
 }
@@ -1,2 +1,7 @@
-pub(crate) mod clients;
-pub(crate) mod transactions;
+pub mod clients;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod transactions;
+
+pub use clients::ClientStore;
+pub use transactions::TransactionStore;
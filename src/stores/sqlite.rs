@@ -0,0 +1,858 @@
+use crate::stores::{
+    clients::{Balance, Client, ClientStore, Status, StatusChange},
+    transactions::{Kind, Transaction, TransactionStore},
+};
+use crate::{errors::Error, ClientID, TransactionID};
+use rusqlite::{params, Connection, OptionalExtension};
+use rust_decimal::Decimal;
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS clients (
+        id INTEGER PRIMARY KEY,
+        status TEXT NOT NULL,
+        credit_limit TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS client_balances (
+        client_id INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        PRIMARY KEY (client_id, currency)
+    );
+    CREATE TABLE IF NOT EXISTS client_status_changes (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        client_id INTEGER NOT NULL,
+        from_status TEXT NOT NULL,
+        to_status TEXT NOT NULL,
+        reason TEXT NOT NULL,
+        operator TEXT
+    );
+    CREATE TABLE IF NOT EXISTS transactions (
+        id INTEGER PRIMARY KEY,
+        kind TEXT NOT NULL,
+        client_id INTEGER NOT NULL,
+        counterparty INTEGER,
+        currency TEXT NOT NULL,
+        amount TEXT NOT NULL,
+        fee TEXT NOT NULL,
+        disputed_amount TEXT NOT NULL,
+        resolved_amount TEXT NOT NULL,
+        charged_back_amount TEXT NOT NULL,
+        captured_amount TEXT NOT NULL,
+        released_amount TEXT NOT NULL,
+        expires_at INTEGER,
+        sequence INTEGER NOT NULL,
+        dispute_count INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS transactions_client_id ON transactions (client_id);
+    CREATE TABLE IF NOT EXISTS evicted_transactions (
+        id INTEGER PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS record_counts (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        handled INTEGER NOT NULL,
+        accepted INTEGER NOT NULL
+    );
+";
+
+/// Opens (creating it if needed) the `SQLite` database at `path` and returns the client and transaction stores backed
+/// by it.
+///
+/// Both stores share the same connection so that the changes made by a single `Engine::handle` call are committed
+/// atomically.
+///
+/// # Errors
+///
+/// Will return `Err` if the database cannot be opened or its schema cannot be created
+pub fn open(path: impl AsRef<Path>) -> Result<(SqliteClients, SqliteTransactions), Error> {
+    split(Connection::open(path).map_err(store_failure)?)
+}
+
+/// Same as `open` but backed by a throwaway in-memory database
+///
+/// # Errors
+///
+/// Will return `Err` if the database schema cannot be created
+pub fn open_in_memory() -> Result<(SqliteClients, SqliteTransactions), Error> {
+    split(Connection::open_in_memory().map_err(store_failure)?)
+}
+
+fn split(connection: Connection) -> Result<(SqliteClients, SqliteTransactions), Error> {
+    connection.execute_batch(SCHEMA).map_err(store_failure)?;
+    let connection = Arc::new(Mutex::new(connection));
+
+    Ok((
+        SqliteClients {
+            connection: Arc::clone(&connection),
+        },
+        SqliteTransactions { connection },
+    ))
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn store_failure(error: rusqlite::Error) -> Error {
+    Error::StoreFailure(error.to_string())
+}
+
+fn parse_decimal(value: &str) -> Result<Decimal, Error> {
+    Decimal::from_str(value).map_err(|e| Error::StoreFailure(e.to_string()))
+}
+
+fn parse_status(value: &str) -> Result<Status, Error> {
+    [
+        Status::Active,
+        Status::Frozen,
+        Status::Locked,
+        Status::Closed,
+    ]
+    .into_iter()
+    .find(|status| status.as_str() == value)
+    .ok_or_else(|| Error::StoreFailure(format!("unknown client status {value}")))
+}
+
+fn parse_balance(available: &str, held: &str) -> Result<Balance, Error> {
+    Ok(Balance {
+        available_amount: parse_decimal(available)?,
+        held_amount: parse_decimal(held)?,
+    })
+}
+
+fn lock(connection: &Mutex<Connection>) -> Result<MutexGuard<'_, Connection>, Error> {
+    connection
+        .lock()
+        .map_err(|e| Error::StoreFailure(e.to_string()))
+}
+
+// Each store wraps its changes in its own named savepoint. Savepoints nest, so releasing the outermost one commits
+// the changes of both stores in a single transaction.
+fn execute(connection: &Mutex<Connection>, sql: &str) -> Result<(), Error> {
+    lock(connection)?.execute_batch(sql).map_err(store_failure)
+}
+
+pub struct SqliteClients {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl ClientStore for SqliteClients {
+    fn find_client(&self, id: ClientID) -> Result<Option<Client>, Error> {
+        let connection = lock(&self.connection)?;
+        let Some((status, credit_limit)) = connection
+            .query_row(
+                "SELECT status, credit_limit FROM clients WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+            )
+            .optional()
+            .map_err(store_failure)?
+        else {
+            return Ok(None);
+        };
+
+        let mut statement = connection
+            .prepare("SELECT currency, available, held FROM client_balances WHERE client_id = ?1")
+            .map_err(store_failure)?;
+        let rows = statement
+            .query_map(params![id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .map_err(store_failure)?;
+
+        let mut client = Client {
+            status: parse_status(&status)?,
+            credit_limit: parse_decimal(&credit_limit)?,
+            ..Client::default()
+        };
+        for row in rows {
+            let (currency, available, held) = row.map_err(store_failure)?;
+            client
+                .balances
+                .insert(currency, parse_balance(&available, &held)?);
+        }
+
+        Ok(Some(client))
+    }
+
+    fn upsert_client(&mut self, id: ClientID, client: Client) -> Result<(), Error> {
+        let connection = lock(&self.connection)?;
+        connection
+            .execute(
+                "INSERT OR REPLACE INTO clients (id, status, credit_limit) VALUES (?1, ?2, ?3)",
+                params![id, client.status.as_str(), client.credit_limit.to_string()],
+            )
+            .map_err(store_failure)?;
+        for (currency, balance) in &client.balances {
+            connection
+                .execute(
+                    "INSERT OR REPLACE INTO client_balances (client_id, currency, available, held)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        id,
+                        currency,
+                        balance.available_amount.to_string(),
+                        balance.held_amount.to_string()
+                    ],
+                )
+                .map_err(store_failure)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_all(&self) -> Result<Vec<(ClientID, Client)>, Error> {
+        let connection = lock(&self.connection)?;
+        let mut statement = connection
+            .prepare(
+                "SELECT clients.id, clients.status, clients.credit_limit, client_balances.currency,
+                        client_balances.available, client_balances.held
+                 FROM clients LEFT JOIN client_balances ON client_balances.client_id = clients.id
+                 ORDER BY clients.id",
+            )
+            .map_err(store_failure)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, ClientID>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                ))
+            })
+            .map_err(store_failure)?;
+
+        let mut clients: Vec<(ClientID, Client)> = Vec::new();
+        for row in rows {
+            let (id, status, credit_limit, currency, available, held) =
+                row.map_err(store_failure)?;
+            if clients.last().is_none_or(|(last, _)| *last != id) {
+                let client = Client {
+                    status: parse_status(&status)?,
+                    credit_limit: parse_decimal(&credit_limit)?,
+                    ..Client::default()
+                };
+                clients.push((id, client));
+            }
+            if let (Some(currency), Some(available), Some(held), Some((_, client))) =
+                (currency, available, held, clients.last_mut())
+            {
+                client
+                    .balances
+                    .insert(currency, parse_balance(&available, &held)?);
+            }
+        }
+
+        Ok(clients)
+    }
+
+    fn save_status_change(&mut self, id: ClientID, change: StatusChange) -> Result<(), Error> {
+        lock(&self.connection)?
+            .execute(
+                "INSERT INTO client_status_changes (client_id, from_status, to_status, reason, operator)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    id,
+                    change.from.as_str(),
+                    change.to.as_str(),
+                    change.reason,
+                    change.operator
+                ],
+            )
+            .map_err(store_failure)?;
+
+        Ok(())
+    }
+
+    fn get_status_changes(&self, id: ClientID) -> Result<Vec<StatusChange>, Error> {
+        let connection = lock(&self.connection)?;
+        let mut statement = connection
+            .prepare(
+                "SELECT from_status, to_status, reason, operator FROM client_status_changes
+                 WHERE client_id = ?1 ORDER BY seq",
+            )
+            .map_err(store_failure)?;
+        let rows = statement
+            .query_map(params![id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })
+            .map_err(store_failure)?;
+
+        rows.map(|row| {
+            let (from, to, reason, operator) = row.map_err(store_failure)?;
+            Ok(StatusChange {
+                from: parse_status(&from)?,
+                to: parse_status(&to)?,
+                reason,
+                operator,
+            })
+        })
+        .collect()
+    }
+
+    fn begin(&mut self) -> Result<(), Error> {
+        execute(&self.connection, "SAVEPOINT clients")
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        execute(&self.connection, "RELEASE clients")
+    }
+
+    fn rollback(&mut self) -> Result<(), Error> {
+        execute(&self.connection, "ROLLBACK TO clients; RELEASE clients")
+    }
+}
+
+pub struct SqliteTransactions {
+    connection: Arc<Mutex<Connection>>,
+}
+
+// Columns of the transactions table read by `read_transaction`, in order
+const TRANSACTION_COLUMNS: &str = "kind, client_id, counterparty, currency, amount, fee, disputed_amount,
+    resolved_amount, charged_back_amount, captured_amount, released_amount, expires_at, sequence, dispute_count";
+
+// Raw column values of a transactions row: kind, client id, counterparty, currency, the seven amounts (amount, fee,
+// disputed, resolved, charged back, captured and released), expiry, sequence and dispute count
+type TransactionRow = (
+    String,
+    ClientID,
+    Option<ClientID>,
+    String,
+    [String; 7],
+    Option<u64>,
+    u64,
+    u32,
+);
+
+// Reads the `TRANSACTION_COLUMNS` of a row, starting at column `first`
+fn read_transaction(row: &rusqlite::Row<'_>, first: usize) -> rusqlite::Result<TransactionRow> {
+    Ok((
+        row.get(first)?,
+        row.get(first + 1)?,
+        row.get(first + 2)?,
+        row.get(first + 3)?,
+        [
+            row.get(first + 4)?,
+            row.get(first + 5)?,
+            row.get(first + 6)?,
+            row.get(first + 7)?,
+            row.get(first + 8)?,
+            row.get(first + 9)?,
+            row.get(first + 10)?,
+        ],
+        row.get(first + 11)?,
+        row.get(first + 12)?,
+        row.get(first + 13)?,
+    ))
+}
+
+fn parse_transaction(
+    (
+        kind,
+        client_id,
+        counterparty,
+        currency,
+        [amount, fee, disputed, resolved, charged_back, captured, released],
+        expires_at,
+        sequence,
+        dispute_count,
+    ): TransactionRow,
+) -> Result<Transaction, Error> {
+    Ok(Transaction {
+        kind: match kind.as_str() {
+            "deposit" => Kind::Deposit,
+            "withdrawal" => Kind::Withdrawal,
+            "transfer" => Kind::Transfer,
+            "authorization" => Kind::Authorization,
+            other => {
+                return Err(Error::StoreFailure(format!(
+                    "unknown transaction kind {other}"
+                )))
+            }
+        },
+        client_id,
+        counterparty,
+        currency,
+        amount: parse_decimal(&amount)?,
+        fee: parse_decimal(&fee)?,
+        disputed_amount: parse_decimal(&disputed)?,
+        resolved_amount: parse_decimal(&resolved)?,
+        charged_back_amount: parse_decimal(&charged_back)?,
+        captured_amount: parse_decimal(&captured)?,
+        released_amount: parse_decimal(&released)?,
+        expires_at,
+        sequence,
+        dispute_count,
+    })
+}
+
+impl TransactionStore for SqliteTransactions {
+    fn find_transaction(
+        &self,
+        transaction_id: TransactionID,
+    ) -> Result<Option<Transaction>, Error> {
+        lock(&self.connection)?
+            .query_row(
+                &format!("SELECT {TRANSACTION_COLUMNS} FROM transactions WHERE id = ?1"),
+                params![transaction_id],
+                |row| read_transaction(row, 0),
+            )
+            .optional()
+            .map_err(store_failure)?
+            .map(parse_transaction)
+            .transpose()
+    }
+
+    fn get_client_transactions(&self, client_id: ClientID) -> Result<Vec<Transaction>, Error> {
+        let connection = lock(&self.connection)?;
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT {TRANSACTION_COLUMNS} FROM transactions WHERE client_id = ?1 ORDER BY id"
+            ))
+            .map_err(store_failure)?;
+        let rows = statement
+            .query_map(params![client_id], |row| read_transaction(row, 0))
+            .map_err(store_failure)?;
+
+        rows.map(|row| parse_transaction(row.map_err(store_failure)?))
+            .collect()
+    }
+
+    fn get_all_transactions(&self) -> Result<Vec<(TransactionID, Transaction)>, Error> {
+        let connection = lock(&self.connection)?;
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT id, {TRANSACTION_COLUMNS} FROM transactions ORDER BY id"
+            ))
+            .map_err(store_failure)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, TransactionID>(0)?, read_transaction(row, 1)?))
+            })
+            .map_err(store_failure)?;
+
+        rows.map(|row| {
+            let (transaction_id, transaction) = row.map_err(store_failure)?;
+            Ok((transaction_id, parse_transaction(transaction)?))
+        })
+        .collect()
+    }
+
+    fn upsert_transaction(
+        &mut self,
+        transaction_id: TransactionID,
+        transaction: Transaction,
+    ) -> Result<(), Error> {
+        let kind = match transaction.kind {
+            Kind::Deposit => "deposit",
+            Kind::Withdrawal => "withdrawal",
+            Kind::Transfer => "transfer",
+            Kind::Authorization => "authorization",
+        };
+        lock(&self.connection)?
+            .execute(
+                "INSERT OR REPLACE INTO transactions
+                 (id, kind, client_id, counterparty, currency, amount, fee, disputed_amount, resolved_amount,
+                  charged_back_amount, captured_amount, released_amount, expires_at, sequence, dispute_count)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+                params![
+                    transaction_id,
+                    kind,
+                    transaction.client_id,
+                    transaction.counterparty,
+                    transaction.currency,
+                    transaction.amount.to_string(),
+                    transaction.fee.to_string(),
+                    transaction.disputed_amount.to_string(),
+                    transaction.resolved_amount.to_string(),
+                    transaction.charged_back_amount.to_string(),
+                    transaction.captured_amount.to_string(),
+                    transaction.released_amount.to_string(),
+                    transaction.expires_at,
+                    transaction.sequence,
+                    transaction.dispute_count,
+                ],
+            )
+            .map_err(store_failure)?;
+
+        Ok(())
+    }
+
+    fn evict_transaction(&mut self, transaction_id: TransactionID) -> Result<(), Error> {
+        let connection = lock(&self.connection)?;
+        connection
+            .execute(
+                "DELETE FROM transactions WHERE id = ?1",
+                params![transaction_id],
+            )
+            .map_err(store_failure)?;
+        connection
+            .execute(
+                "INSERT OR IGNORE INTO evicted_transactions (id) VALUES (?1)",
+                params![transaction_id],
+            )
+            .map_err(store_failure)?;
+
+        Ok(())
+    }
+
+    fn get_evicted_ids(&self) -> Result<Vec<TransactionID>, Error> {
+        let connection = lock(&self.connection)?;
+        let mut statement = connection
+            .prepare("SELECT id FROM evicted_transactions ORDER BY id")
+            .map_err(store_failure)?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, TransactionID>(0))
+            .map_err(store_failure)?;
+
+        rows.map(|row| row.map_err(store_failure)).collect()
+    }
+
+    fn is_evicted(&self, transaction_id: TransactionID) -> Result<bool, Error> {
+        lock(&self.connection)?
+            .query_row(
+                "SELECT COUNT(*) FROM evicted_transactions WHERE id = ?1",
+                params![transaction_id],
+                |row| row.get::<_, i64>(0),
+            )
+            .map(|count| count > 0)
+            .map_err(store_failure)
+    }
+
+    fn get_record_counts(&self) -> Result<(u64, u64), Error> {
+        // The counts are saved with every accepted record, so a store without them is empty
+        let counts = lock(&self.connection)?
+            .query_row(
+                "SELECT handled, accepted FROM record_counts WHERE id = 0",
+                [],
+                |row| Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?)),
+            )
+            .optional()
+            .map_err(store_failure)?;
+        Ok(counts.unwrap_or_default())
+    }
+
+    fn save_record_counts(&mut self, handled: u64, accepted: u64) -> Result<(), Error> {
+        lock(&self.connection)?
+            .execute(
+                "INSERT OR REPLACE INTO record_counts (id, handled, accepted) VALUES (0, ?1, ?2)",
+                params![handled, accepted],
+            )
+            .map_err(store_failure)?;
+
+        Ok(())
+    }
+
+    fn begin(&mut self) -> Result<(), Error> {
+        execute(&self.connection, "SAVEPOINT transactions")
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        execute(&self.connection, "RELEASE transactions")
+    }
+
+    fn rollback(&mut self) -> Result<(), Error> {
+        execute(
+            &self.connection,
+            "ROLLBACK TO transactions; RELEASE transactions",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dtos::{TransactionRecord, TransactionType},
+        engine::{Config, Engine},
+    };
+
+    use rust_decimal_macros::dec;
+    use std::{env, fs, path::PathBuf};
+
+    fn temp_database(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "transaction-action-{}-{name}.sqlite",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_state_survives_reopen() -> Result<(), Error> {
+        let path = temp_database("reopen");
+        let client_id = 7;
+
+        {
+            let (clients, transactions) = open(&path)?;
+            let mut engine = Engine::new(clients, transactions)?;
+            engine.handle(&TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client_id,
+                transaction_id: 1,
+                amount: Some(dec!(10.5)),
+                ..TransactionRecord::default()
+            })?;
+            engine.handle(&TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client_id,
+                transaction_id: 2,
+                amount: Some(dec!(2)),
+                ..TransactionRecord::default()
+            })?;
+            engine.handle(&TransactionRecord {
+                transaction_type: TransactionType::Dispute,
+                client_id,
+                transaction_id: 2,
+                amount: None,
+                ..TransactionRecord::default()
+            })?;
+            engine.handle(&TransactionRecord {
+                transaction_type: TransactionType::Transfer,
+                client_id,
+                transaction_id: 3,
+                amount: Some(dec!(0.5)),
+                to_client_id: Some(8),
+                ..TransactionRecord::default()
+            })?;
+        }
+
+        let (clients, transactions) = open(&path)?;
+        assert_eq!(
+            clients.find_client(client_id)?,
+            Some(Client {
+                balances: [(
+                    String::new(),
+                    Balance {
+                        available_amount: dec!(10),
+                        held_amount: dec!(2),
+                    }
+                )]
+                .into(),
+                status: Status::Active,
+                ..Client::default()
+            }),
+            "balances should be persisted"
+        );
+        assert_eq!(
+            transactions.find_transaction(3)?,
+            Some(Transaction {
+                sequence: 4,
+                ..Transaction::new(Kind::Transfer, client_id, dec!(0.5)).with_counterparty(8)
+            }),
+            "transfers should keep their destination client"
+        );
+        assert_eq!(
+            transactions.find_transaction(2)?,
+            Some(Transaction {
+                disputed_amount: dec!(2),
+                sequence: 2,
+                dispute_count: 1,
+                ..Transaction::new(Kind::Deposit, client_id, dec!(2))
+            }),
+            "disputed state should be persisted"
+        );
+        assert_eq!(
+            transactions
+                .get_client_transactions(client_id)?
+                .iter()
+                .map(|transaction| transaction.amount)
+                .collect::<Vec<_>>(),
+            vec![dec!(10.5), dec!(2), dec!(0.5)],
+            "client history should be listed in id order"
+        );
+
+        let mut engine = Engine::new(clients, transactions)?;
+        assert_eq!(
+            engine.handle(&TransactionRecord {
+                transaction_type: TransactionType::Deposit,
+                client_id,
+                transaction_id: 1,
+                amount: Some(dec!(1)),
+                ..TransactionRecord::default()
+            }),
+            Err(Error::TransactionIdAlreadyExists(1)),
+            "transaction ids should be remembered across restarts"
+        );
+
+        // A snapshot of the database can be restored into the in-memory stores
+        let mut restored = Engine::default();
+        restored.restore(engine.snapshot()?)?;
+        assert_eq!(restored.snapshot()?, engine.snapshot()?);
+
+        fs::remove_file(&path).map_err(|e| Error::StoreFailure(e.to_string()))
+    }
+
+    #[test]
+    fn test_holds_expire_after_reopen() -> Result<(), Error> {
+        let path = temp_database("holds");
+        let config = Config {
+            hold_expiry: Some(1),
+            ..Config::default()
+        };
+        let record = |transaction_type, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount: Some(amount),
+            ..TransactionRecord::default()
+        };
+
+        {
+            let (clients, transactions) = open(&path)?;
+            let mut engine = Engine::new(clients, transactions)?.with_config(config.clone())?;
+            engine.handle(&record(TransactionType::Deposit, 1, dec!(10)))?;
+            engine.handle(&record(TransactionType::Authorize, 2, dec!(4)))?;
+            assert_eq!(
+                engine.handle(&record(TransactionType::Deposit, 1, dec!(1))),
+                Err(Error::TransactionIdAlreadyExists(1))
+            );
+        }
+
+        let (clients, transactions) = open(&path)?;
+        let mut engine = Engine::new(clients, transactions)?.with_config(config)?;
+        assert_eq!(
+            engine.record_counts(),
+            (2, 2),
+            "record numbers should continue from the last accepted record"
+        );
+        engine.handle(&record(TransactionType::Deposit, 3, dec!(1)))?;
+        let (clients, transactions) = engine.stores();
+        assert_eq!(
+            clients.find_client(1)?.map(|client| client.balance("")),
+            Some(Balance {
+                available_amount: dec!(11),
+                held_amount: dec!(0),
+            }),
+            "the hold should expire with the first accepted record after the restart"
+        );
+        assert_eq!(
+            transactions
+                .find_transaction(3)?
+                .map(|transaction| transaction.sequence),
+            Some(3),
+            "new transactions should not reuse the sequence numbers of the previous run"
+        );
+
+        fs::remove_file(&path).map_err(|e| Error::StoreFailure(e.to_string()))
+    }
+
+    #[test]
+    fn test_dispute_window_after_reopen() -> Result<(), Error> {
+        let path = temp_database("window");
+        let config = Config {
+            dispute_window: Some(2),
+            ..Config::default()
+        };
+        let record = |transaction_type, transaction_id, amount| TransactionRecord {
+            transaction_type,
+            client_id: 1,
+            transaction_id,
+            amount,
+            ..TransactionRecord::default()
+        };
+
+        {
+            let (clients, transactions) = open(&path)?;
+            let mut engine = Engine::new(clients, transactions)?.with_config(config.clone())?;
+            engine.handle(&record(TransactionType::Deposit, 1, Some(dec!(10))))?;
+            engine.handle(&record(TransactionType::Deposit, 2, Some(dec!(20))))?;
+        }
+
+        // Transaction 1 leaves the window with the third accepted record, the first one after the restart
+        let (clients, transactions) = open(&path)?;
+        let mut engine = Engine::new(clients, transactions)?.with_config(config)?;
+        engine.handle(&record(TransactionType::Deposit, 3, Some(dec!(30))))?;
+        assert_eq!(engine.stores().1.get_evicted_ids()?, vec![1]);
+        assert_eq!(
+            engine.handle(&record(TransactionType::Dispute, 1, None)),
+            Err(Error::DisputeWindowExpired(1))
+        );
+        engine.handle(&record(TransactionType::Dispute, 2, None))?;
+
+        fs::remove_file(&path).map_err(|e| Error::StoreFailure(e.to_string()))
+    }
+
+    #[test]
+    fn test_evicted_ids_survive_reopen() -> Result<(), Error> {
+        let path = temp_database("evict");
+        {
+            let (_, mut transactions) = open(&path)?;
+            transactions.save_new_transaction(1, Transaction::new(Kind::Deposit, 1, dec!(3)))?;
+            transactions.save_new_transaction(2, Transaction::new(Kind::Deposit, 1, dec!(4)))?;
+
+            transactions.begin()?;
+            transactions.evict_transaction(1)?;
+            transactions.rollback()?;
+            assert!(!transactions.is_evicted(1)?, "evictions should roll back");
+
+            transactions.evict_transaction(1)?;
+        }
+
+        let (_, transactions) = open(&path)?;
+        assert_eq!(transactions.find_transaction(1)?, None);
+        assert_eq!(transactions.get_evicted_ids()?, vec![1]);
+        assert_eq!(
+            transactions.get_transaction(1, 1),
+            Err(Error::DisputeWindowExpired(1))
+        );
+        assert!(transactions.has_id(1)?, "evicted ids should not be reused");
+        assert_eq!(transactions.get_all_transactions()?.len(), 1);
+
+        fs::remove_file(&path).map_err(|e| Error::StoreFailure(e.to_string()))
+    }
+
+    #[test]
+    fn test_rollback_discards_changes() -> Result<(), Error> {
+        let (mut clients, mut transactions) = open_in_memory()?;
+        let client_id = 3;
+
+        clients.begin()?;
+        transactions.begin()?;
+        clients.deposit(client_id, "", dec!(5))?;
+        transactions
+            .save_new_transaction(10, Transaction::new(Kind::Deposit, client_id, dec!(5)))?;
+        transactions.commit()?;
+        clients.commit()?;
+
+        clients.begin()?;
+        transactions.begin()?;
+        clients.withdrawal(client_id, "", dec!(1))?;
+        transactions
+            .save_new_transaction(11, Transaction::new(Kind::Withdrawal, client_id, dec!(1)))?;
+        transactions.rollback()?;
+        clients.rollback()?;
+
+        assert_eq!(
+            clients
+                .find_client(client_id)?
+                .map(|c| c.balance("").available_amount),
+            Some(dec!(5)),
+            "rolled back withdrawal should not change the balance"
+        );
+        assert!(
+            !transactions.has_id(11)?,
+            "rolled back transaction should not be saved"
+        );
+        assert!(
+            transactions.has_id(10)?,
+            "committed transaction should be kept"
+        );
+
+        Ok(())
+    }
+}
@@ -1,51 +1,280 @@
 use crate::{errors::Error, ClientID, TransactionID};
 use rust_decimal::Decimal;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 // DAO (representation of what would be our Transactions table in the database)
-#[derive(Debug)]
-pub(crate) struct Transaction {
-    pub(crate) kind: Kind,
-    pub(crate) client_id: ClientID,
-    pub(crate) amount: Decimal,
-    pub(crate) disputed: bool,
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Transaction {
+    pub kind: Kind,
+    /// Client the transaction belongs to, the source client of transfers
+    pub client_id: ClientID,
+    /// Destination client of transfers
+    pub counterparty: Option<ClientID>,
+    /// Currency of `amount`, empty for records without a currency
+    pub currency: String,
+    pub amount: Decimal,
+    /// Fee charged on top of `amount`, see `FeeSchedule`
+    pub fee: Decimal,
+    /// Part of `amount` currently under dispute
+    pub disputed_amount: Decimal,
+    /// Total amount released by resolves. Resolved funds can be disputed again.
+    pub resolved_amount: Decimal,
+    /// Total amount reversed by chargebacks
+    pub charged_back_amount: Decimal,
+    /// Part of an authorization hold settled by captures
+    pub captured_amount: Decimal,
+    /// Part of an authorization hold released by a void or by expiring
+    pub released_amount: Decimal,
+    /// Number of accepted records after which an authorization hold expires, see `Config::hold_expiry`
+    pub expires_at: Option<u64>,
+    /// Number of accepted records when the transaction was saved, the record that created it included
+    pub sequence: u64,
+    /// Number of accepted disputes of the transaction
+    pub dispute_count: u32,
 }
 
-#[derive(PartialEq, Eq, Debug)]
-pub(crate) enum Kind {
+impl Transaction {
+    /// Creates an undisputed transaction without a currency
+    #[must_use]
+    pub fn new(kind: Kind, client_id: ClientID, amount: Decimal) -> Self {
+        Self {
+            kind,
+            client_id,
+            counterparty: None,
+            currency: String::new(),
+            amount,
+            fee: Decimal::ZERO,
+            disputed_amount: Decimal::ZERO,
+            resolved_amount: Decimal::ZERO,
+            charged_back_amount: Decimal::ZERO,
+            captured_amount: Decimal::ZERO,
+            released_amount: Decimal::ZERO,
+            expires_at: None,
+            sequence: 0,
+            dispute_count: 0,
+        }
+    }
+
+    /// Sets the currency of the transaction
+    #[must_use]
+    pub fn with_currency(mut self, currency: impl Into<String>) -> Self {
+        self.currency = currency.into();
+        self
+    }
+
+    /// Sets the destination client of a transfer
+    #[must_use]
+    pub fn with_counterparty(mut self, counterparty: ClientID) -> Self {
+        self.counterparty = Some(counterparty);
+        self
+    }
+
+    /// Sets the fee charged on the transaction
+    #[must_use]
+    pub fn with_fee(mut self, fee: Decimal) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    /// Returns true if some of the transaction amount is under dispute
+    #[must_use]
+    pub fn disputed(&self) -> bool {
+        self.disputed_amount > Decimal::ZERO
+    }
+
+    /// Amount the transaction moved for its client. Deposits only credit what is left after their fee.
+    #[must_use]
+    pub fn net_amount(&self) -> Decimal {
+        match self.kind {
+            Kind::Deposit => self.amount - self.fee,
+            Kind::Withdrawal | Kind::Transfer | Kind::Authorization => self.amount,
+        }
+    }
+
+    /// Part of the net amount that is neither under dispute nor charged back
+    #[must_use]
+    pub fn disputable_amount(&self) -> Decimal {
+        self.net_amount() - self.disputed_amount - self.charged_back_amount
+    }
+
+    /// Part of an authorization hold that is still held, neither captured nor released
+    #[must_use]
+    pub fn open_hold_amount(&self) -> Decimal {
+        self.amount - self.captured_amount - self.released_amount
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
     Deposit,
     Withdrawal,
+    /// Funds moved from `client_id` to `counterparty`
+    Transfer,
+    /// Funds held by an `authorize` record until they are captured or released
+    Authorization,
 }
 
-// In a proper implementation, the Transactions store would connect to a database instead of being an in-memory store
-#[derive(Default)]
-pub(crate) struct Transactions {
-    database: HashMap<TransactionID, Transaction>,
-}
+/// Storage backend for the transactions created by records (deposits, withdrawals, transfers and authorization holds),
+/// along with the ids of the transactions evicted after their dispute window.
+///
+/// Implementors only need to provide the `find_transaction`, `upsert_transaction`, `get_client_transactions`,
+/// `get_all_transactions`, `evict_transaction`, `get_evicted_ids` and `is_evicted` primitives and the `begin`,
+/// `commit` and `rollback` transaction hooks, the lookups used by the `Engine` are built on top of them. `rollback`
+/// has to discard everything written since `begin` for a record to be undone when the storage fails partway through
+/// it.
+pub trait TransactionStore {
+    /// Looks up a transaction by id
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn find_transaction(&self, transaction_id: TransactionID)
+        -> Result<Option<Transaction>, Error>;
 
-impl Transactions {
-    pub(crate) fn has_id(&self, transaction_id: TransactionID) -> bool {
-        self.database.contains_key(&transaction_id)
+    /// Inserts or replaces the transaction with the given id
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn upsert_transaction(
+        &mut self,
+        transaction_id: TransactionID,
+        transaction: Transaction,
+    ) -> Result<(), Error>;
+
+    /// Every transaction of the given client, ordered by id. Rules read it for most records, so stores should look the
+    /// transactions up by client rather than scan them all.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn get_client_transactions(&self, client_id: ClientID) -> Result<Vec<Transaction>, Error>;
+
+    /// Every transaction, ordered by id
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn get_all_transactions(&self) -> Result<Vec<(TransactionID, Transaction)>, Error>;
+
+    /// Drops a transaction that can no longer be disputed, only keeping its id so it is never reused. Evicting an id
+    /// that is not stored just records it as evicted.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn evict_transaction(&mut self, transaction_id: TransactionID) -> Result<(), Error>;
+
+    /// Ids of every evicted transaction, in order
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn get_evicted_ids(&self) -> Result<Vec<TransactionID>, Error>;
+
+    /// Returns true if the transaction with the given id was evicted. Looked up for most records, so it should not
+    /// go through `get_evicted_ids`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn is_evicted(&self, transaction_id: TransactionID) -> Result<bool, Error>;
+
+    /// Numbers of records handled and accepted as of the last accepted record, as saved by `save_record_counts`. Stores
+    /// that do not save them derive both from the highest sequence number of the stored transactions, which misses the
+    /// records accepted after the last saved transaction.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn get_record_counts(&self) -> Result<(u64, u64), Error> {
+        let sequence = self
+            .get_all_transactions()?
+            .iter()
+            .map(|(_, transaction)| transaction.sequence)
+            .max()
+            .unwrap_or_default();
+        Ok((sequence, sequence))
+    }
+
+    /// Called by the `Engine` with its record counts before committing an accepted record, so a store that outlives the
+    /// process can hand them back to the next engine built over it. Does nothing by default.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn save_record_counts(&mut self, _handled: u64, _accepted: u64) -> Result<(), Error> {
+        Ok(())
     }
 
-    pub(crate) fn save_new_transaction(
+    /// Called by the `Engine` before processing a record, starting a transaction (or savepoint) that `commit` or
+    /// `rollback` ends
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn begin(&mut self) -> Result<(), Error>;
+
+    /// Called by the `Engine` once a record has been successfully processed, keeping every change made since `begin`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn commit(&mut self) -> Result<(), Error>;
+
+    /// Called by the `Engine` when processing a record failed, discarding every change made since `begin`. A record
+    /// can write several times before it fails, so this has to restore the store as it was at `begin`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn rollback(&mut self) -> Result<(), Error>;
+
+    /// Returns true if a transaction with the given id has already been saved, evicted transactions included
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the underlying storage fails
+    fn has_id(&self, transaction_id: TransactionID) -> Result<bool, Error> {
+        Ok(self.find_transaction(transaction_id)?.is_some() || self.is_evicted(transaction_id)?)
+    }
+
+    /// Saves a transaction whose id has not been seen before
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the transaction id already exists
+    fn save_new_transaction(
         &mut self,
         transaction_id: TransactionID,
         transaction: Transaction,
-    ) {
-        let prev = self.database.insert(transaction_id, transaction);
-        assert!(prev.is_none());
+    ) -> Result<(), Error> {
+        if self.has_id(transaction_id)? {
+            return Err(Error::TransactionIdAlreadyExists(transaction_id));
+        }
+
+        self.upsert_transaction(transaction_id, transaction)
     }
 
-    pub(crate) fn get_mut_transaction(
-        &mut self,
+    /// Fetches the transaction with the given id, making sure it belongs to the given client
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the transaction does not exist, was evicted or belongs to another client
+    fn get_transaction(
+        &self,
         transaction_id: TransactionID,
         client_id: ClientID,
-    ) -> Result<&mut Transaction, Error> {
-        let transaction = self
-            .database
-            .get_mut(&transaction_id)
-            .ok_or(Error::TransactionNotExists(transaction_id))?;
+    ) -> Result<Transaction, Error> {
+        let Some(transaction) = self.find_transaction(transaction_id)? else {
+            return Err(if self.is_evicted(transaction_id)? {
+                Error::DisputeWindowExpired(transaction_id)
+            } else {
+                Error::TransactionNotExists(transaction_id)
+            });
+        };
         if transaction.client_id != client_id {
             return Err(Error::TransactionWithWrongClientId(
                 transaction_id,
@@ -57,6 +286,187 @@ impl Transactions {
     }
 }
 
+// Disjoint ranges of ids, from the first id of each range to its last. Transactions are evicted about in the order of
+// their ids, so the evicted ids collapse into a few ranges and take memory for the gaps between them rather than for
+// every id.
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
+struct IdRanges(BTreeMap<TransactionID, TransactionID>);
+
+impl IdRanges {
+    // Range holding the id, if any
+    fn range_of(&self, id: TransactionID) -> Option<(TransactionID, TransactionID)> {
+        self.0
+            .range(..=id)
+            .next_back()
+            .map(|(start, end)| (*start, *end))
+            .filter(|(_, end)| id <= *end)
+    }
+
+    fn contains(&self, id: TransactionID) -> bool {
+        self.range_of(id).is_some()
+    }
+
+    fn insert(&mut self, id: TransactionID) {
+        if self.contains(id) {
+            return;
+        }
+        // Joins the ranges ending right before and starting right after the id
+        let start = id
+            .checked_sub(1)
+            .and_then(|previous| self.range_of(previous))
+            .map_or(id, |(start, _)| start);
+        let end = id
+            .checked_add(1)
+            .and_then(|next| self.0.remove(&next))
+            .unwrap_or(id);
+        self.0.insert(start, end);
+    }
+
+    fn remove(&mut self, id: TransactionID) {
+        let Some((start, end)) = self.range_of(id) else {
+            return;
+        };
+        self.0.remove(&start);
+        if start < id {
+            self.0.insert(start, id - 1);
+        }
+        if id < end {
+            self.0.insert(id + 1, end);
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = TransactionID> + '_ {
+        self.0.iter().flat_map(|(start, end)| *start..=*end)
+    }
+}
+
+// In a proper implementation, the Transactions store would connect to a database instead of being an in-memory store
+#[derive(Default, Clone)]
+pub struct Transactions {
+    pub(crate) database: HashMap<TransactionID, Transaction>,
+    // Ids of the stored transactions of each client, so the history of a client is read without scanning `database`
+    by_client: HashMap<ClientID, BTreeSet<TransactionID>>,
+    // Ids of evicted transactions, kept as ranges of consecutive ids instead of whole transactions
+    evicted: IdRanges,
+    // Original state of every transaction touched since `begin`, restored on `rollback`
+    savepoint: Option<HashMap<TransactionID, Option<Transaction>>>,
+}
+
+impl Transactions {
+    // Every write to `database` goes through `insert` and `remove` so `by_client` stays in step with it
+    pub(crate) fn insert(&mut self, transaction_id: TransactionID, transaction: Transaction) {
+        let client_id = transaction.client_id;
+        self.by_client
+            .entry(client_id)
+            .or_default()
+            .insert(transaction_id);
+        if let Some(previous) = self.database.insert(transaction_id, transaction) {
+            if previous.client_id != client_id {
+                self.unindex(transaction_id, previous.client_id);
+            }
+        }
+    }
+
+    fn remove(&mut self, transaction_id: TransactionID) {
+        if let Some(transaction) = self.database.remove(&transaction_id) {
+            self.unindex(transaction_id, transaction.client_id);
+        }
+    }
+
+    fn unindex(&mut self, transaction_id: TransactionID, client_id: ClientID) {
+        if let Some(ids) = self.by_client.get_mut(&client_id) {
+            ids.remove(&transaction_id);
+            if ids.is_empty() {
+                self.by_client.remove(&client_id);
+            }
+        }
+    }
+
+    fn save_original(&mut self, transaction_id: TransactionID) {
+        if let Some(savepoint) = &mut self.savepoint {
+            savepoint
+                .entry(transaction_id)
+                .or_insert_with(|| self.database.get(&transaction_id).cloned());
+        }
+    }
+}
+
+impl TransactionStore for Transactions {
+    fn find_transaction(
+        &self,
+        transaction_id: TransactionID,
+    ) -> Result<Option<Transaction>, Error> {
+        Ok(self.database.get(&transaction_id).cloned())
+    }
+
+    fn upsert_transaction(
+        &mut self,
+        transaction_id: TransactionID,
+        transaction: Transaction,
+    ) -> Result<(), Error> {
+        self.save_original(transaction_id);
+        self.insert(transaction_id, transaction);
+        Ok(())
+    }
+
+    fn get_client_transactions(&self, client_id: ClientID) -> Result<Vec<Transaction>, Error> {
+        Ok(self
+            .by_client
+            .get(&client_id)
+            .into_iter()
+            .flatten()
+            .map(|transaction_id| self.database[transaction_id].clone())
+            .collect())
+    }
+
+    fn get_all_transactions(&self) -> Result<Vec<(TransactionID, Transaction)>, Error> {
+        let mut transactions: Vec<_> = self
+            .database
+            .iter()
+            .map(|(transaction_id, transaction)| (*transaction_id, transaction.clone()))
+            .collect();
+        transactions.sort_unstable_by_key(|(transaction_id, _)| *transaction_id);
+        Ok(transactions)
+    }
+
+    fn evict_transaction(&mut self, transaction_id: TransactionID) -> Result<(), Error> {
+        self.save_original(transaction_id);
+        self.remove(transaction_id);
+        self.evicted.insert(transaction_id);
+        Ok(())
+    }
+
+    fn get_evicted_ids(&self) -> Result<Vec<TransactionID>, Error> {
+        Ok(self.evicted.iter().collect())
+    }
+
+    fn is_evicted(&self, transaction_id: TransactionID) -> Result<bool, Error> {
+        Ok(self.evicted.contains(transaction_id))
+    }
+
+    fn begin(&mut self) -> Result<(), Error> {
+        self.savepoint = Some(HashMap::new());
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        self.savepoint = None;
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), Error> {
+        for (transaction_id, transaction) in self.savepoint.take().unwrap_or_default() {
+            // Transactions are only ever evicted once, so an evicted id touched since `begin` was evicted by it
+            self.evicted.remove(transaction_id);
+            match transaction {
+                Some(transaction) => self.insert(transaction_id, transaction),
+                None => self.remove(transaction_id),
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -69,22 +479,20 @@ mod tests {
 
         let transaction_id = 445;
         let other_transaction_id = 446;
-        transactions.database.insert(
+        transactions.insert(
             transaction_id,
             Transaction {
-                kind: Kind::Withdrawal,
-                client_id: 4,
-                amount: dec!(12),
-                disputed: true,
+                disputed_amount: dec!(12),
+                ..Transaction::new(Kind::Withdrawal, 4, dec!(12))
             },
         );
 
-        assert!(transactions.has_id(transaction_id));
-        assert!(!transactions.has_id(other_transaction_id));
+        assert!(transactions.has_id(transaction_id).unwrap());
+        assert!(!transactions.has_id(other_transaction_id).unwrap());
     }
 
     #[test]
-    fn test_save_new_transaction() {
+    fn test_save_new_transaction() -> Result<(), Error> {
         let mut transactions = Transactions::default();
         assert_eq!(
             transactions.database.len(),
@@ -98,13 +506,8 @@ mod tests {
         // Should save new transaction
         transactions.save_new_transaction(
             transaction_id,
-            Transaction {
-                kind: Kind::Deposit,
-                client_id,
-                amount: dec!(45),
-                disputed: false,
-            },
-        );
+            Transaction::new(Kind::Deposit, client_id, dec!(45)),
+        )?;
         assert_eq!(
             transactions.database.len(),
             1,
@@ -115,10 +518,27 @@ mod tests {
             dec!(45),
             "transaction amount should match"
         );
+
+        // Should refuse to overwrite an existing transaction
+        assert_eq!(
+            transactions.save_new_transaction(
+                transaction_id,
+                Transaction::new(Kind::Withdrawal, client_id, dec!(1)),
+            ),
+            Err(Error::TransactionIdAlreadyExists(transaction_id)),
+            "transaction ids should not be reused"
+        );
+        assert_eq!(
+            transactions.database[&transaction_id].amount,
+            dec!(45),
+            "existing transaction should be untouched"
+        );
+
+        Ok(())
     }
 
     #[test]
-    fn test_get_mut_transaction() -> Result<(), Error> {
+    fn test_get_transaction() -> Result<(), Error> {
         let mut transactions = Transactions::default();
 
         let transaction_id = 445;
@@ -128,34 +548,31 @@ mod tests {
         transactions.database.insert(
             transaction_id,
             Transaction {
-                kind: Kind::Withdrawal,
-                client_id,
-                amount: dec!(12),
-                disputed: true,
+                disputed_amount: dec!(12),
+                ..Transaction::new(Kind::Withdrawal, client_id, dec!(12))
             },
         );
 
         // Should successfully get transaction with matching transaction id and client id
-        let transaction = transactions.get_mut_transaction(transaction_id, client_id)?;
+        let transaction = transactions.get_transaction(transaction_id, client_id)?;
         assert_eq!(transaction.client_id, client_id, "client id should match");
         assert_eq!(
             transaction.amount,
             dec!(12),
             "transaction amount should match"
         );
-        transaction.disputed = true;
 
         // Error cases
         assert_eq!(
             transactions
-                .get_mut_transaction(other_transaction_id, client_id)
+                .get_transaction(other_transaction_id, client_id)
                 .unwrap_err(),
             Error::TransactionNotExists(other_transaction_id),
             "unrecognized transaction id should be an error"
         );
         assert_eq!(
             transactions
-                .get_mut_transaction(transaction_id, other_client_id)
+                .get_transaction(transaction_id, other_client_id)
                 .unwrap_err(),
             Error::TransactionWithWrongClientId(transaction_id, other_client_id),
             "transaction id with mismatched client id should be an error"
@@ -163,4 +580,163 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_get_client_transactions() -> Result<(), Error> {
+        let mut transactions = Transactions::default();
+        transactions.save_new_transaction(3, Transaction::new(Kind::Deposit, 1, dec!(3)))?;
+        transactions.save_new_transaction(1, Transaction::new(Kind::Withdrawal, 1, dec!(1)))?;
+        transactions.save_new_transaction(2, Transaction::new(Kind::Deposit, 2, dec!(2)))?;
+
+        assert_eq!(
+            transactions.get_client_transactions(1)?,
+            vec![
+                Transaction::new(Kind::Withdrawal, 1, dec!(1)),
+                Transaction::new(Kind::Deposit, 1, dec!(3)),
+            ],
+            "only the client transactions should be listed, in id order"
+        );
+        assert!(transactions.get_client_transactions(3)?.is_empty());
+
+        // The per client index follows evictions and rollbacks
+        transactions.begin()?;
+        transactions.evict_transaction(1)?;
+        transactions.save_new_transaction(4, Transaction::new(Kind::Deposit, 1, dec!(4)))?;
+        assert_eq!(
+            transactions.get_client_transactions(1)?,
+            vec![
+                Transaction::new(Kind::Deposit, 1, dec!(3)),
+                Transaction::new(Kind::Deposit, 1, dec!(4)),
+            ]
+        );
+        transactions.rollback()?;
+        assert_eq!(
+            transactions.get_client_transactions(1)?,
+            vec![
+                Transaction::new(Kind::Withdrawal, 1, dec!(1)),
+                Transaction::new(Kind::Deposit, 1, dec!(3)),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_transaction() -> Result<(), Error> {
+        let mut transactions = Transactions::default();
+        transactions.save_new_transaction(1, Transaction::new(Kind::Deposit, 1, dec!(10)))?;
+        transactions.save_new_transaction(2, Transaction::new(Kind::Deposit, 1, dec!(5)))?;
+
+        transactions.begin()?;
+        transactions.evict_transaction(1)?;
+        transactions.rollback()?;
+        assert!(
+            transactions.database.contains_key(&1) && !transactions.is_evicted(1)?,
+            "rolled back evictions should restore the transaction"
+        );
+
+        transactions.evict_transaction(1)?;
+        assert_eq!(transactions.find_transaction(1)?, None);
+        assert!(transactions.has_id(1)?, "evicted ids should not be reused");
+        assert_eq!(
+            transactions.get_transaction(1, 1),
+            Err(Error::DisputeWindowExpired(1))
+        );
+        assert_eq!(
+            transactions.save_new_transaction(1, Transaction::new(Kind::Deposit, 1, dec!(1))),
+            Err(Error::TransactionIdAlreadyExists(1))
+        );
+        assert_eq!(transactions.get_evicted_ids()?, vec![1]);
+        assert_eq!(
+            transactions.get_all_transactions()?,
+            vec![(2, Transaction::new(Kind::Deposit, 1, dec!(5)))]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_evicted_id_ranges() {
+        let mut evicted = IdRanges::default();
+        for id in [3, 1, 2, 5, u32::MAX, 0] {
+            evicted.insert(id);
+        }
+        assert_eq!(
+            evicted,
+            IdRanges(BTreeMap::from([(0, 3), (5, 5), (u32::MAX, u32::MAX)])),
+            "consecutive ids should share a range"
+        );
+        evicted.insert(4);
+        assert_eq!(
+            evicted,
+            IdRanges(BTreeMap::from([(0, 5), (u32::MAX, u32::MAX)]))
+        );
+
+        evicted.remove(2);
+        assert!(!evicted.contains(2) && evicted.contains(1) && evicted.contains(3));
+        assert_eq!(
+            evicted.iter().collect::<Vec<_>>(),
+            vec![0, 1, 3, 4, 5, u32::MAX]
+        );
+    }
+
+    #[test]
+    fn test_disputable_amount() {
+        let mut transaction = Transaction::new(Kind::Deposit, 1, dec!(100));
+        assert!(!transaction.disputed(), "new transaction is not disputed");
+        assert_eq!(transaction.disputable_amount(), dec!(100));
+
+        transaction.disputed_amount = dec!(30);
+        transaction.charged_back_amount = dec!(20);
+        transaction.resolved_amount = dec!(45);
+        assert!(transaction.disputed(), "transaction is partially disputed");
+        assert_eq!(
+            transaction.disputable_amount(),
+            dec!(50),
+            "disputed and charged back amounts cannot be disputed, resolved amounts can"
+        );
+
+        let transaction = Transaction::new(Kind::Deposit, 1, dec!(100)).with_fee(dec!(2));
+        assert_eq!(
+            transaction.disputable_amount(),
+            dec!(98),
+            "the fee of a deposit was never credited, so it cannot be disputed"
+        );
+        let transaction = Transaction::new(Kind::Withdrawal, 1, dec!(100)).with_fee(dec!(2));
+        assert_eq!(transaction.disputable_amount(), dec!(100));
+    }
+
+    #[test]
+    fn test_rollback() -> Result<(), Error> {
+        let mut transactions = Transactions::default();
+        transactions.save_new_transaction(1, Transaction::new(Kind::Deposit, 1, dec!(10)))?;
+
+        transactions.begin()?;
+        let mut transaction = transactions.get_transaction(1, 1)?;
+        transaction.disputed_amount = dec!(10);
+        transactions.upsert_transaction(1, transaction)?;
+        transactions.save_new_transaction(2, Transaction::new(Kind::Deposit, 1, dec!(5)))?;
+        transactions.rollback()?;
+
+        assert_eq!(
+            transactions.database.len(),
+            1,
+            "new transactions should be discarded"
+        );
+        assert!(
+            !transactions.database[&1].disputed(),
+            "updated transactions should be restored"
+        );
+
+        transactions.begin()?;
+        transactions.save_new_transaction(2, Transaction::new(Kind::Deposit, 1, dec!(5)))?;
+        transactions.commit()?;
+        transactions.rollback()?;
+        assert!(
+            transactions.has_id(2)?,
+            "committed changes should not be rolled back"
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,427 @@
+use crate::{
+    dtos::TransactionRecord,
+    stores::{clients::Clients, transactions::Transactions, ClientStore, TransactionStore},
+    Engine, Error,
+};
+use std::num::NonZeroUsize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    sync::mpsc,
+    task::{self, JoinHandle},
+};
+
+/// Number of records queued for the engine by default before streams wait for it to catch up
+pub const DEFAULT_QUEUE_CAPACITY: NonZeroUsize = match NonZeroUsize::new(1024) {
+    Some(capacity) => capacity,
+    None => unreachable!(),
+};
+
+struct Submission {
+    line: u64,
+    // Rows that fail to parse are queued too, so they take their number among the records handled by the engine
+    record: Result<TransactionRecord, Error>,
+    errors: mpsc::UnboundedSender<(u64, Error)>,
+}
+
+/// Async (tokio) front end feeding many csv streams, like network connections, to one shared `Engine`.
+///
+/// The engine runs on a blocking thread and is fed through a bounded queue. Each stream is read on its own task and
+/// queues its records in order, so the records of a stream are processed in the order they were written while
+/// streams are interleaved in the order their records arrive. Once the queue is full, streams stop reading until the
+/// engine catches up, which applies backpressure to their sources.
+pub struct StreamProcessor<C = Clients, T = Transactions> {
+    engine: Engine<C, T>,
+    capacity: NonZeroUsize,
+}
+
+impl Default for StreamProcessor {
+    fn default() -> Self {
+        Self::new(Engine::default())
+    }
+}
+
+impl<C, T> StreamProcessor<C, T>
+where
+    C: ClientStore + Send + 'static,
+    T: TransactionStore + Send + 'static,
+{
+    /// Creates a processor that feeds the given engine
+    pub fn new(engine: Engine<C, T>) -> Self {
+        Self {
+            engine,
+            capacity: DEFAULT_QUEUE_CAPACITY,
+        }
+    }
+
+    /// Sets how many records can be queued for the engine before streams have to wait
+    #[must_use]
+    pub fn with_capacity(mut self, capacity: NonZeroUsize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Starts the engine on a blocking thread of the current tokio runtime. Streams are fed through the returned
+    /// `Ingest` and its clones, and the handle resolves to the engine once every `Ingest` is dropped and the queued
+    /// records are processed.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if called outside of a tokio runtime
+    pub fn spawn(self) -> (Ingest, JoinHandle<Engine<C, T>>) {
+        let (sender, mut receiver) = mpsc::channel::<Submission>(self.capacity.get());
+        let mut engine = self.engine;
+        let handle = task::spawn_blocking(move || {
+            while let Some(submission) = receiver.blocking_recv() {
+                let result = match submission.record {
+                    Ok(record) => engine.handle(&record),
+                    Err(error) => {
+                        engine.skip_record();
+                        Err(error)
+                    }
+                };
+                if let Err(error) = result {
+                    let _ = submission.errors.send((submission.line, error));
+                }
+            }
+            engine
+        });
+        (Ingest { sender }, handle)
+    }
+}
+
+/// Outcome of processing a stream
+#[derive(Default, Debug)]
+pub struct StreamReport {
+    /// Number of rows read from the stream, header and blank lines excluded
+    pub rows: u64,
+    /// Rejected rows of the stream with their line number (counting from 1, the header being line 1), in line order
+    pub errors: Vec<(u64, Error)>,
+}
+
+/// Handle feeding streams to the engine of a `StreamProcessor`, cloned for every concurrent stream
+#[derive(Clone)]
+pub struct Ingest {
+    sender: mpsc::Sender<Submission>,
+}
+
+impl Ingest {
+    /// Reads the stream as a csv and feeds its records to the engine. Resolves once every record of the stream is
+    /// processed.
+    ///
+    /// Errors only concern this stream and are returned in the report: rows that cannot be parsed or are rejected by
+    /// the engine are reported and the stream goes on, while a read failure ends it.
+    pub async fn process(&self, input: impl AsyncRead + Unpin) -> StreamReport {
+        let (errors, mut rejected) = mpsc::unbounded_channel();
+        let mut report = StreamReport::default();
+        let mut input = BufReader::new(input);
+        let mut rows = RowReader::default();
+        let mut headers = None;
+        loop {
+            let buffer = match input.fill_buf().await {
+                Ok(buffer) => buffer,
+                Err(e) => {
+                    report
+                        .errors
+                        .push((rows.line(), Error::CSVRowReadFailure(e.to_string())));
+                    break;
+                }
+            };
+            let end = buffer.is_empty();
+            let (consumed, row) = rows.read(buffer);
+            input.consume(consumed);
+            let Some((line, row)) = row else {
+                if end {
+                    break;
+                }
+                continue;
+            };
+            let Some(headers) = &headers else {
+                headers = Some(row);
+                continue;
+            };
+
+            report.rows += 1;
+            let submission = Submission {
+                line,
+                record: row
+                    .deserialize(Some(headers))
+                    .map_err(|e| Error::CSVRowReadFailure(e.to_string())),
+                errors: errors.clone(),
+            };
+            if self.sender.send(submission).await.is_err() {
+                report.errors.push((line, Error::ProcessorStopped));
+                break;
+            }
+        }
+
+        // Every queued record holds a sender, so the channel closes once the last one is processed
+        drop(errors);
+        while let Some(error) = rejected.recv().await {
+            report.errors.push(error);
+        }
+        report.errors.sort_by_key(|(line, _)| *line);
+        report
+    }
+}
+
+// Splits a stream into csv rows as its bytes come in, so rows can span several reads and quoted fields several lines.
+// Rows are trimmed and flexible like the rows read by `CSVProcessor`, and positioned at the line they start on.
+struct RowReader {
+    core: csv_core::Reader,
+    // Fields of the row being read, and where each of them ends in `fields`
+    fields: Vec<u8>,
+    fields_len: usize,
+    ends: Vec<usize>,
+    ends_len: usize,
+    // Line the row being read starts on, `None` between rows
+    start: Option<u64>,
+    // Lines read so far, counted by line feeds
+    lines: u64,
+    // Rows read so far, the header included
+    rows: u64,
+}
+
+impl Default for RowReader {
+    fn default() -> Self {
+        Self {
+            core: csv_core::Reader::new(),
+            fields: vec![0; 1024],
+            fields_len: 0,
+            ends: vec![0; 16],
+            ends_len: 0,
+            start: None,
+            lines: 0,
+            rows: 0,
+        }
+    }
+}
+
+impl RowReader {
+    /// Line of the row being read, or of the next one between rows
+    fn line(&self) -> u64 {
+        self.start.unwrap_or(self.lines + 1)
+    }
+
+    /// Reads the row at the start of `input`, an empty input marking the end of the stream. Returns how many bytes
+    /// were consumed, along with the row and its line once it is complete.
+    fn read(&mut self, input: &[u8]) -> (usize, Option<(u64, csv::ByteRecord)>) {
+        let mut consumed = 0;
+        if self.start.is_none() {
+            // Blank lines are skipped, as in `CSVProcessor::process`
+            consumed = input
+                .iter()
+                .take_while(|byte| matches!(byte, b'\n' | b'\r'))
+                .count();
+            self.count_lines(&input[..consumed]);
+            if consumed == input.len() {
+                return (consumed, None);
+            }
+            self.start = Some(self.lines + 1);
+        }
+
+        loop {
+            let (result, read, written, ended) = self.core.read_record(
+                &input[consumed..],
+                &mut self.fields[self.fields_len..],
+                &mut self.ends[self.ends_len..],
+            );
+            self.count_lines(&input[consumed..consumed + read]);
+            consumed += read;
+            self.fields_len += written;
+            self.ends_len += ended;
+            match result {
+                csv_core::ReadRecordResult::InputEmpty | csv_core::ReadRecordResult::End => {
+                    return (consumed, None);
+                }
+                csv_core::ReadRecordResult::OutputFull => {
+                    self.fields.resize(self.fields.len() * 2, 0);
+                }
+                csv_core::ReadRecordResult::OutputEndsFull => {
+                    self.ends.resize(self.ends.len() * 2, 0);
+                }
+                csv_core::ReadRecordResult::Record => return (consumed, Some(self.take_row())),
+            }
+        }
+    }
+
+    fn count_lines(&mut self, bytes: &[u8]) {
+        self.lines += bytes
+            .iter()
+            .map(|byte| u64::from(*byte == b'\n'))
+            .sum::<u64>();
+    }
+
+    fn take_row(&mut self) -> (u64, csv::ByteRecord) {
+        let line = self.start.take().unwrap_or(self.lines);
+        let mut row = csv::ByteRecord::new();
+        let mut start = 0;
+        for &end in &self.ends[..self.ends_len] {
+            row.push_field(&self.fields[start..end]);
+            start = end;
+        }
+        row.trim();
+        let mut position = csv::Position::new();
+        position.set_line(line).set_record(self.rows);
+        row.set_position(Some(position));
+        self.rows += 1;
+        self.fields_len = 0;
+        self.ends_len = 0;
+        (line, row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rust_decimal_macros::dec;
+    use std::{
+        fmt::Write as _,
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{AsyncWriteExt, ReadBuf};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_streams() -> Result<(), Error> {
+        let (ingest, engine) = StreamProcessor::default()
+            .with_capacity(NonZeroUsize::MIN)
+            .spawn();
+
+        let mut tasks = Vec::new();
+        for client in 1..=20u16 {
+            let ingest = ingest.clone();
+            // Small pipes so writers wait on the readers, which wait on the engine
+            let (mut writer, reader) = tokio::io::duplex(16);
+            tasks.push(tokio::spawn(async move {
+                let report = ingest.process(reader);
+                let write = async move {
+                    let mut input = String::from("type, client, tx, amount\n");
+                    for index in 0..10u32 {
+                        let tx = u32::from(client) * 100 + index;
+                        let _ = writeln!(input, "deposit, {client}, {tx}, 1");
+                    }
+                    let _ = write!(input, "withdrawal, {client}, {client}, 100\n\n");
+                    input.push_str("deposit, x, 1, 1\n");
+                    writer.write_all(input.as_bytes()).await.expect("pipe");
+                };
+                let (report, ()) = tokio::join!(report, write);
+                report
+            }));
+        }
+        drop(ingest);
+
+        for (client, task) in (1..=20u16).zip(tasks) {
+            let report = task.await.expect("stream task should not panic");
+            assert_eq!(report.rows, 12);
+            let lines: Vec<_> = report.errors.iter().map(|(line, _)| *line).collect();
+            assert_eq!(lines, vec![12, 14]);
+            assert_eq!(
+                report.errors[0].1,
+                Error::ClientCannotWithdrawl {
+                    id: client,
+                    amount: dec!(100),
+                    available: dec!(10),
+                }
+            );
+            assert!(matches!(report.errors[1].1, Error::CSVRowReadFailure(_)));
+        }
+
+        let engine = engine.await.expect("engine task should not panic");
+        let clients = engine.get_clients()?;
+        assert_eq!(clients.len(), 20);
+        for (_, client) in clients {
+            assert_eq!(client.balance("").available_amount, dec!(10));
+        }
+        Ok(())
+    }
+
+    // Yields its bytes, then fails like a dropped connection
+    struct FailingReader(&'static [u8]);
+
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.0.is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            let (read, rest) = self.0.split_at(self.0.len().min(buf.remaining()));
+            buf.put_slice(read);
+            self.0 = rest;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_failure_ends_stream() {
+        let (ingest, engine) = StreamProcessor::default().spawn();
+        let report = ingest
+            .process(FailingReader(
+                b"type,client,tx,amount\ndeposit,1,1,2\ndeposit,1,",
+            ))
+            .await;
+        drop(ingest);
+
+        assert_eq!(report.rows, 1);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].0, 3);
+        assert!(matches!(report.errors[0].1, Error::CSVRowReadFailure(_)));
+        let engine = engine.await.expect("engine task should not panic");
+        assert_eq!(engine.record_counts(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_rows_failing_to_parse_are_counted() {
+        let (ingest, engine) = StreamProcessor::default().spawn();
+        let input: &[u8] =
+            b"type,client,tx,amount\ndeposit,1,1,2\n\xff\ndeposit,x,2,2\ndeposit,1,3,2\n";
+        let report = ingest.process(input).await;
+        drop(ingest);
+
+        assert_eq!(report.rows, 4);
+        let lines: Vec<_> = report.errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![3, 4]);
+        assert!(report
+            .errors
+            .iter()
+            .all(|(_, error)| matches!(error, Error::CSVRowReadFailure(_))));
+        let engine = engine.await.expect("engine task should not panic");
+        assert_eq!(
+            engine.record_counts(),
+            (4, 2),
+            "rows that fail to parse should take a record number"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quoted_fields_spanning_lines() -> Result<(), Error> {
+        let (ingest, engine) = StreamProcessor::default().spawn();
+        let (mut writer, reader) = tokio::io::duplex(4);
+        let input = "type,client,tx,amount,reason,operator\n\
+                     deposit,1,1,2,,\n\
+                     freeze,1,0,,\"chargebacks,\r\nthen more\",ops\n\
+                     deposit,1,2,2,,\n\
+                     deposit,x,3,1,\"one\ntwo\",\n\
+                     deposit,2,4,3,,";
+        let write = async move {
+            writer.write_all(input.as_bytes()).await.expect("pipe");
+        };
+        let (report, ()) = tokio::join!(ingest.process(reader), write);
+        drop(ingest);
+
+        assert_eq!(report.rows, 5);
+        let lines: Vec<_> = report.errors.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![5, 6], "rows should keep the line they start on");
+        assert_eq!(report.errors[0].1, Error::ClientFrozen(1));
+        assert!(matches!(report.errors[1].1, Error::CSVRowReadFailure(_)));
+        let engine = engine.await.expect("engine task should not panic");
+        assert_eq!(engine.record_counts(), (5, 3));
+        let changes = engine.stores().0.get_status_changes(1)?;
+        assert_eq!(changes[0].reason, "chargebacks,\r\nthen more");
+        Ok(())
+    }
+}